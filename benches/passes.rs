@@ -0,0 +1,122 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Benchmarks of pass two, pass three, and byte-for-byte verification over a
+//! few generated trees of files, each of which stresses something different:
+//!
+//! - `small`: lots of small files, in a handful of sizes, mostly different.
+//! - `large`: a few large files, some of them identical.
+//! - `identical`: lots of files that are all the same.
+//!
+//! ```text
+//! cargo bench --bench passes
+//! ```
+//!
+//! The trees are generated in a temporary directory, under
+//! `DRUPES_BENCH_DIR` if that's set (and the system's temporary directory if
+//! not), so point it at whichever storage you want to measure. They're
+//! removed afterwards. After the first iteration the files will generally be
+//! in the page cache, so this mostly measures CPU and syscall overhead, not
+//! the disk.
+
+use std::{collections::BTreeMap, fs, hint::black_box, path::{Path, PathBuf}, time::Instant};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use drupes::{discard_unique_sizes, first_mismatch, pass_three, pass_two, walk, ScanOptions};
+
+fn passes(c: &mut Criterion) {
+    let base = match std::env::var_os("DRUPES_BENCH_DIR") {
+        Some(dir) => tempfile::tempdir_in(dir),
+        None => tempfile::tempdir(),
+    }.expect("unable to make a directory for the files");
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+    let dir = base.path().join("small");
+    let mut last: Vec<Vec<u8>> = vec![vec![]; 20];
+    for i in 0..5_000 {
+        let sub = dir.join(format!("{}", i % 50));
+        fs::create_dir_all(&sub).unwrap();
+        // Every so often, repeat the last file of the same size, so that
+        // there's something for pass three to do.
+        if i % 10 != 0 || last[i % 20].is_empty() {
+            last[i % 20] = rng.bytes(1_000 + (i % 20) * 100);
+        }
+        fs::write(sub.join(format!("{i}")), &last[i % 20]).unwrap();
+    }
+    bench(c, "small", &dir);
+
+    let dir = base.path().join("large");
+    fs::create_dir_all(&dir).unwrap();
+    let big = rng.bytes(32 << 20);
+    for name in ["a", "b", "c"] {
+        fs::write(dir.join(name), &big).unwrap();
+    }
+    // Same size as the others, and the same start, but not the same.
+    let mut different = big;
+    *different.last_mut().unwrap() ^= 1;
+    fs::write(dir.join("d"), different).unwrap();
+    bench(c, "large", &dir);
+
+    let dir = base.path().join("identical");
+    fs::create_dir_all(&dir).unwrap();
+    let same = rng.bytes(64 << 10);
+    for i in 0..1_000 {
+        fs::write(dir.join(format!("{i}")), &same).unwrap();
+    }
+    bench(c, "identical", &dir);
+}
+
+/// Benchmarks each step on the tree at `dir`, as a group called `name`.
+fn bench(c: &mut Criterion, name: &str, dir: &Path) {
+    let opts = ScanOptions::new();
+    let start = Instant::now();
+    let mut paths: BTreeMap<u64, Vec<PathBuf>> =
+        walk(&[dir.to_owned()], &opts, start, |_| ()).unwrap();
+    discard_unique_sizes(&mut paths, &opts);
+    let hashed = pass_three(pass_two(&paths, &opts, start, |_| ()), &opts, start, |_| ());
+
+    let mut group = c.benchmark_group(name);
+    // The large tree takes a while to get through, so don't insist on as
+    // many samples as usual.
+    group.sample_size(10);
+    group.bench_function("pass two", |b| {
+        b.iter(|| pass_two(black_box(&paths), &opts, start, |_| ()));
+    });
+    group.bench_function("pass three", |b| {
+        b.iter_batched(
+            || pass_two(&paths, &opts, start, |_| ()),
+            |prehashed| pass_three(prehashed, &opts, start, |_| ()),
+            BatchSize::LargeInput,
+        );
+    });
+    group.bench_function("verify", |b| {
+        b.iter(|| {
+            for files in hashed.values().filter(|files| files.len() > 1) {
+                assert!(first_mismatch(files).unwrap().is_none());
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, passes);
+criterion_main!(benches);
+
+/// A tiny, deterministic source of file contents, so that every run gets the
+/// same files. Quality is not a concern.
+struct XorShift(u64);
+
+impl XorShift {
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len + 8);
+        while out.len() < len {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            out.extend_from_slice(&self.0.to_le_bytes());
+        }
+        out.truncate(len);
+        out
+    }
+}
@@ -0,0 +1,41 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Times hashing one large file with BLAKE3, on one thread and then on all of
+//! them, the way `drupes` does for memory-mapped files in pass three.
+//!
+//! ```text
+//! cargo run --release --example hash_large_file -- some-huge-file
+//! ```
+//!
+//! Run it twice if you want to be sure the file is in the page cache for both
+//! measurements, since otherwise you're mostly timing your disk.
+
+use std::{fs::File, time::Instant};
+
+use anyhow::Context as _;
+
+fn main() -> anyhow::Result<()> {
+    let path = std::env::args_os().nth(1)
+        .context("usage: hash_large_file <FILE>")?;
+    let f = File::open(&path)?;
+    // Safety: see the caveats in `update_mmap` in the library; this is just a
+    // benchmark.
+    let map = unsafe { memmap2::Mmap::map(&f)? };
+
+    let start = Instant::now();
+    let serial = blake3::Hasher::new().update(&map).finalize();
+    let serial_time = start.elapsed();
+
+    let start = Instant::now();
+    let parallel = blake3::Hasher::new().update_rayon(&map).finalize();
+    let parallel_time = start.elapsed();
+
+    assert_eq!(serial, parallel);
+    println!("{} bytes, hash {serial}", map.len());
+    println!("one thread:   {serial_time:?}");
+    println!("all threads:  {parallel_time:?} ({} threads)",
+        rayon::current_num_threads());
+    Ok(())
+}
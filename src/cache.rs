@@ -0,0 +1,222 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A record of the full hashes of files from earlier runs, so that files that
+//! haven't changed don't need to be read again.
+
+use std::{collections::HashMap, fs::{File, Metadata}, io::{BufReader, BufWriter, ErrorKind, Write as _}, path::{Path, PathBuf}, sync::Mutex, time::UNIX_EPOCH};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{Digest, HashAlgorithm};
+
+/// Bumped whenever the format of the cache file changes, so that old caches
+/// get ignored instead of misread.
+const CACHE_VERSION: u32 = 2;
+
+/// Hashes from [`pass_two`](crate::pass_two) and
+/// [`pass_three`](crate::pass_three), saved between runs, and keyed by each
+/// file's path, size, and modification time. With both, a file that hasn't
+/// changed doesn't need to be read at all.
+///
+/// A file whose size or modification time has changed since its hash was
+/// saved is hashed again, like any other. That's the same test `make` and
+/// `rsync` use, and it has the same weakness: a file that's changed without
+/// either of those changing (say, by something that sets the modification
+/// time back afterwards) will be treated as unchanged.
+///
+/// Since the hashes depend on the
+/// [algorithm](crate::ScanOptions::hash_algorithm) and (for BLAKE3) the
+/// [prehash size](crate::ScanOptions::prehash_size), a cache saved with
+/// different settings is ignored.
+#[derive(Debug)]
+pub struct HashCache {
+    algorithm: HashAlgorithm,
+    prehash_size: usize,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+/// What we remember about a file: its size and modification time, as of when
+/// it was hashed, and its hashes from `pass_two` and (if it got that far)
+/// `pass_three`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    stamp: Stamp,
+    prehash: Digest,
+    hash: Option<Digest>,
+}
+
+/// The size and modification time of a file, which we take to change whenever
+/// its contents do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl Stamp {
+    /// Gets the stamp of the file described by `meta`, if its modification
+    /// time can be represented.
+    fn new(meta: &Metadata) -> Option<Self> {
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: meta.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+/// The cache, in the form it's saved to disk.
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    algorithm: String,
+    prehash_size: usize,
+    entries: Vec<CacheFileEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFileEntry {
+    path: String,
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    prehash: String,
+    hash: Option<String>,
+}
+
+impl HashCache {
+    /// Creates an empty cache, for hashes made with `algorithm` and
+    /// `prehash_size`.
+    pub fn new(algorithm: HashAlgorithm, prehash_size: usize) -> Self {
+        Self { algorithm, prehash_size, entries: Mutex::default() }
+    }
+
+    /// Loads a cache saved by [`save`](Self::save). If there isn't one at
+    /// `path` yet, or it was saved with different settings, this returns an
+    /// empty cache instead.
+    pub fn load(
+        path: &Path,
+        algorithm: HashAlgorithm,
+        prehash_size: usize,
+    ) -> anyhow::Result<Self> {
+        let cache = Self::new(algorithm, prehash_size);
+        let f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e)
+                .with_context(|| format!("unable to open cache {}", path.display())),
+        };
+        let file: CacheFile = serde_json::from_reader(BufReader::new(f))
+            .with_context(|| format!("problem reading cache {}", path.display()))?;
+        if file.version != CACHE_VERSION
+            || file.algorithm != algorithm.to_string()
+            || file.prehash_size != prehash_size
+        {
+            return Ok(cache);
+        }
+
+        let entries = file.entries.into_iter()
+            .filter_map(|e| Some((PathBuf::from(e.path), Entry {
+                stamp: Stamp { size: e.size, mtime_secs: e.mtime_secs, mtime_nanos: e.mtime_nanos },
+                prehash: Digest::from_hex(&e.prehash)?,
+                hash: match e.hash {
+                    Some(hash) => Some(Digest::from_hex(&hash)?),
+                    None => None,
+                },
+            })))
+            .collect();
+        *cache.entries.lock().unwrap() = entries;
+        Ok(cache)
+    }
+
+    /// Writes the cache to `path`, replacing whatever was there.
+    ///
+    /// Entries for files that no longer exist are dropped, so that the cache
+    /// doesn't grow forever as files come and go.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut entries: Vec<_> = entries.iter()
+            .filter(|(p, _)| p.symlink_metadata().is_ok())
+            // Paths that aren't UTF-8 can't be stored in JSON as they are, so
+            // those files just don't get cached.
+            .filter_map(|(p, e)| Some(CacheFileEntry {
+                path: p.to_str()?.to_string(),
+                size: e.stamp.size,
+                mtime_secs: e.stamp.mtime_secs,
+                mtime_nanos: e.stamp.mtime_nanos,
+                prehash: e.prehash.to_hex(),
+                hash: e.hash.map(|hash| hash.to_hex()),
+            }))
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            algorithm: self.algorithm.to_string(),
+            prehash_size: self.prehash_size,
+            entries,
+        };
+
+        // Write to a temporary file and move it into place, so that if we're
+        // interrupted, the old cache is still there.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut out = BufWriter::new(File::create(&tmp)
+            .with_context(|| format!("unable to create {}", tmp.display()))?);
+        serde_json::to_writer(&mut out, &file)?;
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("unable to write cache {}", path.display()))
+    }
+
+    /// Looks up what we have for the file at `path`, whose current metadata
+    /// is `meta`. Returns `None` if we don't have it, or the file has changed.
+    fn lookup(&self, path: &Path, meta: &Metadata) -> Option<Entry> {
+        let path = std::path::absolute(path).ok()?;
+        let entry = *self.entries.lock().unwrap().get(&path)?;
+        (Stamp::new(meta)? == entry.stamp).then_some(entry)
+    }
+
+    /// Looks up the prehash of the file at `path`, like
+    /// [`get`](Self::get).
+    pub(crate) fn get_prehash(&self, path: &Path, meta: &Metadata) -> Option<Digest> {
+        Some(self.lookup(path, meta)?.prehash)
+    }
+
+    /// Looks up the full hash of the file at `path`, whose current metadata is
+    /// `meta`. Returns `None` if we don't have it, or the file has changed.
+    pub(crate) fn get(&self, path: &Path, meta: &Metadata) -> Option<Digest> {
+        self.lookup(path, meta)?.hash
+    }
+
+    /// Records that the file at `path`, with metadata `meta`, has prehash
+    /// `prehash`, and full hash `hash` if we know it. A full hash recorded
+    /// earlier for the same version of the file is kept.
+    pub(crate) fn insert(
+        &self,
+        path: &Path,
+        meta: &Metadata,
+        prehash: Digest,
+        hash: Option<Digest>,
+    ) {
+        // Paths are stored in absolute form, so that the cache still works
+        // when run from somewhere else.
+        let Ok(path) = std::path::absolute(path) else {
+            return;
+        };
+        let Some(stamp) = Stamp::new(meta) else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let hash = hash.or_else(|| entries.get(&path)
+            .filter(|old| old.stamp == stamp && old.prehash == prehash)
+            .and_then(|old| old.hash));
+        entries.insert(path, Entry { stamp, prehash, hash });
+    }
+}
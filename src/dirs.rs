@@ -0,0 +1,147 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finding whole directories that are duplicates of one another, from the
+//! results for the files in them.
+
+use std::{cmp::Reverse, collections::{HashMap, HashSet}, ffi::OsStr, path::{Path, PathBuf}};
+
+use crate::{hash::Hasher, Digest, DuplicateGroup, HashAlgorithm};
+
+/// What we know about one directory while working out its hash.
+#[derive(Default)]
+struct Dir<'a> {
+    /// The files directly in this directory, with their sizes and hashes.
+    files: Vec<(&'a OsStr, u64, Digest)>,
+    subdirs: Vec<&'a Path>,
+    /// Whether there's a file in this directory with no copy anywhere, which
+    /// means the directory can't be a duplicate of anything.
+    has_unique: bool,
+}
+
+/// The combined hash of everything under a directory, along with the total
+/// size of the files in it.
+type Summary = Option<(Digest, u64)>;
+
+/// Finds directories under `roots` whose contents are entirely duplicated by
+/// another directory: the same files, under the same names, in the same
+/// layout.
+///
+/// `files` is every file the walk found, each with its size and hash if it
+/// has a copy somewhere (from [`pass_three`](crate::pass_three)), or `None`
+/// if it doesn't. Only files that were found count, so a directory with
+/// extra files that `walk` was told to skip can still be a duplicate, and
+/// empty directories are ignored entirely.
+///
+/// Each group's `hash` combines the names and hashes of everything in the
+/// directories, using `algorithm`, and its `size` is the total size of the
+/// files in each directory. Only the topmost directories are reported: if two
+/// directories are duplicates, their matching subdirectories aren't reported
+/// separately, unless they also have copies somewhere else.
+pub fn find_duplicate_dirs<'a>(
+    roots: &[PathBuf],
+    files: &[(&'a Path, Option<(u64, Digest)>)],
+    algorithm: HashAlgorithm,
+) -> Vec<DuplicateGroup> {
+    let in_roots = |dir: &Path| roots.iter().any(|root| dir.starts_with(root));
+
+    let mut dirs: HashMap<&'a Path, Dir<'a>> = HashMap::new();
+    for &(path, hashed) in files {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        if !in_roots(parent) {
+            continue;
+        }
+        let dir = dirs.entry(parent).or_default();
+        match hashed {
+            Some((size, hash)) => dir.files.push((name, size, hash)),
+            None => dir.has_unique = true,
+        }
+    }
+
+    // Link each directory to its parent, adding any directories along the way
+    // that don't have files of their own.
+    let mut pending: Vec<&Path> = dirs.keys().copied().collect();
+    while let Some(dir) = pending.pop() {
+        let Some(parent) = dir.parent().filter(|parent| in_roots(parent)) else {
+            continue;
+        };
+        let parent = dirs.entry(parent).or_insert_with(|| {
+            pending.push(parent);
+            Dir::default()
+        });
+        parent.subdirs.push(dir);
+    }
+
+    // Work from the bottom up, so that each directory's subdirectories are
+    // done before it is.
+    let mut order: Vec<&Path> = dirs.keys().copied().collect();
+    order.sort_by_key(|dir| Reverse(dir.components().count()));
+    let mut summaries: HashMap<&Path, Summary> = HashMap::new();
+    for path in order {
+        let summary = summarize_dir(&dirs[path], &summaries, algorithm);
+        summaries.insert(path, summary);
+    }
+
+    let mut by_hash: HashMap<(Digest, u64), Vec<&Path>> = HashMap::new();
+    for (path, summary) in &summaries {
+        if let Some(key) = summary {
+            by_hash.entry(*key).or_default().push(path);
+        }
+    }
+    by_hash.retain(|_, dirs| dirs.len() > 1);
+
+    // A group is only worth reporting if at least one of its directories
+    // isn't already covered by its parent being reported.
+    let duplicated: HashSet<&Path> = by_hash.values().flatten().copied().collect();
+    let mut groups: Vec<DuplicateGroup> = by_hash.into_iter()
+        .filter(|(_, dirs)| dirs.iter().any(|dir| {
+            dir.parent().is_none_or(|parent| !duplicated.contains(parent))
+        }))
+        .map(|((hash, size), dirs)| {
+            let mut paths: Vec<PathBuf> = dirs.into_iter().map(Path::to_path_buf).collect();
+            paths.sort();
+            DuplicateGroup { hash, size, paths }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+    groups
+}
+
+/// Works out the combined hash of `dir`, given those of its subdirectories,
+/// or `None` if it can't be a duplicate.
+fn summarize_dir(
+    dir: &Dir,
+    summaries: &HashMap<&Path, Summary>,
+    algorithm: HashAlgorithm,
+) -> Summary {
+    if dir.has_unique {
+        return None;
+    }
+    // Each entry is a name, whether it's a directory, and the hash and size of
+    // what's there, sorted by name so that the order we found them in
+    // doesn't matter.
+    let mut entries: Vec<(&OsStr, bool, Digest, u64)> = dir.files.iter()
+        .map(|&(name, size, hash)| (name, false, hash, size))
+        .collect();
+    for subdir in &dir.subdirs {
+        let (hash, size) = summaries[subdir]?;
+        entries.push((subdir.file_name().unwrap_or_default(), true, hash, size));
+    }
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut hasher = Hasher::new(algorithm);
+    let mut total = 0;
+    for (name, is_dir, hash, size) in entries {
+        let name = name.as_encoded_bytes();
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name);
+        hasher.update(&[u8::from(is_dir)]);
+        hasher.update(&size.to_le_bytes());
+        hasher.update(hash.as_bytes());
+        total += size;
+    }
+    Some((hasher.finalize(), total))
+}
@@ -0,0 +1,136 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finding the `.gitignore` files, and the other files like them, that apply
+//! while [`walk`](crate::walk) goes down the tree. Reading them and matching
+//! against them is left to the `ignore` crate's gitignore matcher, which is
+//! the one ripgrep uses, so all we do here is keep track of which ones are in
+//! effect where.
+
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// The files that are read from each directory, in order of increasing
+/// precedence.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+/// Read before the others, but only in the directory at the top of a
+/// repository, which is the only place git looks for it.
+const REPO_EXCLUDE: &str = ".git/info/exclude";
+
+/// The patterns from one directory's ignore files.
+#[derive(Debug)]
+struct IgnoreLevel {
+    /// The directory the patterns are relative to, spelled the way the walk
+    /// spells it.
+    base: PathBuf,
+    /// Where `base` is, relative to the directory the files were actually in.
+    /// This is empty, except for files found above the root of the walk.
+    prefix: PathBuf,
+    matcher: Gitignore,
+}
+
+impl IgnoreLevel {
+    /// Reads `files`, whichever of them exist, with the later ones taking
+    /// precedence. Returns `None` if none of them have any patterns in them.
+    fn read(dir: &Path, files: &[PathBuf], base: &Path, prefix: &Path) -> Option<Self> {
+        let mut builder = GitignoreBuilder::new(dir);
+        for file in files.iter().filter(|file| file.is_file()) {
+            if let Some(e) = builder.add(file) {
+                eprintln!("warning: problem reading {}: {e}", file.display());
+            }
+        }
+        let matcher = builder.build()
+            .map_err(|e| eprintln!("warning: problem reading ignore files in {}: {e}", dir.display()))
+            .ok()?;
+        Self::new(matcher, base, prefix)
+    }
+
+    fn new(matcher: Gitignore, base: &Path, prefix: &Path) -> Option<Self> {
+        if matcher.is_empty() {
+            return None;
+        }
+        Some(Self { base: base.to_owned(), prefix: prefix.to_owned(), matcher })
+    }
+
+    /// Whether these files say to ignore `path` (`Some(true)`), to keep it
+    /// despite what some other file says (`Some(false)`), or don't say.
+    fn decides(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        // Hand the matcher a path relative to its directory, so that it
+        // doesn't matter how the walk spells things.
+        let rel = self.prefix.join(path.strip_prefix(&self.base).ok()?);
+        let decided = self.matcher.matched(&rel, is_dir);
+        (!decided.is_none()).then(|| decided.is_ignore())
+    }
+}
+
+/// The ignore files to read in `dir`, from the least to the most specific.
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if dir.join(".git").is_dir() {
+        files.push(dir.join(REPO_EXCLUDE));
+    }
+    files.extend(IGNORE_FILES.iter().map(|name| dir.join(name)));
+    files
+}
+
+/// All the ignore files that apply at some point in a walk, from the least
+/// to the most specific. This is carried down the walk by jwalk, with each
+/// directory adding its own files for its children.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IgnoreStack(Vec<Arc<IgnoreLevel>>);
+
+impl IgnoreStack {
+    /// Starts the stack for a walk of `root`, with the user's global ignore
+    /// file and any ignore files in the directories above `root`, if `root` is
+    /// inside a git repository.
+    pub(crate) fn for_root(root: &Path) -> Self {
+        let mut stack = Self::default();
+        let Ok(real) = root.canonicalize() else {
+            return stack;
+        };
+        let repo = real.ancestors().skip(1).find(|dir| dir.join(".git").exists());
+
+        // This is wherever git's `core.excludesFile` says, or its default
+        // under `~/.config/git`. Its patterns are relative to the top of the
+        // repository, or to the root if there isn't one.
+        let (global, e) = GitignoreBuilder::new(root).build_global();
+        if let Some(e) = e {
+            eprintln!("warning: problem reading the global git ignore file: {e}");
+        }
+        let prefix = repo.and_then(|repo| real.strip_prefix(repo).ok())
+            .unwrap_or(Path::new(""));
+        stack.push(IgnoreLevel::new(global, root, prefix));
+
+        if let Some(repo) = repo {
+            for dir in real.ancestors().skip(1).take_while(|dir| dir.starts_with(repo))
+                .collect::<Vec<_>>().into_iter().rev()
+            {
+                let prefix = real.strip_prefix(dir).unwrap_or(Path::new(""));
+                stack.push(IgnoreLevel::read(dir, &files_in(dir), root, prefix));
+            }
+        }
+        stack
+    }
+
+    /// Adds the ignore files in `dir`, which is where the walk has got to.
+    pub(crate) fn push_dir(&mut self, dir: &Path) {
+        self.push(IgnoreLevel::read(dir, &files_in(dir), dir, Path::new("")));
+    }
+
+    fn push(&mut self, level: Option<IgnoreLevel>) {
+        if let Some(level) = level {
+            self.0.push(Arc::new(level));
+        }
+    }
+
+    /// Checks whether `path` is ignored. A more specific ignore file overrides
+    /// a less specific one, as in git.
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.0.iter().rev()
+            .find_map(|level| level.decides(path, is_dir))
+            .unwrap_or(false)
+    }
+}
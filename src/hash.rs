@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The hash algorithms we know how to use, behind a common interface.
+
+use std::{fmt, io::Read, str::FromStr};
+
+use sha2::Digest as _;
+
+/// Hash algorithms that can be used to compare file contents.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// BLAKE3, the default: fast, and cryptographically strong.
+    #[default]
+    Blake3,
+    /// SHA-256, which is slower, but which checksums from lots of other tools
+    /// use.
+    Sha256,
+    /// 128-bit XXH3, which is _very_ fast, but not a cryptographic hash. It's
+    /// good at telling different files apart by accident, but someone could
+    /// craft two different files that it thinks are the same.
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// Names of all the algorithms, as accepted by `FromStr`.
+    pub const NAMES: [&'static str; 3] = ["blake3", "sha256", "xxh3"];
+
+    /// Hashes `data` in one go.
+    pub(crate) fn hash(self, data: &[u8]) -> Digest {
+        let mut hasher = Hasher::new(self);
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Produces a hasher for the full contents of a file, given the hash of
+    /// its first `prehash_size` bytes, along with how far into the file it
+    /// should start reading.
+    ///
+    /// For BLAKE3, the prehash becomes the key for a keyed hash of the rest of
+    /// the file, so that we don't need to read the start of the file again.
+    /// The other algorithms don't have keyed modes, so they start over from
+    /// the beginning of the file; as a bonus, this means their results match
+    /// the checksums that other tools produce. So does BLAKE3's, if there was
+    /// no prehash, since then there's nothing to chain off of.
+    pub(crate) fn chained_hasher(self, prehash: &Digest, prehash_size: u64) -> (Hasher, u64) {
+        match self {
+            Self::Blake3 if prehash_size > 0 => {
+                let key = prehash.as_bytes()
+                    .try_into()
+                    .expect("BLAKE3 prehash should be 32 bytes");
+                (Hasher::Blake3(Box::new(blake3::Hasher::new_keyed(key))), prehash_size)
+            }
+            _ => (Hasher::new(self), 0),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+            Self::Xxh3 => "xxh3",
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(Self::Blake3),
+            "sha256" => Ok(Self::Sha256),
+            "xxh3" => Ok(Self::Xxh3),
+            _ => Err(format!("unknown hash algorithm: {s}")),
+        }
+    }
+}
+
+/// The result of hashing something with one of the [`HashAlgorithm`]s.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest {
+    bytes: [u8; 32],
+    len: u8,
+}
+
+impl Digest {
+    fn new(digest: &[u8]) -> Self {
+        let mut bytes = [0; 32];
+        bytes[..digest.len()].copy_from_slice(digest);
+        Self { bytes, len: digest.len() as u8 }
+    }
+
+    /// The raw bytes of the hash. How many there are depends on the algorithm.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+
+    /// The hash in lowercase hexadecimal.
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+
+    /// The reverse of [`to_hex`](Self::to_hex). Returns `None` if `hex` isn't
+    /// a valid hash.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if !hex.len().is_multiple_of(2) || hex.len() > 64 {
+            return None;
+        }
+        let bytes: Option<Vec<u8>> = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+        Some(Self::new(&bytes?))
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.as_bytes() {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An in-progress hash, using any of the [`HashAlgorithm`]s.
+///
+/// Some of the hashers are rather large, so they're boxed to keep this from
+/// being the size of the largest.
+pub(crate) enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+            HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Xxh3 => Self::Xxh3(Box::default()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+            Self::Sha256(h) => h.update(data),
+            Self::Xxh3(h) => h.update(data),
+        }
+    }
+
+    /// Like `update`, but for large inputs that are already in memory: BLAKE3
+    /// can split the hashing of a single input across several threads, so
+    /// that one huge file doesn't leave all but one core idle.
+    ///
+    /// This is a tradeoff. The threads come from the same Rayon pool as the
+    /// rest of the scan, so when there are lots of files to hash, it mostly
+    /// just adds overhead; it pays off when a few very large files are all
+    /// that's left. For inputs that aren't large, it's a loss, so this is only
+    /// used for files big enough to be memory-mapped.
+    pub(crate) fn update_parallel(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(h) => {
+                h.update_rayon(data);
+            }
+            _ => self.update(data),
+        }
+    }
+
+    /// Feeds everything from `reader` into the hash.
+    pub(crate) fn update_reader(&mut self, mut reader: impl Read) -> std::io::Result<()> {
+        match self {
+            // BLAKE3 has its own, which is smarter about buffer sizes than
+            // we're going to be.
+            Self::Blake3(h) => {
+                h.update_reader(reader)?;
+            }
+            _ => {
+                std::io::copy(&mut reader, self)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finalize(self) -> Digest {
+        match self {
+            Self::Blake3(h) => Digest::new(h.finalize().as_bytes()),
+            Self::Sha256(h) => Digest::new(&h.finalize()),
+            Self::Xxh3(h) => Digest::new(&h.digest128().to_be_bytes()),
+        }
+    }
+}
+
+impl std::io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,2505 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The duplicate-finding machinery behind the `drupes` command, for use from
+//! other programs.
+//!
+//! Finding duplicates happens in three passes, each of which narrows down the
+//! set of files that might be duplicates using progressively more expensive
+//! checks: [`pass_one`] groups files by size, [`pass_two`] by a hash of their
+//! first few kilobytes, and [`pass_three`] by a hash of their entire contents.
+//!
+//! Most users will want [`find_duplicates`], which runs all three and hands
+//! back the results in a convenient form. The passes are also available
+//! separately, for programs (like `drupes` itself) that want to look at the
+//! intermediate results. Programs that keep watching the same files can use
+//! [`Scanner::index`] and [`Scanner::update`] to only look again at what's
+//! changed.
+
+use std::{cell::Cell, collections::{BTreeMap, HashMap, HashSet}, fs::File, io::{ErrorKind, Read, Seek}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+
+use anyhow::Context as _;
+use globset::{Glob, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rayon::prelude::*;
+use jwalk::WalkDirGeneric;
+
+use gitignore::IgnoreStack;
+
+mod cache;
+mod dirs;
+mod gitignore;
+mod hash;
+mod output;
+
+pub use cache::HashCache;
+pub use dirs::find_duplicate_dirs;
+pub use hash::{Digest, HashAlgorithm};
+pub use output::{write_csv, write_groups, write_json, write_json_lines, write_stats_json, write_table, ListOptions};
+
+/// Default number of bytes at the start of each file that are hashed in
+/// [`pass_two`]; see [`ScanOptions::prehash_size`].
+pub const PREHASH_SIZE: usize = 4 * 1024;
+
+/// Files at least this large are memory-mapped for hashing by [`pass_three`],
+/// unless that's turned off with [`ScanOptions::mmap`]. Below this, the cost
+/// of setting the mapping up isn't worth it.
+pub const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// Options controlling how a scan runs, and which files it will consider.
+///
+/// This is built up by chaining methods, starting from [`ScanOptions::new`],
+/// which considers every non-empty file without following symlinks:
+///
+/// ```
+/// let opts = drupes::ScanOptions::new()
+///     .include_empty(true)
+///     .min_size(1024)
+///     .follow_symlinks(false);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    include_empty: bool,
+    min_size: u64,
+    max_size: Option<u64>,
+    exclude_sizes: Vec<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    exclude: Vec<String>,
+    exclude_from: Vec<PathBuf>,
+    include_ext: Vec<String>,
+    exclude_ext: Vec<String>,
+    follow_symlinks: bool,
+    keep_hardlinks: bool,
+    same_filesystem: bool,
+    max_depth: Option<usize>,
+    include_hidden: bool,
+    respect_gitignore: bool,
+    prehash_size: usize,
+    hash_algorithm: HashAlgorithm,
+    mmap: bool,
+    cache: Option<Arc<HashCache>>,
+    on_read: Option<ReadHook>,
+    retries: u32,
+    strict: bool,
+    verbose: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            include_empty: false,
+            min_size: 0,
+            max_size: None,
+            exclude_sizes: vec![],
+            newer_than: None,
+            older_than: None,
+            exclude: vec![],
+            exclude_from: vec![],
+            include_ext: vec![],
+            exclude_ext: vec![],
+            follow_symlinks: false,
+            keep_hardlinks: false,
+            same_filesystem: false,
+            max_depth: None,
+            include_hidden: false,
+            respect_gitignore: false,
+            prehash_size: PREHASH_SIZE,
+            hash_algorithm: HashAlgorithm::default(),
+            mmap: true,
+            cache: None,
+            on_read: None,
+            retries: 2,
+            strict: false,
+            verbose: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Creates the default set of options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consider empty files, which will all be reported as duplicates of one
+    /// another.
+    pub fn include_empty(self, include_empty: bool) -> Self {
+        Self { include_empty, ..self }
+    }
+
+    /// Ignore files smaller than `bytes`.
+    pub fn min_size(self, bytes: u64) -> Self {
+        Self { min_size: bytes, ..self }
+    }
+
+    /// Ignore files larger than `bytes`, if it's given.
+    pub fn max_size(self, bytes: Option<u64>) -> Self {
+        Self { max_size: bytes, ..self }
+    }
+
+    /// Ignore files that are exactly `bytes` long. This can be called more than
+    /// once to skip more sizes.
+    pub fn exclude_size(mut self, bytes: u64) -> Self {
+        self.exclude_sizes.push(bytes);
+        self
+    }
+
+    /// Ignore files last modified before `time`, if it's given.
+    pub fn newer_than(self, time: Option<SystemTime>) -> Self {
+        Self { newer_than: time, ..self }
+    }
+
+    /// Ignore files last modified after `time`, if it's given.
+    pub fn older_than(self, time: Option<SystemTime>) -> Self {
+        Self { older_than: time, ..self }
+    }
+
+    /// Checks a file's modification time against `newer_than` and
+    /// `older_than`. If either is set and we can't get the modification time,
+    /// the file is left out, since we can't tell whether it's wanted.
+    fn mtime_in_range(&self, meta: &std::fs::Metadata) -> bool {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+        let Ok(mtime) = meta.modified() else {
+            return false;
+        };
+        self.newer_than.is_none_or(|t| mtime >= t)
+            && self.older_than.is_none_or(|t| mtime <= t)
+    }
+
+    /// Skip paths (or filenames) matching the glob `pattern`. Directories that
+    /// match aren't searched. This can be called more than once to add more
+    /// patterns.
+    ///
+    /// A pattern starting with `!` does the opposite, bringing back anything
+    /// that earlier patterns excluded, like in a `.gitignore`: when several
+    /// patterns match, the last one wins. As with a `.gitignore`, this can't
+    /// bring back files inside a directory that's been excluded, since that
+    /// directory is never searched.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Skip files matching the patterns in the file at `path`, which are
+    /// read with the full `.gitignore` syntax: `#` comments, `!` to bring
+    /// files back, a trailing `/` to match only directories, and a leading (or
+    /// inner) `/` to anchor a pattern to the top of each root, as though the
+    /// file were a `.gitignore` there. This can be called more than once, and
+    /// later files take precedence over earlier ones.
+    ///
+    /// These are kept apart from the [`exclude`](Self::exclude) patterns: a
+    /// file is skipped if either says so, so a `!` in one can't bring back a
+    /// file excluded by the other.
+    pub fn exclude_from(mut self, path: impl Into<PathBuf>) -> Self {
+        self.exclude_from.push(path.into());
+        self
+    }
+
+    /// Only consider files with the extension `ext`. This can be called more
+    /// than once to allow more extensions; if it's never called, files with
+    /// any extension are considered.
+    ///
+    /// Extensions are compared ignoring case, and a leading `.` is optional,
+    /// so `.JPG`, `jpg`, and `Jpg` are all the same. An empty extension (or
+    /// just `.`) stands for files with no extension at all.
+    pub fn include_ext(mut self, ext: &str) -> Self {
+        self.include_ext.push(normalize_ext(ext));
+        self
+    }
+
+    /// Skip files with the extension `ext`, which is treated the same way as
+    /// in [`include_ext`](Self::include_ext). This takes precedence over
+    /// `include_ext`, so an extension that's both included and excluded is
+    /// excluded.
+    pub fn exclude_ext(mut self, ext: &str) -> Self {
+        self.exclude_ext.push(normalize_ext(ext));
+        self
+    }
+
+    /// Checks whether a file, with filename `name` and metadata `meta`,
+    /// passes all the filters that can be applied to a single file.
+    fn wants_file(&self, name: &Path, meta: &std::fs::Metadata) -> bool {
+        self.ext_allowed(name)
+            && (meta.len() > 0 || self.include_empty)
+            && meta.len() >= self.min_size
+            && self.max_size.is_none_or(|max| meta.len() <= max)
+            && !self.exclude_sizes.contains(&meta.len())
+            && self.mtime_in_range(meta)
+    }
+
+    /// Compiles the `exclude_from` files, for the walk of `root`.
+    fn exclude_files(&self, root: &Path) -> anyhow::Result<ExcludeFiles> {
+        if self.exclude_from.is_empty() {
+            return Ok(ExcludeFiles(None));
+        }
+        let mut builder = GitignoreBuilder::new(root);
+        for path in &self.exclude_from {
+            if let Some(e) = builder.add(path) {
+                return Err(e).with_context(|| format!("problem reading exclude patterns from {}",
+                    path.display()));
+            }
+        }
+        Ok(ExcludeFiles(Some(builder.build()?)))
+    }
+
+    /// Compiles the `exclude` patterns.
+    fn excludes(&self) -> anyhow::Result<Excludes> {
+        let mut builder = GlobSetBuilder::new();
+        let mut negated = vec![];
+        for pattern in &self.exclude {
+            let (glob, negate) = match pattern.strip_prefix('!') {
+                Some(rest) => (rest, true),
+                None => (pattern.as_str(), false),
+            };
+            builder.add(Glob::new(glob)
+                .with_context(|| format!("bad exclude pattern: {pattern}"))?);
+            negated.push(negate);
+        }
+        Ok(Excludes { set: builder.build()?, negated })
+    }
+
+    /// Checks the extension of `path` against `include_ext` and
+    /// `exclude_ext`.
+    fn ext_allowed(&self, path: &Path) -> bool {
+        if self.include_ext.is_empty() && self.exclude_ext.is_empty() {
+            return true;
+        }
+        let ext = path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        !self.exclude_ext.contains(&ext)
+            && (self.include_ext.is_empty() || self.include_ext.contains(&ext))
+    }
+
+    /// Follow symbolic links to files and directories.
+    pub fn follow_symlinks(self, follow_symlinks: bool) -> Self {
+        Self { follow_symlinks, ..self }
+    }
+
+    /// Consider hardlinks to the same file as separate files, instead of only
+    /// considering one name for each file. Either way, the walk finds every
+    /// name; this decides whether [`dedup_same_files`] collapses them.
+    pub fn keep_hardlinks(self, keep_hardlinks: bool) -> Self {
+        Self { keep_hardlinks, ..self }
+    }
+
+    /// Don't descend into directories on a different filesystem from the root
+    /// they were found under, like `find -xdev`. The directories themselves
+    /// are still seen, just treated as though they were empty.
+    ///
+    /// This only works on platforms where [`device_id`] does; elsewhere, it
+    /// does nothing.
+    pub fn same_filesystem(self, same_filesystem: bool) -> Self {
+        Self { same_filesystem, ..self }
+    }
+
+    /// Only look at files up to `depth` levels below each root, if it's
+    /// given: with a depth of 1, only the files directly inside each root are
+    /// considered.
+    pub fn max_depth(self, depth: Option<usize>) -> Self {
+        Self { max_depth: depth, ..self }
+    }
+
+    /// Look at hidden files and directories, whose names start with `.` (or,
+    /// on Windows, which have the hidden attribute set), while walking. These
+    /// are skipped by default, and hidden directories aren't searched.
+    pub fn include_hidden(self, include_hidden: bool) -> Self {
+        Self { include_hidden, ..self }
+    }
+
+    /// Skip whatever's listed in `.gitignore` and `.ignore` files while
+    /// walking, along with `.git/info/exclude` at the top of the repository
+    /// and the user's global git ignore file (`core.excludesFile`, or
+    /// `~/.config/git/ignore` by default). Files in the directories above
+    /// each root count too, up to the top of the git repository it's in, if
+    /// any. `.git` directories themselves are always skipped when this is
+    /// set.
+    ///
+    /// This only affects [`walk`]: files named directly, as in
+    /// [`walk_files`], are never ignored.
+    pub fn respect_gitignore(self, respect_gitignore: bool) -> Self {
+        Self { respect_gitignore, ..self }
+    }
+
+    /// Hash the first `bytes` of each file in [`pass_two`], instead of the
+    /// default [`PREHASH_SIZE`]. Files whose first `bytes` all hash differently
+    /// don't need to be read any further, so for files that tend to start the
+    /// same way (e.g. logs with a long common header), a larger value can save
+    /// a lot of reading.
+    ///
+    /// Zero skips prehashing: `pass_two` doesn't open any files, and just
+    /// groups them by size, so that [`pass_three`] hashes each one from the
+    /// start, giving plain hashes like [`hash_file`]'s. For a small number of
+    /// small files, that can be quicker.
+    pub fn prehash_size(self, bytes: usize) -> Self {
+        Self { prehash_size: bytes, ..self }
+    }
+
+    /// Compare files using `algorithm`, instead of BLAKE3.
+    pub fn hash_algorithm(self, algorithm: HashAlgorithm) -> Self {
+        Self { hash_algorithm: algorithm, ..self }
+    }
+
+    /// Whether to memory-map large files in [`pass_three`], instead of reading
+    /// them in the usual way. This is on by default, since it's usually
+    /// faster; files at least [`MMAP_THRESHOLD`] bytes long are mapped, and if
+    /// mapping a file fails, it gets read the usual way instead.
+    pub fn mmap(self, mmap: bool) -> Self {
+        Self { mmap, ..self }
+    }
+
+    /// Look up hashes in `cache` in [`pass_two`] and [`pass_three`], instead
+    /// of reading files that haven't changed since they were cached, and
+    /// record the hashes of any files that are read. It's up to the caller to load and
+    /// save the cache.
+    pub fn cache(self, cache: Option<Arc<HashCache>>) -> Self {
+        Self { cache, ..self }
+    }
+
+    /// Call `on_read` with the path of each file whose contents [`pass_two`]
+    /// or [`pass_three`] is about to read, which is handy for keeping count of
+    /// how much work a scan does, or for checking that the
+    /// [cache](Self::cache) is saving any. It can be called from several
+    /// threads at once.
+    pub fn on_read(self, on_read: impl Fn(&Path) + Send + Sync + 'static) -> Self {
+        Self { on_read: Some(ReadHook(Arc::new(on_read))), ..self }
+    }
+
+    /// Calls the [`on_read`](Self::on_read) function, if there is one.
+    fn reading(&self, path: &Path) {
+        if let Some(ReadHook(on_read)) = &self.on_read {
+            on_read(path);
+        }
+    }
+
+    /// How many more times to try reading a file in [`pass_two`] and
+    /// [`pass_three`], if reading it fails in a way that might just be a
+    /// hiccup (say, a network filesystem timing out), before giving up on it.
+    /// Errors that won't go away by themselves, like the file not existing,
+    /// aren't retried. The default is 2.
+    pub fn retries(self, retries: u32) -> Self {
+        Self { retries, ..self }
+    }
+
+    /// Stop with an error if any directory or file can't be read during the
+    /// walk, instead of printing a warning and carrying on without it.
+    pub fn strict(self, strict: bool) -> Self {
+        Self { strict, ..self }
+    }
+
+    /// Print information about each pass to stderr as it runs.
+    pub fn verbose(self, verbose: bool) -> Self {
+        Self { verbose, ..self }
+    }
+}
+
+/// The function given to [`ScanOptions::on_read`], in a form that can be
+/// cloned and debug-printed along with the rest of the options.
+#[derive(Clone)]
+struct ReadHook(Arc<dyn Fn(&Path) + Send + Sync>);
+
+impl std::fmt::Debug for ReadHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReadHook")
+    }
+}
+
+/// The compiled form of the [`ScanOptions::exclude_from`] files, for one root,
+/// if there are any.
+struct ExcludeFiles(Option<Gitignore>);
+
+impl ExcludeFiles {
+    /// Checks whether the file or directory at `path`, which is under the root
+    /// and was found by searching down from it, should be left out.
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        self.0.as_ref().is_some_and(|patterns| patterns.matched(path, is_dir).is_ignore())
+    }
+
+    /// Checks whether the file at `path` should be left out, either itself or
+    /// because it's in a directory that should be, for files that weren't
+    /// found by searching down to them. Patterns that need to be anchored
+    /// somewhere can't match paths outside the root.
+    fn matches_file(&self, path: &Path) -> bool {
+        let Some(patterns) = &self.0 else {
+            return false;
+        };
+        // This panics if given a path outside the root, so those just get
+        // checked on their own.
+        let under_root = match path.strip_prefix(patterns.path()) {
+            Ok(rel) => Some(rel),
+            Err(_) if path.is_relative() => Some(path),
+            Err(_) => None,
+        };
+        match under_root {
+            Some(rel) => patterns.matched_path_or_any_parents(rel, false).is_ignore(),
+            None => patterns.matched(path, false).is_ignore(),
+        }
+    }
+}
+
+/// The compiled form of the [`ScanOptions::exclude`] patterns.
+struct Excludes {
+    set: globset::GlobSet,
+    /// Whether each pattern in `set`, in order, started with a `!`.
+    negated: Vec<bool>,
+}
+
+impl Excludes {
+    /// Checks whether the file or directory at `path`, whose name is `name`,
+    /// should be left out.
+    fn matches(&self, path: &Path, name: impl AsRef<Path>) -> bool {
+        if !self.negated.contains(&true) {
+            return self.set.is_match(path) || self.set.is_match(name);
+        }
+        // The last pattern to match either the path or the name decides.
+        let last = self.set.matches(path).into_iter()
+            .chain(self.set.matches(name))
+            .max();
+        last.is_some_and(|i| !self.negated[i])
+    }
+}
+
+/// Puts an extension into the form `ScanOptions` compares against: lowercase,
+/// without the leading dot.
+fn normalize_ext(ext: &str) -> String {
+    ext.strip_prefix('.').unwrap_or(ext).to_lowercase()
+}
+
+/// A set of files with identical contents.
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    /// Hash of the contents of the files, as produced by [`pass_three`]. For
+    /// BLAKE3, this isn't quite the same as just hashing the file, but it
+    /// identifies the contents just as well; see
+    /// [`ScanOptions::hash_algorithm`].
+    pub hash: Digest,
+    /// Size of each file, in bytes.
+    pub size: u64,
+    /// The files, in sorted order. There will always be at least two.
+    ///
+    /// When writing groups out with [`write_groups`] and friends, the first
+    /// file is the one that's kept, so you may want to reorder them first.
+    pub paths: Vec<PathBuf>,
+}
+
+/// Statistics about the results of a scan, from [`summarize`].
+///
+/// The `Display` impl prints these the way `drupes --summarize` does, and
+/// [`write_stats_json`] the way `drupes --stats-json` does.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ScanStats {
+    /// Number of duplicate files, not counting the first in each group.
+    pub dupe_count: usize,
+    /// Number of groups of duplicates.
+    pub set_count: usize,
+    /// Space, in bytes, that would be freed by removing all the duplicates.
+    pub reclaimable_bytes: u64,
+    /// Number of files that had the same size as some other file, and so were
+    /// looked at more closely.
+    pub total_files: usize,
+    /// Number of distinct sizes those files had.
+    pub size_classes: usize,
+    /// Number of distinct sizes and prehashes found by [`pass_two`].
+    pub prehash_groups: usize,
+    /// Number of files (and directories) that couldn't be read, and so were
+    /// left out. This isn't worked out by [`summarize`]; it's up to the caller
+    /// to fill it in, if they're keeping count (see [`Progress::errors`]).
+    pub unreadable: u64,
+    /// How long the scan took, and how many bytes it read from files, if the
+    /// caller was timing it (as `drupes --timing` does). Like `unreadable`,
+    /// this is left for the caller to fill in.
+    #[serde(skip)]
+    pub timing: Option<(Duration, u64)>,
+}
+
+impl std::fmt::Display for ScanStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} duplicate files (in {} sets), occupying {}",
+            self.dupe_count,
+            self.set_count,
+            size::Size::from_bytes(self.reclaimable_bytes))?;
+        writeln!(f, "checked {} files in {} size classes",
+            self.total_files,
+            self.size_classes)?;
+        write!(f, "prehashing identified {} groups", self.prehash_groups)?;
+        if self.unreadable > 0 {
+            write!(f, "\n{} files could not be read and were excluded",
+                self.unreadable)?;
+        }
+        if let Some((elapsed, bytes_read)) = self.timing {
+            let rate = bytes_read as f64 / elapsed.as_secs_f64();
+            write!(f, "\nfinished in {elapsed:.2?}, after reading {} ({}/s)",
+                size::Size::from_bytes(bytes_read),
+                size::Size::from_bytes(rate as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Works out statistics about a scan.
+///
+/// `sized` is the result of [`pass_one`], and `prehash_groups` the number of
+/// entries in the result of [`pass_two`]. `groups` gives the size of each file
+/// in a group of duplicates, and the number of files in the group; groups
+/// with fewer than two files are ignored.
+pub fn summarize(
+    sized: &BTreeMap<u64, Vec<PathBuf>>,
+    prehash_groups: usize,
+    groups: impl IntoIterator<Item = (u64, usize)>,
+) -> ScanStats {
+    let mut stats = ScanStats {
+        total_files: sized.values().map(Vec::len).sum(),
+        size_classes: sized.len(),
+        prehash_groups,
+        ..ScanStats::default()
+    };
+    for (size, count) in groups {
+        if count > 1 {
+            stats.set_count += 1;
+            stats.dupe_count += count - 1;
+            stats.reclaimable_bytes += size * (count as u64 - 1);
+        }
+    }
+    stats
+}
+
+/// The parts of a scan, as reported in [`Progress`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Phase {
+    /// Walking the filesystem to find files ([`pass_one`]).
+    Walking,
+    /// Hashing the start of each file ([`pass_two`]).
+    Prehashing,
+    /// Hashing the entire contents of each file ([`pass_three`]).
+    FullHashing,
+    /// Comparing the files in a group byte-for-byte
+    /// ([`first_mismatch_reporting`]).
+    Verifying,
+}
+
+/// A report on how far along a scan is, passed to a progress callback.
+///
+/// Counts start over from zero in each phase.
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    /// What the scan is currently doing.
+    pub phase: Phase,
+    /// Number of files dealt with so far in this phase.
+    pub files: u64,
+    /// Number of bytes read from files so far in this phase.
+    pub bytes_hashed: u64,
+    /// Number of files (or, while walking, directories) so far in this phase
+    /// that couldn't be read, and were left out. These are included in
+    /// `files`.
+    pub errors: u64,
+}
+
+/// Searches `roots` for duplicate files, running all three passes.
+///
+/// Returns the groups of duplicates found, sorted by path.
+pub fn find_duplicates(
+    roots: Vec<PathBuf>,
+    opts: &ScanOptions,
+) -> anyhow::Result<Vec<DuplicateGroup>> {
+    find_duplicates_with_progress(roots, opts, |_| ())
+}
+
+/// Searches `roots` for duplicate files, like [`find_duplicates`], calling
+/// `on_progress` each time a file is dealt with.
+///
+/// `on_progress` will be called from several threads at once, so it has to be
+/// `Sync`, and it should be quick about it.
+pub fn find_duplicates_with_progress(
+    roots: Vec<PathBuf>,
+    opts: &ScanOptions,
+    on_progress: impl Fn(Progress) + Sync,
+) -> anyhow::Result<Vec<DuplicateGroup>> {
+    Scanner::new(opts.clone()).scan_with_progress(&roots, on_progress)
+}
+
+/// Finds duplicates, like [`find_duplicates`], with options that are set up
+/// once and then used for as many scans as you like, for programs that scan
+/// over and over.
+///
+/// A scanner can be given its own Rayon thread pool to do its hashing on,
+/// which stays around between scans; otherwise it uses Rayon's global pool.
+///
+/// ```no_run
+/// let scanner = drupes::Scanner::new(drupes::ScanOptions::new())
+///     .threads(2)?;
+/// for _ in 0..2 {
+///     let groups = scanner.scan(&["/srv/uploads".into()])?;
+///     println!("{} groups of duplicates", groups.len());
+/// }
+/// # anyhow::Ok(())
+/// ```
+#[derive(Debug)]
+pub struct Scanner {
+    opts: ScanOptions,
+    /// This is shared with the thread behind [`scan_iter`](Self::scan_iter).
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl Scanner {
+    /// Creates a scanner that scans with `opts`, on Rayon's global pool.
+    pub fn new(opts: ScanOptions) -> Self {
+        Self { opts, pool: None }
+    }
+
+    /// Does the scanner's hashing on `pool`, instead of Rayon's global pool.
+    pub fn pool(self, pool: rayon::ThreadPool) -> Self {
+        Self { pool: Some(Arc::new(pool)), ..self }
+    }
+
+    /// Does the scanner's hashing on a new pool of its own, with `threads`
+    /// threads. As with Rayon, 0 means one per CPU.
+    pub fn threads(self, threads: usize) -> anyhow::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()?;
+        Ok(self.pool(pool))
+    }
+
+    /// The options this scanner scans with.
+    pub fn options(&self) -> &ScanOptions {
+        &self.opts
+    }
+
+    /// Searches `roots` for duplicate files, returning the groups found,
+    /// sorted by path.
+    pub fn scan(&self, roots: &[PathBuf]) -> anyhow::Result<Vec<DuplicateGroup>> {
+        self.scan_with_progress(roots, |_| ())
+    }
+
+    /// Like [`scan`](Self::scan), but calls `on_progress` each time a file is
+    /// dealt with, as for [`find_duplicates_with_progress`].
+    pub fn scan_with_progress(
+        &self,
+        roots: &[PathBuf],
+        on_progress: impl Fn(Progress) + Sync,
+    ) -> anyhow::Result<Vec<DuplicateGroup>> {
+        let opts = &self.opts;
+        let start = Instant::now();
+        // The walk stays out of the pool: jwalk does its own thing, and would
+        // deadlock if it were asked to do it on a pool with only one thread.
+        let paths = pass_one(roots, opts, start, &on_progress)?;
+        let hash = || {
+            let prehashed = pass_two(&paths, opts, start, &on_progress);
+            pass_three(prehashed, opts, start, &on_progress)
+        };
+        let hashed = match &self.pool {
+            Some(pool) => pool.install(hash),
+            None => hash(),
+        };
+
+        let mut groups = hashed.into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .filter_map(|((size, hash), mut files)| {
+                files.sort();
+                dedup_same_files(&mut files, opts);
+                (files.len() > 1).then(|| DuplicateGroup {
+                    hash,
+                    size,
+                    paths: files.into_iter().map(Path::to_owned).collect(),
+                })
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+        Ok(groups)
+    }
+
+    /// Like [`scan`](Self::scan), but hands back each group as soon as it's
+    /// been found, instead of collecting them all first, so that they can be
+    /// dealt with (and dropped) one at a time.
+    ///
+    /// The walk happens before this returns, and any error from it is
+    /// returned here. The hashing happens on another thread while the groups
+    /// are being read, and stops early if the iterator is dropped. Groups turn
+    /// up in no particular order, though the paths within each are sorted.
+    ///
+    /// ```no_run
+    /// let scanner = drupes::Scanner::new(drupes::ScanOptions::new());
+    /// for group in scanner.scan_iter(&["/srv/uploads".into()])? {
+    ///     println!("{} copies of {}", group.paths.len(), group.paths[0].display());
+    /// }
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn scan_iter(&self, roots: &[PathBuf]) -> anyhow::Result<DuplicateGroups> {
+        let opts = self.opts.clone();
+        let pool = self.pool.clone();
+        let start = Instant::now();
+        let paths = pass_one(roots, &opts, start, |_| ())?;
+
+        // A few groups can be waiting to be read, but no more than that,
+        // since the point is not to pile them up.
+        let (tx, rx) = std::sync::mpsc::sync_channel(16);
+        let worker = std::thread::spawn(move || {
+            let hash = || {
+                let prehashed = pass_two_owned(paths, &opts, start, |_| ());
+                // Sending only fails if nobody's listening any more, in which
+                // case we can stop.
+                let _ = pass_three_each(prehashed, &opts, start, |_| (), |(size, hash), mut paths| {
+                    paths.sort();
+                    dedup_same_files(&mut paths, &opts);
+                    if paths.len() > 1 {
+                        tx.send(DuplicateGroup { hash, size, paths }).map_err(drop)?;
+                    }
+                    Ok::<_, ()>(())
+                });
+            };
+            match &pool {
+                Some(pool) => pool.install(hash),
+                None => hash(),
+            }
+        });
+        Ok(DuplicateGroups { groups: rx, worker: Some(worker) })
+    }
+
+    /// Searches `roots` for duplicate files, like [`scan`](Self::scan), but
+    /// keeps what it learns about every file, so that the results can be
+    /// brought up to date with [`update`](Self::update) when some of the files
+    /// change, instead of scanning everything again. Get the groups of
+    /// duplicates out with [`groups`](Self::groups).
+    ///
+    /// An index should only be used with the scanner that made it, or one
+    /// with the same options, since its hashes depend on them.
+    ///
+    /// ```no_run
+    /// let scanner = drupes::Scanner::new(drupes::ScanOptions::new());
+    /// let mut index = scanner.index(&["/srv/uploads".into()])?;
+    /// // ...and then, when a filesystem watcher says something's changed:
+    /// scanner.update(&mut index, &["/srv/uploads/new.jpg".into()])?;
+    /// println!("{} groups of duplicates", scanner.groups(&index).len());
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn index(&self, roots: &[PathBuf]) -> anyhow::Result<ScanIndex> {
+        let mut index = ScanIndex { roots: roots.to_vec(), files: HashMap::new() };
+        for (size, paths) in walk(roots, &self.opts, Instant::now(), |_| ())? {
+            for path in paths {
+                index.files.insert(path, IndexedFile::new(size));
+            }
+        }
+        self.refresh(&mut index);
+        Ok(index)
+    }
+
+    /// Brings `index` up to date, given the paths that have changed since it
+    /// was made (or last updated), such as from a filesystem watcher.
+    ///
+    /// Each path can be a file or directory that's been created, changed, or
+    /// removed. A directory is searched again, as if it were one of the roots.
+    /// Only files found this way whose size or modification time have changed
+    /// are read again, and then only as much as is needed to tell whether
+    /// they have copies; everything else keeps the hashes it had. Paths that
+    /// aren't under any of the index's roots are ignored, as are hidden ones,
+    /// unless the options say to include them.
+    pub fn update(&self, index: &mut ScanIndex, changed: &[PathBuf]) -> anyhow::Result<()> {
+        // The `exclude_from` patterns are anchored at the top of the root, not
+        // wherever the search below starts, so they're checked separately.
+        let unanchored = ScanOptions { exclude_from: vec![], ..self.opts.clone() };
+        for path in changed {
+            let Some(root) = index.roots.iter().find(|root| path.starts_with(root)) else {
+                continue;
+            };
+            let exclude_files = self.opts.exclude_files(root)?;
+            // Searching from `path` wouldn't notice that it's somewhere the
+            // search from the root would have skipped.
+            let hidden = path.strip_prefix(root).is_ok_and(|rel| rel.components()
+                .any(|part| part.as_os_str().as_encoded_bytes().starts_with(b".")));
+            if hidden && !self.opts.include_hidden {
+                continue;
+            }
+            let found = match std::fs::metadata(path) {
+                Ok(meta) if meta.is_dir() => walk(std::slice::from_ref(path), &unanchored,
+                    Instant::now(), |_| ())?,
+                // This checks the options, and says why if the file's no
+                // good, which is what we'd want here too.
+                Ok(_) => walk_files(std::slice::from_ref(path), &unanchored, Instant::now(),
+                    |_| ())?,
+                // It's gone, along with anything that was in it.
+                Err(_) => BTreeMap::new(),
+            };
+            let found: HashMap<PathBuf, u64> = found.into_iter()
+                .flat_map(|(size, paths)| paths.into_iter().map(move |path| (path, size)))
+                .filter(|(path, _)| !exclude_files.matches_file(path))
+                .collect();
+            index.files.retain(|f, _| !f.starts_with(path) || found.contains_key(f));
+            for (path, size) in found {
+                let unchanged = index.files.get(&path).is_some_and(|file| {
+                    file.size == size && file.modified.is_some()
+                        && std::fs::metadata(&path).and_then(|meta| meta.modified()).ok()
+                            == file.modified
+                });
+                if !unchanged {
+                    index.files.insert(path, IndexedFile::new(size));
+                }
+            }
+        }
+        self.refresh(index);
+        Ok(())
+    }
+
+    /// The groups of duplicates in `index`, sorted by path, as
+    /// [`scan`](Self::scan) would have found them.
+    pub fn groups(&self, index: &ScanIndex) -> Vec<DuplicateGroup> {
+        let mut by_hash: HashMap<(u64, Digest), Vec<&Path>> = HashMap::new();
+        for (path, file) in &index.files {
+            if let Some(hash) = file.hash {
+                by_hash.entry((file.size, hash)).or_default().push(path);
+            }
+        }
+        let mut groups = by_hash.into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .filter_map(|((size, hash), mut files)| {
+                files.sort();
+                dedup_same_files(&mut files, &self.opts);
+                (files.len() > 1).then(|| DuplicateGroup {
+                    hash,
+                    size,
+                    paths: files.into_iter().map(Path::to_owned).collect(),
+                })
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.paths.cmp(&b.paths));
+        groups
+    }
+
+    /// Fills in whatever's missing from `index`: the prehash of every file
+    /// whose size isn't unique, and the full hash of every file whose prehash
+    /// isn't. Files that can't be read are left without, and tried again next
+    /// time.
+    fn refresh(&self, index: &mut ScanIndex) {
+        let opts = &self.opts;
+        let start = Instant::now();
+        let mut sizes: HashMap<u64, usize> = HashMap::new();
+        for file in index.files.values() {
+            *sizes.entry(file.size).or_default() += 1;
+        }
+        let mut unprehashed: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+        for (path, file) in &index.files {
+            if file.prehash.is_none() && sizes[&file.size] > 1 {
+                unprehashed.entry(file.size).or_default().push(path.clone());
+            }
+        }
+
+        let prehash = || {
+            // Note the modification times first, so that if a file changes
+            // while it's being read, it won't look unchanged later.
+            let modified: Vec<(PathBuf, Option<SystemTime>)> = unprehashed.values()
+                .flatten()
+                .par_bridge()
+                .map(|path| {
+                    let modified = std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+                    (path.clone(), modified)
+                })
+                .collect();
+            (modified, pass_two_owned(unprehashed, opts, start, |_| ()))
+        };
+        let (modified, prehashed) = match &self.pool {
+            Some(pool) => pool.install(prehash),
+            None => prehash(),
+        };
+        for (path, modified) in modified {
+            if let Some(file) = index.files.get_mut(&path) {
+                file.modified = modified;
+            }
+        }
+        for ((_, prehash), paths) in prehashed {
+            for path in paths {
+                if let Some(file) = index.files.get_mut(&path) {
+                    file.prehash = Some(prehash);
+                }
+            }
+        }
+
+        let mut prehashes: HashMap<(u64, Digest), usize> = HashMap::new();
+        for file in index.files.values() {
+            if let Some(prehash) = file.prehash {
+                *prehashes.entry((file.size, prehash)).or_default() += 1;
+            }
+        }
+        let unhashed: Vec<((u64, Digest), &Path)> = index.files.iter()
+            .filter_map(|(path, file)| {
+                let key = (file.size, file.prehash?);
+                (file.hash.is_none() && prehashes[&key] > 1).then_some((key, path.as_path()))
+            })
+            .collect();
+        let hash = || {
+            let progress = ProgressCounter::new(Phase::FullHashing, |_| ());
+            unhashed.into_par_iter()
+                .filter_map(|(key, path)| hash_rest(key, path.to_owned(), opts, &progress))
+                .collect::<Vec<_>>()
+        };
+        let hashed = match &self.pool {
+            Some(pool) => pool.install(hash),
+            None => hash(),
+        };
+        for ((_, hash), path) in hashed {
+            if let Some(file) = index.files.get_mut(&path) {
+                file.hash = Some(hash);
+            }
+        }
+    }
+}
+
+/// The groups of duplicates from [`Scanner::scan_iter`], as they're found.
+#[derive(Debug)]
+pub struct DuplicateGroups {
+    groups: std::sync::mpsc::Receiver<DuplicateGroup>,
+    worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Iterator for DuplicateGroups {
+    type Item = DuplicateGroup;
+
+    fn next(&mut self) -> Option<DuplicateGroup> {
+        match self.groups.recv() {
+            Ok(group) => Some(group),
+            // The worker's done, one way or another. If it panicked, pass
+            // that on, rather than making it look like there was nothing
+            // left to find.
+            Err(_) => {
+                if let Some(Err(panic)) = self.worker.take().map(|worker| worker.join()) {
+                    std::panic::resume_unwind(panic);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// What [`Scanner::index`] found out about the files under some roots, which
+/// [`Scanner::update`] keeps up to date as they change.
+#[derive(Clone, Debug)]
+pub struct ScanIndex {
+    roots: Vec<PathBuf>,
+    /// Every file found, whether or not it has a copy.
+    files: HashMap<PathBuf, IndexedFile>,
+}
+
+/// What a [`ScanIndex`] knows about one file. The hashes are only filled in
+/// when they're needed to tell whether the file has a copy.
+#[derive(Clone, Debug)]
+struct IndexedFile {
+    size: u64,
+    /// The file's modification time, as of just before it was read, so that
+    /// we can tell if it's changed since.
+    modified: Option<SystemTime>,
+    prehash: Option<Digest>,
+    /// The full hash, as from `pass_three`.
+    hash: Option<Digest>,
+}
+
+impl IndexedFile {
+    fn new(size: u64) -> Self {
+        Self { size, modified: None, prehash: None, hash: None }
+    }
+}
+
+/// The result of comparing two directory trees with [`diff_trees`].
+#[derive(Clone, Debug, Default)]
+pub struct TreeDiff {
+    /// Contents found in both trees, along with where they are in each. Sorted
+    /// by path.
+    pub in_both: Vec<SharedFiles>,
+    /// Files in the base tree whose contents don't appear anywhere in the
+    /// other tree. Sorted.
+    pub only_in_base: Vec<PathBuf>,
+    /// Files in the other tree whose contents don't appear anywhere in the
+    /// base tree. Sorted.
+    pub only_in_other: Vec<PathBuf>,
+}
+
+/// A set of files with identical contents, some under each of the trees given
+/// to [`diff_trees`].
+#[derive(Clone, Debug)]
+pub struct SharedFiles {
+    /// Hash of the contents of the files, as in [`DuplicateGroup::hash`].
+    pub hash: Digest,
+    /// Size of each file, in bytes.
+    pub size: u64,
+    /// The files under the base tree, in sorted order. There's at least one.
+    pub base: Vec<PathBuf>,
+    /// The files under the other tree, in sorted order. There's at least one.
+    pub other: Vec<PathBuf>,
+}
+
+/// Compares the contents of the files under `base` with those under `other`,
+/// regardless of their names: which contents are in both, and which are only
+/// in one or the other.
+///
+/// This answers questions like "is everything in this backup already in my
+/// archive?" It goes through the same passes as [`find_duplicates`], but keeps
+/// track of which tree each file came from, and also reports the files that
+/// have no duplicates.
+///
+/// Files that can't be read are left out entirely. It's an error for one tree
+/// to be inside the other.
+pub fn diff_trees(
+    base: &Path,
+    other: &Path,
+    opts: &ScanOptions,
+) -> anyhow::Result<TreeDiff> {
+    let (b, o) = (base.canonicalize()?, other.canonicalize()?);
+    if b.starts_with(&o) || o.starts_with(&b) {
+        anyhow::bail!("can't compare {} and {}, since one is inside the other",
+            base.display(), other.display());
+    }
+
+    let start = Instant::now();
+    // Walk the trees separately, so we know which files came from where.
+    let base_files = walk(&[base.to_owned()], opts, start, |_| ())?;
+    let other_files = walk(&[other.to_owned()], opts, start, |_| ())?;
+    let in_base: HashSet<&Path> = base_files.values().flatten().map(PathBuf::as_path).collect();
+
+    let mut diff = TreeDiff::default();
+    // Sets the files in `files` aside according to which trees they're in,
+    // given that they all have the same contents.
+    let mut sort_out = |hash: Option<Digest>, size: u64, files: &[&Path]| {
+        let (mut b, mut o) = (vec![], vec![]);
+        for f in files {
+            if in_base.contains(f) {
+                b.push(f.to_path_buf());
+            } else {
+                o.push(f.to_path_buf());
+            }
+        }
+        match hash {
+            Some(hash) if !b.is_empty() && !o.is_empty() => {
+                b.sort();
+                o.sort();
+                diff.in_both.push(SharedFiles { hash, size, base: b, other: o });
+            }
+            _ => {
+                diff.only_in_base.extend(b);
+                diff.only_in_other.extend(o);
+            }
+        }
+    };
+
+    // Only files of a size that turns up in both trees could possibly be in
+    // both; the rest can be sorted out right away.
+    let mut shared: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for (size, files) in base_files.iter().chain(&other_files) {
+        if base_files.contains_key(size) && other_files.contains_key(size) {
+            shared.entry(*size).or_default().extend(files.iter().cloned());
+        } else {
+            let files: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+            sort_out(None, *size, &files);
+        }
+    }
+
+    // Files with a unique prehash have no match, and won't be read any
+    // further, so they need to be sorted out here too.
+    let prehashed = pass_two(&shared, opts, start, |_| ());
+    for ((size, _), files) in &prehashed {
+        if files.len() == 1 {
+            sort_out(None, *size, files);
+        }
+    }
+    let hashed = pass_three(prehashed, opts, start, |_| ());
+    for ((size, hash), files) in &hashed {
+        sort_out(Some(*hash), *size, files);
+    }
+
+    diff.in_both.sort_by(|a, b| (&a.base, &a.other).cmp(&(&b.base, &b.other)));
+    diff.only_in_base.sort();
+    diff.only_in_other.sort();
+    Ok(diff)
+}
+
+/// Identity of a file on disk, for detecting hardlinks: device and inode
+/// numbers.
+pub type FileId = (u64, u64);
+
+/// Gets the identity of the file described by `meta`, if the platform gives us
+/// a cheap way of finding it.
+#[cfg(unix)]
+pub fn file_id(meta: &std::fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+/// Gets the identity of the file described by `meta`, if the platform gives us
+/// a cheap way of finding it.
+///
+/// We don't have one here, so every path is treated as distinct.
+#[cfg(not(unix))]
+pub fn file_id(_meta: &std::fs::Metadata) -> Option<FileId> {
+    None
+}
+
+/// Removes any path from `files` that leads to the same file as an earlier
+/// one, so that no file gets reported (or deleted) as a duplicate of itself.
+/// Paths are the same if they're the same once canonicalized, which catches
+/// a file reached through overlapping roots or symlinks; unless `opts` says to
+/// keep hardlinks, paths to the same [`file_id`] count as the same too.
+///
+/// Paths that can't be canonicalized are left alone.
+pub fn dedup_same_files<P: AsRef<Path>>(files: &mut Vec<P>, opts: &ScanOptions) {
+    let mut seen_paths = HashSet::new();
+    let mut seen_ids = HashSet::new();
+    files.retain(|f| {
+        let Ok(real) = f.as_ref().canonicalize() else {
+            return true;
+        };
+        let id = std::fs::metadata(&real).ok().as_ref()
+            .and_then(file_id)
+            .filter(|_| !opts.keep_hardlinks);
+        seen_paths.insert(real) && id.is_none_or(|id| seen_ids.insert(id))
+    });
+}
+
+/// Checks whether the file described by `meta` looks sparse: it takes up
+/// less space on disk than its length, because some of it is holes that read
+/// as zeros. VM images often are.
+///
+/// Sparse files hash correctly, so this is only a hint that hashing one might
+/// take longer than the space it uses would suggest, since every byte of it
+/// still has to be read. Files that are compressed by the filesystem can look
+/// sparse too.
+#[cfg(unix)]
+pub fn is_sparse(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // `blocks` is always in units of 512 bytes, whatever the block size.
+    meta.blocks().saturating_mul(512) < meta.len()
+}
+
+/// Checks whether the file described by `meta` looks sparse.
+///
+/// We don't have a cheap way of finding out here, so nothing is.
+#[cfg(not(unix))]
+pub fn is_sparse(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Checks whether the file described by `meta` has Windows's hidden
+/// attribute set.
+#[cfg(windows)]
+fn has_hidden_attribute(meta: &std::fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+/// Checks whether the file described by `meta` has Windows's hidden
+/// attribute set, which it never does elsewhere.
+#[cfg(not(windows))]
+fn has_hidden_attribute(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Gets the ID of the device (i.e. filesystem) holding the file described by
+/// `meta`, if the platform gives us a cheap way of finding it.
+#[cfg(unix)]
+pub fn device_id(meta: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev())
+}
+
+/// Gets the ID of the device (i.e. filesystem) holding the file described by
+/// `meta`, if the platform gives us a cheap way of finding it.
+///
+/// We don't have one here, so nothing is ever considered to be on a different
+/// filesystem.
+#[cfg(not(unix))]
+pub fn device_id(_meta: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// PASS ONE
+///
+/// Traverse the requested parts of the filesystem, collating files by size
+/// (i.e. producing a map with file sizes as keys, and lists of files as
+/// values).
+///
+/// Any value in the map with more than one path represents a "file size
+/// group," which is a potential duplicate group. On the other hand, any value
+/// in the map containing only _one_ path need not be considered further, so
+/// those are dropped before returning.
+///
+/// We do this because, generally speaking, getting the size of a file is much
+/// cheaper than reading its contents, and in practice file sizes are
+/// _relatively_ unique.
+///
+/// This is the same as [`walk`] followed by [`discard_unique_sizes`], which
+/// can be called separately if you need to know about every file.
+pub fn pass_one(
+    roots: &[PathBuf],
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress),
+) -> anyhow::Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let mut paths = walk(roots, opts, start, on_progress)?;
+    discard_unique_sizes(&mut paths, opts);
+    Ok(paths)
+}
+
+/// The first half of [`pass_one`]: traverses `roots`, collating every file
+/// that passes the filters in `opts` by size, including those whose size is
+/// unique.
+///
+/// A root that's inside another root (or the same as one) isn't searched
+/// again, so each file is only found once; a warning is printed for each one
+/// that's skipped. Every name for a file with several hardlinks is kept,
+/// though, whatever the options say: those are only collapsed down to one by
+/// [`dedup_same_files`], once the groups of duplicates have been worked out,
+/// so that anything that wants to know about every path still can.
+pub fn walk(
+    roots: &[PathBuf],
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress),
+) -> anyhow::Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let excludes = Arc::new(opts.excludes()?);
+
+    // Canonical paths of every directory we've walked into, used to avoid
+    // walking the same directory twice (or forever) when following symlinks.
+    // This is shared across roots, since a link in one root might point into
+    // another. (jwalk also runs the roots themselves through
+    // `process_read_dir`, so they get recorded here too.)
+    let visited_dirs = Arc::new(Mutex::new(HashSet::new()));
+    let mut paths: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    // These are `Cell`s so that they can be shared with `skip_or_bail` below.
+    let files_seen = Cell::new(0);
+    let walk_errors = Cell::new(0);
+    for root in distinct_roots(roots) {
+        if opts.verbose {
+            eprintln!("{:?} starting walk of {}",
+                start.elapsed(), root.display());
+        }
+
+        let excludes = Arc::clone(&excludes);
+        let exclude_files = opts.exclude_files(root)?;
+        let visited_dirs = Arc::clone(&visited_dirs);
+        let follow_symlinks = opts.follow_symlinks;
+        // The device the root is on, if we're staying on it. If we can't
+        // find out, the walk will fail on its own in a moment.
+        let root_dev = if opts.same_filesystem {
+            std::fs::metadata(root).ok().as_ref().and_then(device_id)
+        } else {
+            None
+        };
+        let respect_gitignore = opts.respect_gitignore;
+        let include_hidden = opts.include_hidden;
+        let ignores = if respect_gitignore {
+            IgnoreStack::for_root(root)
+        } else {
+            IgnoreStack::default()
+        };
+        let walk = WalkDirGeneric::<(IgnoreStack, ())>::new(root)
+            .follow_links(follow_symlinks)
+            .skip_hidden(!opts.include_hidden)
+            .max_depth(opts.max_depth.unwrap_or(usize::MAX))
+            .root_read_dir_state(ignores)
+            // Apply exclusions as each directory is read, instead of filtering
+            // the stream of entries it produces. Removing a directory here
+            // keeps jwalk from descending into it at all, which matters a lot
+            // for things like `.git`, and either way we never pay for getting
+            // metadata on an excluded file.
+            .process_read_dir(move |depth, path, ignores, children| {
+                // jwalk calls this once for the root itself, with no depth,
+                // and the root is never ignored. Past that, each directory's
+                // ignore files apply to everything under it.
+                let check_ignores = respect_gitignore && depth.is_some();
+                if check_ignores {
+                    ignores.push_dir(path);
+                }
+                // jwalk only knows about names starting with a dot, so on
+                // Windows, we check for the hidden attribute ourselves.
+                if cfg!(windows) && !include_hidden && depth.is_some() {
+                    children.retain(|child| child.as_ref().map_or(true, |entry| {
+                        !entry.metadata().is_ok_and(|meta| has_hidden_attribute(&meta))
+                    }));
+                }
+                children.retain(|child| match child {
+                    Ok(entry) => !(excludes.matches(&entry.path(), &entry.file_name)
+                        || (depth.is_some()
+                            && exclude_files.matches(&entry.path(), entry.file_type.is_dir())))
+                        && !(check_ignores && (entry.file_name == ".git"
+                            || ignores.is_ignored(&entry.path(), entry.file_type.is_dir()))),
+                    // jwalk's own (rather limited) loop detection reports
+                    // loops as errors. Those aren't interesting, since
+                    // skipping loops is what we'd do anyway.
+                    Err(e) if e.loop_ancestor().is_some() => false,
+                    // Keep other errors so they get reported below.
+                    Err(_) => true,
+                });
+
+                if let Some(root_dev) = root_dev {
+                    // Keep mount points, but don't look inside them.
+                    for entry in children.iter_mut().flatten() {
+                        if entry.read_children_path.is_none() {
+                            continue;
+                        }
+                        let dev = entry.metadata().ok().as_ref().and_then(device_id);
+                        if dev.is_some_and(|dev| dev != root_dev) {
+                            entry.read_children_path = None;
+                        }
+                    }
+                }
+
+                if follow_symlinks {
+                    // Don't descend into any directory we've already seen by
+                    // another name. We still keep the entry itself, it's just
+                    // treated as though it were empty.
+                    let mut visited = visited_dirs.lock().unwrap();
+                    for entry in children.iter_mut().flatten() {
+                        if entry.read_children_path.is_none() {
+                            continue;
+                        }
+                        if let Ok(real) = entry.path().canonicalize() {
+                            if !visited.insert(real) {
+                                entry.read_children_path = None;
+                            }
+                        }
+                    }
+                }
+            });
+        // Unless we're being strict, errors here (most often a directory we
+        // don't have permission to read) are reported and skipped, so that
+        // one bad directory doesn't cost us the whole scan.
+        let skip_or_bail = |e: anyhow::Error| if opts.strict {
+            Err(e)
+        } else {
+            eprintln!("warning: skipping: {e:#}");
+            walk_errors.set(walk_errors.get() + 1);
+            on_progress(Progress {
+                phase: Phase::Walking,
+                files: files_seen.get(),
+                bytes_hashed: 0,
+                errors: walk_errors.get(),
+            });
+            Ok(())
+        };
+        for entry in walk {
+            let entry = match entry
+                .with_context(|| format!("problem reading dirent in {}", root.display()))
+            {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skip_or_bail(e)?;
+                    continue;
+                }
+            };
+            // jwalk doesn't report directories it can't read as errors in the
+            // stream. Instead, they show up like any other directory, with the
+            // error tucked away inside.
+            if let Some(e) = &entry.read_children_error {
+                skip_or_bail(anyhow::anyhow!("{e}")
+                    .context(format!("problem reading directory {}",
+                            entry.path().display())))?;
+            }
+            let meta = match entry.metadata()
+                .with_context(|| format!("problem getting metadata for {}",
+                        entry.path().display()))
+            {
+                Ok(meta) => meta,
+                Err(e) => {
+                    skip_or_bail(e)?;
+                    continue;
+                }
+            };
+            if meta.is_file() && opts.wants_file(Path::new(&entry.file_name), &meta) {
+                files_seen.set(files_seen.get() + 1);
+                on_progress(Progress {
+                    phase: Phase::Walking,
+                    files: files_seen.get(),
+                    bytes_hashed: 0,
+                    errors: walk_errors.get(),
+                });
+                paths.entry(meta.len()).or_default().push(entry.path());
+            }
+        }
+    }
+
+    if opts.verbose {
+        eprintln!("{:?} pass one complete, found {} size-groups",
+            start.elapsed(), paths.len());
+    }
+
+    Ok(paths)
+}
+
+/// Drops any of `roots` that's inside another one, or the same as an earlier
+/// one, so that no file gets found twice. Roots are compared in canonical
+/// form, so this catches the same directory spelled different ways, or
+/// reached through a symlink. Roots that can't be canonicalized are kept, so
+/// that the walk reports whatever's wrong with them.
+fn distinct_roots(roots: &[PathBuf]) -> Vec<&PathBuf> {
+    let real: Vec<Option<PathBuf>> = roots.iter()
+        .map(|root| root.canonicalize().ok())
+        .collect();
+    let covering = |i: usize| {
+        let mine = real[i].as_ref()?;
+        real.iter().enumerate().position(|(j, other)| {
+            other.as_ref().is_some_and(|other| {
+                j != i && mine.starts_with(other) && (mine != other || j < i)
+            })
+        })
+    };
+    roots.iter().enumerate()
+        .filter(|&(i, root)| match covering(i) {
+            Some(j) => {
+                let how = if real[i] == real[j] { "the same as" } else { "inside" };
+                eprintln!("warning: not searching {} separately, since it's {how} {}",
+                    root.display(), roots[j].display());
+                false
+            }
+            None => true,
+        })
+        .map(|(_, root)| root)
+        .collect()
+}
+
+/// Like [`walk`], but instead of searching directories, looks only at the
+/// files listed in `files`, for when you already know which files you're
+/// interested in. The same options apply to them, except for the ones that
+/// only make sense for directories.
+///
+/// Paths that don't exist or aren't files are reported and skipped, unless
+/// the options say to be [strict](ScanOptions::strict), in which case they're
+/// an error.
+pub fn walk_files(
+    files: &[PathBuf],
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress),
+) -> anyhow::Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let excludes = opts.excludes()?;
+    // With no roots, patterns are anchored where we are, as they would be for
+    // a `.gitignore` at the top of a repository we're working in.
+    let exclude_files = opts.exclude_files(&std::env::current_dir()?)?;
+    let mut paths: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    let mut files_seen = 0;
+    let mut errors = 0;
+    for path in files {
+        if excludes.matches(path, path.file_name().unwrap_or_default())
+            || exclude_files.matches_file(path)
+        {
+            continue;
+        }
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("problem getting metadata for {}", path.display()))
+            .and_then(|meta| if meta.is_file() {
+                Ok(meta)
+            } else {
+                Err(anyhow::anyhow!("not a file: {}", path.display()))
+            });
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(e) if opts.strict => return Err(e),
+            Err(e) => {
+                eprintln!("warning: skipping: {e:#}");
+                errors += 1;
+                continue;
+            }
+        };
+        files_seen += 1;
+        on_progress(Progress {
+            phase: Phase::Walking,
+            files: files_seen,
+            bytes_hashed: 0,
+            errors,
+        });
+        if opts.wants_file(path, &meta) {
+            paths.entry(meta.len()).or_default().push(path.clone());
+        }
+    }
+
+    if opts.verbose {
+        eprintln!("{:?} pass one complete, found {} size-groups",
+            start.elapsed(), paths.len());
+    }
+
+    Ok(paths)
+}
+
+/// The second half of [`pass_one`]: drops all file size groups that contain no
+/// duplicates (have only one member) from the results of [`walk`].
+///
+/// This saves about 10% of runtime.
+pub fn discard_unique_sizes(paths: &mut BTreeMap<u64, Vec<PathBuf>>, opts: &ScanOptions) {
+    paths.retain(|_size, paths| paths.len() > 1);
+
+    if opts.verbose {
+        eprintln!("...of which {} had more than one member", paths.len());
+    }
+}
+
+/// Takes the text files of up to `max_size` bytes out of the results of
+/// [`walk`], and groups them by their contents after normalizing them with
+/// [`normalize_text`], so that copies that only differ in line endings or
+/// trailing whitespace count as duplicates.
+///
+/// This is an optional extra step between `walk` and
+/// [`discard_unique_sizes`], since normalized copies needn't be the same
+/// size. The files taken out don't go on to [`pass_two`] and [`pass_three`];
+/// instead, the result maps the size and hash of each normalized text to the
+/// files that have it, in the same form as the result of `pass_three`, so
+/// that the two can be merged. Files that aren't text, and files that can't
+/// be read (which will be reported later), are left where they were.
+///
+/// Since these files aren't necessarily byte-for-byte the same,
+/// [`verify_group`] and friends will say they don't match. Compare them with
+/// [`texts_match`] instead.
+pub fn group_text_files(
+    paths: &mut BTreeMap<u64, Vec<PathBuf>>,
+    max_size: u64,
+    opts: &ScanOptions,
+) -> HashMap<(u64, Digest), Vec<PathBuf>> {
+    let candidates: Vec<PathBuf> = paths.range(..=max_size)
+        .flat_map(|(_, files)| files.iter().cloned())
+        .collect();
+    let texts: Vec<((u64, Digest), PathBuf)> = candidates.into_par_iter()
+        .filter_map(|path| {
+            let text = normalize_text(&read_file(&path).ok()?)?;
+            Some(((text.len() as u64, opts.hash_algorithm.hash(&text)), path))
+        })
+        .collect();
+
+    let taken: HashSet<&Path> = texts.iter().map(|(_, path)| path.as_path()).collect();
+    for files in paths.values_mut() {
+        files.retain(|f| !taken.contains(f.as_path()));
+    }
+    paths.retain(|_, files| !files.is_empty());
+    let count = taken.len();
+
+    let mut groups: HashMap<(u64, Digest), Vec<PathBuf>> = HashMap::new();
+    for (key, path) in texts {
+        groups.entry(key).or_default().push(path);
+    }
+    if opts.verbose {
+        eprintln!("found {} text files, with {} different contents once normalized",
+            count, groups.len());
+    }
+    groups
+}
+
+/// Normalizes the contents of a text file for [`group_text_files`]: CRLF and
+/// lone CR line endings become LF, whitespace at the end of each line is
+/// removed, and the text ends with exactly one newline (unless it's empty).
+///
+/// Returns `None` if `data` doesn't look like text, which is decided the same
+/// way git does: text doesn't have any NUL bytes in it.
+pub fn normalize_text(data: &[u8]) -> Option<Vec<u8>> {
+    if data.contains(&0) {
+        return None;
+    }
+    let mut text = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = rest.iter().position(|&b| b == b'\n' || b == b'\r').unwrap_or(rest.len());
+        let line = &rest[..end];
+        let trimmed = line.len() - line.iter().rev().take_while(|b| b.is_ascii_whitespace()).count();
+        text.extend_from_slice(&line[..trimmed]);
+        text.push(b'\n');
+        rest = match &rest[end..] {
+            [b'\r', b'\n', rest @ ..] | [_, rest @ ..] => rest,
+            [] => &[],
+        };
+    }
+    // Blank lines at the end count as trailing whitespace too.
+    while text.ends_with(b"\n\n") {
+        text.pop();
+    }
+    if text == b"\n" {
+        text.clear();
+    }
+    Some(text)
+}
+
+/// Checks that every file in a group from [`group_text_files`] really has the
+/// same contents as the first one, once normalized, and that they're text.
+/// Returns an error if any of them can't be read.
+pub fn texts_match(files: &[impl AsRef<Path>]) -> anyhow::Result<bool> {
+    let read = |path: &Path| {
+        let data = read_file(path)
+            .with_context(|| format!("unable to read path: {}", path.display()))?;
+        anyhow::Ok(normalize_text(&data))
+    };
+    let Some((first, rest)) = files.split_first() else {
+        return Ok(true);
+    };
+    let first = read(first.as_ref())?;
+    for f in rest {
+        if read(f.as_ref())? != first {
+            return Ok(false);
+        }
+    }
+    Ok(first.is_some())
+}
+
+/// PASS TWO
+///
+/// Given a map of files whose sizes are not unique (from [`pass_one`]), this
+/// pass hashes the first few kilobytes of each (the "prehash," whose size is
+/// set by [`ScanOptions::prehash_size`]). If two files have different hashes
+/// for their first few kilobytes, they cannot possibly be duplicates, so we
+/// can use this to avoid reading the full contents of files.
+///
+/// This is a significant performance improvement for directories of large
+/// files like photos or videos (~50%).
+///
+/// The result is keyed by each file's size (as found by `pass_one`) along with
+/// its prehash. Files that can't be read are reported on stderr and left out
+/// of the results, as are any that turn out not to be regular files after all
+/// (a FIFO that's taken a file's place, say), which are never read from.
+pub fn pass_two<'a>(
+    paths: &'a BTreeMap<u64, Vec<PathBuf>>,
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress) + Sync,
+) -> HashMap<(u64, Digest), Vec<&'a Path>> {
+    // Flatten the map into a list of paths to hash, each tagged with its size.
+    // We keep the size around so that files of different sizes never land in
+    // the same group, and so that nobody needs to go back to the filesystem to
+    // find out how big the files in a group are.
+    let files = paths.par_iter()
+        .flat_map(|(size, paths)| paths.par_iter().map(move |p| (*size, p.as_path())));
+    prehash(files, opts, start, on_progress)
+}
+
+/// Like [`pass_two`], but takes ownership of the map from [`pass_one`], and
+/// moves the paths into its results instead of borrowing them.
+///
+/// [`pass_three`] accepts these results too. This is for callers that want to
+/// keep the results of a scan around for a while: with the borrowing version,
+/// every path found has to be kept until you're done with the results, while
+/// here, the paths of files that turn out not to have duplicates are freed
+/// along the way.
+///
+/// It doesn't make scanning itself any leaner. Each path takes a little more
+/// space in the results here than a borrowed one does, and the walk's map has
+/// to exist in full either way, so the peak is about the same; scanning
+/// 100,000 small files in 100 sizes, the peak resident size was 50 MiB this
+/// way, and 47 MiB with `pass_two`.
+pub fn pass_two_owned(
+    paths: BTreeMap<u64, Vec<PathBuf>>,
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress) + Sync,
+) -> HashMap<(u64, Digest), Vec<PathBuf>> {
+    let files = paths.into_par_iter()
+        .flat_map(|(size, paths)| paths.into_par_iter().map(move |p| (size, p)));
+    prehash(files, opts, start, on_progress)
+}
+
+/// The guts of [`pass_two`] and [`pass_two_owned`], which differ only in how
+/// they hold on to paths.
+fn prehash<P: AsRef<Path> + Send>(
+    files: impl ParallelIterator<Item = (u64, P)>,
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress) + Sync,
+) -> HashMap<(u64, Digest), Vec<P>> {
+    let progress = ProgressCounter::new(Phase::Prehashing, &on_progress);
+
+    // This is constructed as a Rayon pipeline because (1) I find it reasonably
+    // clear this way once I got used to it and (2) it's by far the
+    // easiest-to-reach "go faster button."
+    let hashed_files: HashMap<(u64, Digest), Vec<P>> = files
+        // Hash each path, producing a (size and hash, path) pair. Note that
+        // this can fail to access the filesystem.
+        //
+        // We use `map_with` here to allocate exactly one I/O buffer per backing
+        // Rayon thread, instead of one per closure, because I'm neurotic.
+        .map_with(vec![0u8; opts.prehash_size], |buf, (size, file)| {
+            let path = file.as_ref();
+            // There's nothing to read, so don't bother opening the file.
+            if opts.prehash_size == 0 {
+                progress.file_done(0);
+                return Ok(((size, opts.hash_algorithm.hash(&[])), file));
+            }
+            // A file that hasn't changed since we last saw it doesn't need to be
+            // read again. If we can't tell, we'll just read it.
+            let cached = opts.cache.as_ref().and_then(|cache| {
+                cache.get_prehash(path, &std::fs::metadata(path).ok()?)
+            });
+            if let Some(hash) = cached {
+                progress.file_done(0);
+                return Ok(((size, hash), file));
+            }
+            let (total, meta) = with_retries(opts.retries, || {
+                // (On Windows, `open_file` takes care of paths that are too
+                // long to open as they are.)
+                let mut f = open_file(path)
+                    .with_context(|| format!("unable to open: {}", path.display()))?;
+                // For the cache, get the metadata before reading, so that if
+                // the file changes while we read it, it won't look unchanged
+                // next time around.
+                let meta = opts.cache.as_ref().and_then(|_| f.metadata().ok());
+
+                // Read up to `prehash_size` bytes, or fewer if the file is
+                // shorter than that.
+                opts.reading(path);
+                let total = read_up_to(&mut f, buf)
+                    .with_context(|| format!("unable to read path: {}", path.display()))?;
+                anyhow::Ok((total, meta))
+            })?;
+            progress.file_done(total as u64);
+            // Hash the first chunk of the file. Only hash what we actually
+            // read, since the rest of the buffer is left over from whatever
+            // file this thread looked at last.
+            let hash = opts.hash_algorithm.hash(&buf[..total]);
+            if let (Some(cache), Some(meta)) = (&opts.cache, meta) {
+                cache.insert(path, &meta, hash, None);
+            }
+            anyhow::Ok(((size, hash), file))
+        })
+        // Squawk about any reads that failed, and remove them from further
+        // consideration.
+        .filter_map(|result| {
+            match result {
+                Ok(data) => Some(data),
+                // Files that have been deleted since the walk found them
+                // aren't worth making a fuss about.
+                Err(e) if is_vanished(&e) => {
+                    if opts.verbose {
+                        eprintln!("skipping file that's gone: {e}");
+                    }
+                    progress.file_done(0);
+                    None
+                }
+                Err(e) => {
+                    eprintln!("{e:?}");
+                    progress.file_failed();
+                    None
+                }
+            }
+        })
+        // Take the stream of (hash, path) pairs and collate them by hash,
+        // producing "hash groups."
+        //
+        // Rayon's fold is a little surprising: this produces, not a single map,
+        // but a _stream_ of maps, because (roughly speaking) each thread
+        // calculates its own.
+        //
+        // Many hash-groups will only contain one path, and will be filtered out
+        // below. Any group containing multiple paths needs to be hashed more
+        // fully in the next pass.
+        .fold(HashMap::<_, Vec<P>>::new, |mut map, (key, path)| {
+            map.entry(key).or_default().push(path);
+            map
+        })
+        // Collapse the stream of hashmaps into one, merging hash groups as
+        // required.
+        .reduce(HashMap::new, |mut a, b| {
+            for (k, v) in b {
+                a.entry(k).or_default().extend(v);
+            }
+            a
+        });
+
+    if opts.verbose {
+        eprintln!("{:?} pass two complete, found {} unique first blocks",
+            start.elapsed(), hashed_files.len());
+        let dupesets = hashed_files.values()
+            .filter(|paths| paths.len() > 1)
+            .count();
+        eprintln!("...of which {dupesets} are present in more than one file");
+        let dupes = hashed_files.values()
+            .map(|paths| paths.len().saturating_sub(1))
+            .sum::<usize>();
+        eprintln!("...for a total of {dupes} possibly redundant files");
+    }
+
+    hashed_files
+}
+
+/// PASS THREE
+///
+/// For any files whose prehash matches at least one other file (according to
+/// the results of [`pass_two`]), hash the entire contents to scan for
+/// differences.
+///
+/// This needs to be given the same [`ScanOptions::prehash_size`] as
+/// `pass_two`, since it picks up reading where `pass_two` left off. It takes
+/// the results of either `pass_two` or [`pass_two_owned`], and holds on to the
+/// paths the same way they did.
+///
+/// The result maps file sizes and content hashes to files; any entry with more
+/// than one file is a group of duplicates. Files that can't be read, or that
+/// aren't regular files, are reported on stderr and left out of the results,
+/// as in `pass_two`.
+pub fn pass_three<P: AsRef<Path> + Send>(
+    prehashed: HashMap<(u64, Digest), Vec<P>>,
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress) + Sync,
+) -> HashMap<(u64, Digest), Vec<P>> {
+    let progress = ProgressCounter::new(Phase::FullHashing, &on_progress);
+
+    let hashed_files = prehashed.into_par_iter()
+        // Ignore groups with only one member.
+        .filter(|(_, paths)| paths.len() > 1)
+        // Flatten the `prehash => vec of paths` map to a stream of `prehash,
+        // path` pairs. Since the prehash has no (straightforward) relation to
+        // the hash of the overall file, we don't need to maintain the group
+        // structure.
+        //
+        // We do, however, forward the size and prehash value on, so that we
+        // can use them for keying below.
+        .flat_map(|(key, paths)| paths.into_par_iter().map(move |p| (key, p)))
+        // Hash the tail of each file to produce `(path, hash)` pairs. Note that
+        // this can fail to access the filesystem (again).
+        //
+        // This takes the prehash as input, and uses it as the key for a keyed
+        // hash of the rest of the file. This is important for correctness: if
+        // we just hashed the tail end of every file, we could detect two files
+        // as "identical" even if their first `prehash_size` bytes differed! By
+        // incorporating the prehash as key we chain the two hashes and prevent
+        // this.
+        //
+        // For files smaller than `prehash_size`, we immediately finalize the
+        // keyed hash without reading anything.
+        //
+        // (That's for BLAKE3, when there's a prehash. Other algorithms don't
+        // have keyed modes, so we just hash the whole file again, from the
+        // top.)
+        //
+        // Any file that can't be read is reported and dropped.
+        .filter_map(|(key, file)| hash_rest(key, file, opts, &progress))
+        // Collect groups of (path, hash) pairs and collate them by hash. This
+        // is identical to the end of Pass Two.
+        .fold(HashMap::<_, Vec<P>>::new, |mut map, (key, path)| {
+            map.entry(key).or_default().push(path);
+            map
+        })
+        // Collapse the stream of hashmaps into one, merging hash groups as
+        // required. This is also identical to the end of Pass Two.
+        .reduce(HashMap::new, |mut a, b| {
+            for (k, v) in b {
+                a.entry(k).or_default().extend(v);
+            }
+            a
+        });
+
+    if opts.verbose {
+        eprintln!("{:?} pass three complete, generating results",
+            start.elapsed());
+    }
+
+    hashed_files
+}
+
+/// Like [`pass_three`], but hands each group of files with the same size and
+/// hash to `on_group` as soon as it's complete, instead of collecting them all
+/// into a map, so that the first groups can be dealt with while the rest are
+/// still being hashed.
+///
+/// Files with the same contents always have the same prehash, so a group is
+/// complete once every file with its prehash has been hashed. Groups of one
+/// file, which aren't duplicates of anything, are passed on too, as they
+/// would be in the result of `pass_three`. `on_group` is called from several
+/// threads at once, and in no particular order.
+///
+/// If `on_group` returns an error, no more files are hashed (though groups
+/// already being hashed are finished off), and one of the errors is returned.
+pub fn pass_three_each<P: AsRef<Path> + Send, E: Send>(
+    prehashed: HashMap<(u64, Digest), Vec<P>>,
+    opts: &ScanOptions,
+    start: Instant,
+    on_progress: impl Fn(Progress) + Sync,
+    on_group: impl Fn((u64, Digest), Vec<P>) -> Result<(), E> + Sync,
+) -> Result<(), E> {
+    let progress = ProgressCounter::new(Phase::FullHashing, &on_progress);
+
+    prehashed.into_par_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .try_for_each(|(key, paths)| {
+            // This is the same as the middle of `pass_three`, just one
+            // prehash group at a time. The files in the group are still
+            // hashed in parallel, since a group can be most of the work.
+            let hashed: Vec<_> = paths.into_par_iter()
+                .filter_map(|file| hash_rest(key, file, opts, &progress))
+                .collect();
+            let mut by_hash: HashMap<(u64, Digest), Vec<P>> = HashMap::new();
+            for (key, file) in hashed {
+                by_hash.entry(key).or_default().push(file);
+            }
+            by_hash.into_iter().try_for_each(|(key, paths)| on_group(key, paths))
+        })?;
+
+    if opts.verbose {
+        eprintln!("{:?} pass three complete, generating results",
+            start.elapsed());
+    }
+    Ok(())
+}
+
+/// Hashes the rest of `file`, given its size and prehash, for [`pass_three`],
+/// returning its size and full hash. If it can't be read, this says so on
+/// stderr (unless it's gone missing since the walk) and returns `None`.
+fn hash_rest<P: AsRef<Path>>(
+    (size, prehash): (u64, Digest),
+    file: P,
+    opts: &ScanOptions,
+    progress: &ProgressCounter<impl Fn(Progress)>,
+) -> Option<((u64, Digest), P)> {
+    let path = file.as_ref();
+    let hash = with_retries(opts.retries, || {
+        let mut f = open_file(path)
+            .with_context(|| format!("unable to open: {}", path.display()))?;
+        let (mut hasher, offset) = opts.hash_algorithm
+            .chained_hasher(&prehash, opts.prehash_size as u64);
+
+        let meta = f.metadata()?;
+        if let Some(hash) = opts.cache.as_ref().and_then(|c| c.get(path, &meta)) {
+            progress.file_done(0);
+            return Ok(hash);
+        }
+
+        // Small files have already been completely hashed. Skip them.
+        let len = meta.len();
+        if len > offset {
+            opts.reading(path);
+        }
+        // Big files get memory-mapped if possible. For anything else, or
+        // anything that can't be mapped, fall back to reading it.
+        let mapped = opts.mmap
+            && len >= MMAP_THRESHOLD
+            && update_mmap(&mut hasher, &f, offset);
+        if len > offset && !mapped {
+            f.seek(std::io::SeekFrom::Start(offset))?;
+            hasher.update_reader(f)?;
+        }
+        progress.file_done(len.saturating_sub(offset));
+        let hash = hasher.finalize();
+        if let Some(cache) = &opts.cache {
+            cache.insert(path, &meta, prehash, Some(hash));
+        }
+        Ok(hash)
+    });
+    match hash {
+        Ok(hash) => Some(((size, hash), file)),
+        // Files that have been deleted since the walk found them aren't worth
+        // making a fuss about.
+        Err(e) if is_vanished(&e) => {
+            if opts.verbose {
+                eprintln!("skipping file that's gone: {e}");
+            }
+            progress.file_done(0);
+            None
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            progress.file_failed();
+            None
+        }
+    }
+}
+
+/// Hashes the entire contents of the file at `path` with `algorithm`.
+///
+/// Unlike the hashes from [`pass_three`], which for BLAKE3 are chained off of
+/// the prehash (if there is one), this is always a plain hash of the file, as
+/// you'd get from `b3sum` or `sha256sum`.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> anyhow::Result<Digest> {
+    let f = open_file(path)
+        .with_context(|| format!("unable to open: {}", path.display()))?;
+    let mut hasher = hash::Hasher::new(algorithm);
+    hasher.update_reader(f)
+        .with_context(|| format!("unable to read path: {}", path.display()))?;
+    Ok(hasher.finalize())
+}
+
+/// Checks that every file in a group of supposed duplicates really has the
+/// same contents, byte for byte, as the first one.
+///
+/// Returns `Ok(false)` if any of the files differ, and an error only if one of
+/// them can't be read. Use [`first_mismatch`] to find out which file it was.
+///
+/// Note that if this ever returns `false`, it is **almost certainly** a bug in
+/// this program. If it isn't a bug in this program, it's probably a file being
+/// modified out from under us. BLAKE3 is collision-resistant, and finding two
+/// files with the same length, same BLAKE3 hash, and different contents would
+/// be a newsworthy event. It's certainly possible, but rather unlikely.
+pub fn verify_group(files: &[&Path]) -> anyhow::Result<bool> {
+    Ok(first_mismatch(files)?.is_none())
+}
+
+/// Like [`verify_group`], but returns the first file found whose contents
+/// don't match the first file in the group, if there is one.
+pub fn first_mismatch<'a>(files: &[&'a Path]) -> anyhow::Result<Option<&'a Path>> {
+    first_mismatch_with_progress(files, |_| ())
+}
+
+/// Like [`first_mismatch`], but calls `on_read` with the number of bytes read
+/// each time it reads from one of the files, for keeping track of how much
+/// I/O is being done.
+pub fn first_mismatch_with_progress<'a>(
+    files: &[&'a Path],
+    on_read: impl Fn(u64),
+) -> anyhow::Result<Option<&'a Path>> {
+    compare_files(files, None, bytes_read_since_last(on_read))
+}
+
+/// Like [`first_mismatch_with_progress`], but only compares about `percent`
+/// percent of each file, in chunks spread evenly from its start to its end,
+/// which is quicker, but can miss a difference.
+///
+/// A file that differs only outside the chunks that were compared is taken to
+/// match, so this is no substitute for a full comparison before deleting
+/// anything. It makes a good spot check, though: the same chunks are compared
+/// in every file in the group, and files of different lengths are still
+/// always caught. A `percent` of 100 or more compares the whole file.
+pub fn first_mismatch_sampled<'a>(
+    files: &[&'a Path],
+    percent: f64,
+    on_read: impl Fn(u64),
+) -> anyhow::Result<Option<&'a Path>> {
+    let percent = (percent < 100.0).then_some(percent);
+    compare_files(files, percent, bytes_read_since_last(on_read))
+}
+
+/// Like [`first_mismatch_with_progress`], or [`first_mismatch_sampled`] if
+/// `sample` is given, but reports on how it's going to `on_progress`, in the
+/// same way as the passes do, under [`Phase::Verifying`].
+///
+/// A report is made each time the files being compared with the first one
+/// have been read to the end (which happens a batch at a time, for a large
+/// group), with `files` counting the first file too, and once more if a file
+/// turns out not to match. So a group of files that all match gets one report
+/// per file, ending with `files` being the size of the group.
+pub fn first_mismatch_reporting<'a>(
+    files: &[&'a Path],
+    sample: Option<f64>,
+    on_progress: impl Fn(Progress),
+) -> anyhow::Result<Option<&'a Path>> {
+    let sample = sample.filter(|&percent| percent < 100.0);
+    compare_files(files, sample, on_progress)
+}
+
+/// Turns a progress callback for [`compare_files`] into one that calls
+/// `on_read` with the number of bytes read since the last report.
+fn bytes_read_since_last(on_read: impl Fn(u64)) -> impl Fn(Progress) {
+    let last = Cell::new(0);
+    move |p: Progress| on_read(p.bytes_hashed - last.replace(p.bytes_hashed))
+}
+
+/// The guts of [`first_mismatch_reporting`] and friends: compares every file
+/// in `files` with the first one, either in full, or only the chunks picked by
+/// [`sample_offsets`] for `sample` percent, reporting to `on_progress` as
+/// described there.
+fn compare_files<'a>(
+    files: &[&'a Path],
+    sample: Option<f64>,
+    on_progress: impl Fn(Progress),
+) -> anyhow::Result<Option<&'a Path>> {
+    let Some((first, rest)) = files.split_first() else {
+        return Ok(None);
+    };
+
+    // How many files we're done with, and how much we've read from all of
+    // them, for `on_progress`. The first file counts as done once the first
+    // batch is.
+    let done = Cell::new(0);
+    let bytes = Cell::new(0);
+    let on_read = |n: u64| bytes.set(bytes.get() + n);
+    let report = || on_progress(Progress {
+        phase: Phase::Verifying,
+        files: done.get(),
+        bytes_hashed: bytes.get(),
+        errors: 0,
+    });
+    let batch_done = |batch: usize| {
+        let first = u64::from(done.get() == 0);
+        for _ in 0..batch as u64 + first {
+            done.set(done.get() + 1);
+            report();
+        }
+    };
+
+    // Arbitrarily choose the first file in the group as a "representative."
+    // The files are not kept in memory, so this works fine on very large
+    // files (keeping files in memory is the operating system's job).
+    let mut first_f = open_file(first)
+        .with_context(|| format!("can't open {}", first.display()))?;
+    let first_len = first_f.metadata()?.len();
+
+    let mut buf1 = vec![0u8; VERIFY_CHUNK_SIZE];
+    let mut buf2 = vec![0u8; VERIFY_CHUNK_SIZE];
+
+    // Rather than comparing the other files to the first one at a time, which
+    // would mean re-reading the first file for each of them, we open all of
+    // them at once and read through them in lockstep. Groups can be huge,
+    // though, and we don't want to run out of file descriptors, so this is
+    // done in batches; the first file gets read once per batch.
+    for batch in rest.chunks(VERIFY_BATCH_SIZE) {
+        // ...starting from the beginning of the first file, please.
+        first_f.rewind()?;
+
+        let mut others = Vec::with_capacity(batch.len());
+        for &other in batch {
+            let other_f = open_file(other)
+                .with_context(|| format!("can't open {}", other.display()))?;
+
+            // This provides some _very basic_ protection against files being
+            // modified while this program is running, but in general, this
+            // program is not written with that situation in mind.
+            if other_f.metadata()?.len() != first_len {
+                report();
+                return Ok(Some(other));
+            }
+            others.push((other, other_f));
+        }
+
+        if let Some(percent) = sample {
+            for offset in sample_offsets(first_len, percent) {
+                first_f.seek(std::io::SeekFrom::Start(offset))?;
+                let n1 = read_up_to(&mut first_f, &mut buf1)
+                    .with_context(|| format!("unable to read path: {}", first.display()))?;
+                on_read(n1 as u64);
+                for (other, other_f) in &mut others {
+                    other_f.seek(std::io::SeekFrom::Start(offset))?;
+                    let n2 = read_up_to(other_f, &mut buf2)
+                        .with_context(|| format!("unable to read path: {}", other.display()))?;
+                    on_read(n2 as u64);
+                    if buf1[..n1] != buf2[..n2] {
+                        report();
+                        return Ok(Some(other));
+                    }
+                }
+            }
+            batch_done(batch.len());
+            continue;
+        }
+
+        // Compare the files a chunk at a time. Since `read_up_to` only comes
+        // up short at the end of a file, the chunks line up, and if one file
+        // ends before the other (because it shrank after we checked), the
+        // slices will have different lengths and won't match.
+        loop {
+            let n1 = read_up_to(&mut first_f, &mut buf1)
+                .with_context(|| format!("unable to read path: {}", first.display()))?;
+            on_read(n1 as u64);
+            for (other, other_f) in &mut others {
+                let n2 = read_up_to(other_f, &mut buf2)
+                    .with_context(|| format!("unable to read path: {}", other.display()))?;
+                on_read(n2 as u64);
+                if buf1[..n1] != buf2[..n2] {
+                    report();
+                    return Ok(Some(other));
+                }
+            }
+            if n1 == 0 {
+                break;
+            }
+        }
+        batch_done(batch.len());
+    }
+    Ok(None)
+}
+
+/// Feeds the contents of `f`, starting at `offset`, into `hasher` by
+/// memory-mapping it. Returns `false` if the file can't be mapped (because
+/// it's a special file, say), in which case the caller will need to read it
+/// some other way.
+fn update_mmap(hasher: &mut hash::Hasher, f: &File, offset: u64) -> bool {
+    // Safety: the usual caveat with memory-mapped files applies, which is that
+    // if someone changes the file while we're looking at it, we'll see the
+    // changes (or a SIGBUS, if it's truncated). We'd get garbage results in
+    // that case either way, since we're comparing files that are assumed not
+    // to be changing.
+    let map = match unsafe { memmap2::Mmap::map(f) } {
+        Ok(map) => map,
+        Err(_) => return false,
+    };
+    let rest = usize::try_from(offset).ok()
+        .and_then(|offset| map.get(offset..))
+        .unwrap_or(&[]);
+    hasher.update_parallel(rest);
+    true
+}
+
+/// Size of the chunks that [`verify_group`] reads and compares.
+const VERIFY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Picks the offsets of the chunks to compare in a file `len` bytes long, for
+/// comparing about `percent` percent of it. There's always at least one chunk,
+/// and when there's more than one, the first and last chunks of the file are
+/// among them, with the rest spaced evenly in between.
+fn sample_offsets(len: u64, percent: f64) -> impl Iterator<Item = u64> {
+    let chunk = VERIFY_CHUNK_SIZE as u64;
+    let chunks = len.div_ceil(chunk).max(1);
+    let wanted = ((chunks as f64 * percent / 100.0).ceil() as u64).clamp(1, chunks);
+    // (This is done in `u128` so that it can't overflow, however big the file.)
+    (0..wanted).map(move |i| {
+        let index = match wanted {
+            1 => 0,
+            _ => (u128::from(i) * u128::from(chunks - 1) / u128::from(wanted - 1)) as u64,
+        };
+        index * chunk
+    })
+}
+
+/// Maximum number of files, besides the first, that [`first_mismatch`] will
+/// hold open at once.
+const VERIFY_BATCH_SIZE: usize = 256;
+
+/// How long to wait before the first retry in [`with_retries`]. Each retry
+/// after that waits twice as long as the last.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Opens the file at `path` for reading, as long as it's a regular file.
+///
+/// The walk only turns up regular files, but by the time we get around to
+/// reading one, something else could be there instead, like a FIFO, which
+/// would block forever waiting for someone to write to it. So on Unix, the
+/// file is opened without waiting, and then anything that isn't a regular
+/// file is refused with an error.
+fn open_file(path: &Path) -> std::io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // This makes no difference to reading regular files.
+        options.custom_flags(libc::O_NONBLOCK);
+    }
+    let f = options.open(long_path(path))?;
+    if !f.metadata()?.file_type().is_file() {
+        return Err(std::io::Error::new(ErrorKind::InvalidInput, "not a regular file"));
+    }
+    Ok(f)
+}
+
+/// Spells `path` so that Windows will open it even if it's longer than
+/// `MAX_PATH` (260 characters), by making it absolute and giving it the `\\?\`
+/// prefix, or `\\?\UNC\` for a network path like `\\server\share`. Paths
+/// that are short enough, or that already have a prefix like that, are left
+/// alone.
+#[cfg(windows)]
+fn long_path(path: &Path) -> std::borrow::Cow<'_, Path> {
+    use std::{ffi::OsString, os::windows::ffi::{OsStrExt, OsStringExt}, path::{Component, Prefix}};
+    const MAX_PATH: usize = 260;
+
+    // Relative paths count too, since Windows makes them absolute before
+    // checking. Making it absolute ourselves also gets rid of any `.`, `..`,
+    // and forward slashes, which Windows won't do for a `\\?\` path.
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.into();
+    };
+    let wide: Vec<u16> = absolute.as_os_str().encode_wide().collect();
+    if wide.len() < MAX_PATH {
+        return path.into();
+    }
+    let (prefix, skip) = match absolute.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => (r"\\?\", 0),
+            // This replaces the `\\` at the start.
+            Prefix::UNC(..) => (r"\\?\UNC\", 2),
+            // It's already verbatim, or a device, so there's nothing we can
+            // do to help.
+            _ => return path.into(),
+        },
+        _ => return path.into(),
+    };
+    let mut long: Vec<u16> = prefix.encode_utf16().collect();
+    long.extend_from_slice(&wide[skip..]);
+    PathBuf::from(OsString::from_wide(&long)).into()
+}
+
+/// Elsewhere, a path's length is no problem, as long as the OS will take it.
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> std::borrow::Cow<'_, Path> {
+    path.into()
+}
+
+/// Reads the whole of the file at `path`, like `std::fs::read`, but refusing
+/// anything that isn't a regular file, as [`open_file`] does.
+fn read_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![];
+    open_file(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Runs `op`, which opens and reads a file, and if it fails with an error that
+/// might well go away on its own (like a network filesystem timing out), runs
+/// it again, up to `retries` more times, waiting a little longer each time.
+/// Other errors, like the file not existing, are returned right away.
+///
+/// `op` has to start from scratch each time, since there's no telling how far
+/// it got before it failed.
+fn with_retries<T>(retries: u32, mut op: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                std::thread::sleep(RETRY_BACKOFF * 2u32.saturating_pow(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Checks whether `e` is the sort of I/O error that might not happen again if
+/// we try again, for [`with_retries`].
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| matches!(e.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::ResourceBusy
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted))
+}
+
+/// Checks whether `e` is because the file we were after doesn't exist (any
+/// more).
+fn is_vanished(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+/// Fills `buf` from `f`, stopping early only at the end of the file, and
+/// returns the number of bytes read. (It's odd that there's no operation for
+/// this in the standard library.)
+fn read_up_to(f: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match f.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+/// Keeps count of files and bytes for progress reports from passes that run on
+/// several threads at once.
+struct ProgressCounter<F> {
+    phase: Phase,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    on_progress: F,
+}
+
+impl<F: Fn(Progress)> ProgressCounter<F> {
+    fn new(phase: Phase, on_progress: F) -> Self {
+        Self {
+            phase,
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            on_progress,
+        }
+    }
+
+    /// Records that we've finished with a file, after reading `bytes` from it,
+    /// and reports the new totals.
+    fn file_done(&self, bytes: u64) {
+        let files = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_hashed = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        (self.on_progress)(Progress {
+            phase: self.phase,
+            files,
+            bytes_hashed,
+            errors: self.errors.load(Ordering::Relaxed),
+        });
+    }
+
+    /// Records that we've given up on a file because it couldn't be read, and
+    /// reports the new totals.
+    fn file_failed(&self) {
+        let files = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+        let errors = self.errors.fetch_add(1, Ordering::Relaxed) + 1;
+        (self.on_progress)(Progress {
+            phase: self.phase,
+            files,
+            bytes_hashed: self.bytes.load(Ordering::Relaxed),
+            errors,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for opening a file on a flaky network share: the first
+    /// `failures` attempts fail with an error of kind `kind`, and after that,
+    /// the file reads fine.
+    struct FlakyOpener {
+        failures: u32,
+        kind: ErrorKind,
+        attempts: Cell<u32>,
+    }
+
+    impl FlakyOpener {
+        fn new(failures: u32, kind: ErrorKind) -> Self {
+            Self { failures, kind, attempts: Cell::new(0) }
+        }
+
+        fn open_and_read(&self) -> anyhow::Result<Vec<u8>> {
+            self.attempts.set(self.attempts.get() + 1);
+            if self.attempts.get() <= self.failures {
+                return Err(std::io::Error::from(self.kind).into());
+            }
+            let mut data = vec![];
+            std::io::Cursor::new(b"the contents").read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn retries_until_a_transient_error_goes_away() {
+        let opener = FlakyOpener::new(2, ErrorKind::TimedOut);
+        let data = with_retries(3, || opener.open_and_read()).unwrap();
+        assert_eq!(data, b"the contents");
+        assert_eq!(opener.attempts.get(), 3);
+
+        let opener = FlakyOpener::new(3, ErrorKind::WouldBlock);
+        assert_eq!(with_retries(3, || opener.open_and_read()).unwrap(), b"the contents");
+        assert_eq!(opener.attempts.get(), 4);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let opener = FlakyOpener::new(5, ErrorKind::TimedOut);
+        let e = with_retries(2, || opener.open_and_read()).unwrap_err();
+        assert!(is_transient(&e));
+        assert_eq!(opener.attempts.get(), 3);
+
+        // With no retries, there's only the one attempt.
+        let opener = FlakyOpener::new(1, ErrorKind::TimedOut);
+        assert!(with_retries(0, || opener.open_and_read()).is_err());
+        assert_eq!(opener.attempts.get(), 1);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_get_the_prefix() {
+        let deep = "deeper\\".repeat(40);
+        let spelled = |path: &str| long_path(Path::new(path)).into_owned().into_os_string();
+
+        assert_eq!(spelled(r"C:\short\path"), r"C:\short\path");
+        assert_eq!(spelled(&format!(r"C:\{deep}file")), format!(r"\\?\C:\{deep}file").as_str());
+        // Forward slashes and `..` have to go, since Windows won't deal with
+        // them after the prefix.
+        assert_eq!(spelled(&format!(r"C:/x/../{deep}file")), format!(r"\\?\C:\{deep}file").as_str());
+        assert_eq!(spelled(&format!(r"\\server\share\{deep}file")),
+            format!(r"\\?\UNC\server\share\{deep}file").as_str());
+        let already = format!(r"\\?\C:\{deep}file");
+        assert_eq!(spelled(&already), already.as_str());
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        for kind in [ErrorKind::NotFound, ErrorKind::PermissionDenied] {
+            let opener = FlakyOpener::new(1, kind);
+            let e = with_retries(3, || opener.open_and_read()).unwrap_err();
+            assert_eq!(e.downcast_ref::<std::io::Error>().unwrap().kind(), kind);
+            assert_eq!(opener.attempts.get(), 1, "{kind:?}");
+        }
+    }
+}
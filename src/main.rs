@@ -2,15 +2,50 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{collections::{BTreeMap, HashMap}, fs::File, io::{BufReader, ErrorKind, Read, Seek}, path::{Path, PathBuf}, time::Instant};
+use std::{cell::{Cell, RefCell}, cmp::Reverse, collections::{BTreeMap, HashMap, HashSet}, ffi::OsString, fmt::Display, fs::File, io::{BufRead, IsTerminal, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc}, process::ExitCode, time::{Duration, Instant, SystemTime}};
 
 use anyhow::{bail, Context as _};
-use clap::Parser;
+use clap::{builder::{PossibleValuesParser, TypedValueParser as _}, Parser, ValueEnum};
+use drupes::{dedup_same_files, discard_unique_sizes, file_id, find_duplicate_dirs, first_mismatch_sampled, first_mismatch_with_progress, group_text_files, hash_file, is_sparse, pass_three, pass_three_each, pass_two, summarize, texts_match, walk, walk_files, write_csv, write_groups, write_json, write_json_lines, write_stats_json, write_table, Digest, DuplicateGroup, HashAlgorithm, HashCache, ListOptions, Progress, ScanOptions};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use size::Size;
-use jwalk::WalkDir;
 
-const PREHASH_SIZE: usize = 4 * 1024;
+/// Ways of choosing which file to keep in a group of duplicates.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Keep {
+    /// Keep the file with the oldest modification time.
+    Oldest,
+    /// Keep the file with the newest modification time.
+    Newest,
+    /// Keep the file with the shortest path.
+    ShortestPath,
+    /// Keep the file with the longest path.
+    LongestPath,
+}
+
+/// Orders in which to list the files within a group of duplicates.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum PathOrder {
+    /// Alphabetical order.
+    Alpha,
+    /// Oldest modification time first.
+    Mtime,
+    /// Fewest directories deep first.
+    Depth,
+}
+
+/// Orders in which to print groups of duplicates.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum SortKey {
+    /// Groups that would free up the most space, if removed, come first.
+    Size,
+    /// Groups with the most duplicates come first.
+    Count,
+    /// Groups are sorted by the path of their first file.
+    Path,
+}
 
 /// Finds duplicate files and optionally deletes them.
 ///
@@ -27,395 +62,2827 @@ struct Drupes {
     #[clap(short, long)]
     empty: bool,
 
+    /// Let `--delete`, `--trash`, `--link`, `--reflink`, and `--script` act on
+    /// empty files found with `--empty`. By default they leave them alone,
+    /// since empty files only "match" because there's nothing in them, and
+    /// are often there for a reason (like marking a directory as a Python
+    /// package).
+    #[clap(long, requires = "empty")]
+    delete_empties: bool,
+
+    /// Count empty files in the totals printed by `--summarize`, without
+    /// reporting any of them as duplicates.
+    #[clap(long, conflicts_with = "empty")]
+    count_empty: bool,
+
+    /// Ignore files smaller than this. Accepts a plain number of bytes, or a
+    /// human-readable size like `10M` or `1.5 GiB`.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size, default_value = "0")]
+    min_size: u64,
+
+    /// Ignore files larger than this. Accepts the same formats as
+    /// `--min-size`, and can be combined with it to consider only files in a
+    /// range of sizes.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Ignore files that are exactly this size, e.g. placeholder files that
+    /// are all the same length, without ignoring anything bigger or smaller.
+    /// Accepts the same suffixes as `--min-size`. May be given more than once.
+    #[clap(long, value_name = "BYTES", value_parser = parse_size)]
+    exclude_size: Vec<u64>,
+
+    /// Ignore files last modified before this. Accepts a duration before now,
+    /// like `7d`, `24h`, or `30m` (with `s`, `m`, `h`, `d`, or `w` for
+    /// seconds, minutes, hours, days, or weeks), or a date like `2024-01-31`
+    /// or `2024-01-31T12:00:00`, which is taken to be in UTC.
+    #[clap(long, value_name = "WHEN", value_parser = parse_time)]
+    newer_than: Option<SystemTime>,
+
+    /// Ignore files last modified after this. Accepts the same formats as
+    /// `--newer-than`, and can be combined with it to consider only files
+    /// modified in a window of time.
+    #[clap(long, value_name = "WHEN", value_parser = parse_time)]
+    older_than: Option<SystemTime>,
+
+    /// Skip files matching this glob pattern, like `*.tmp` or
+    /// `**/node_modules/**`. Patterns are matched against both the full path
+    /// and the bare filename, and a directory that matches is skipped along
+    /// with everything in it. May be given more than once.
+    ///
+    /// A pattern starting with `!` brings back files that earlier patterns
+    /// excluded, as in a `.gitignore`; when several patterns match, the last
+    /// one wins. Files in a directory that's been skipped can't be brought
+    /// back, though.
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Skip files matching the patterns in this file, which is read the same
+    /// way as a `.gitignore`: `#` starts a comment, `!` brings back files that
+    /// earlier patterns excluded, a trailing `/` matches only directories, and
+    /// a pattern with a `/` at the start or in the middle is anchored to the
+    /// top of each directory being searched (or, with `--files-from`, the
+    /// current directory). May be given more than once, with later files
+    /// taking precedence. A file is skipped if it matches either these or
+    /// `--exclude`, so a `!` in one can't bring back files the other excluded.
+    #[clap(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Only consider files with this extension, like `jpg`. Case doesn't
+    /// matter, and a leading `.` is optional; `.` on its own means files with
+    /// no extension. May be given more than once.
+    #[clap(long, value_name = "EXT")]
+    include_ext: Vec<String>,
+
+    /// Skip files with this extension, written the same way as for
+    /// `--include-ext`. If an extension is both included and excluded, it's
+    /// excluded. May be given more than once.
+    #[clap(long, value_name = "EXT")]
+    exclude_ext: Vec<String>,
+
     /// Don't print the first filename in a set of duplicates, so that all the
     /// printed filenames are files to consider removing.
     #[clap(short('f'), long)]
     omit_first: bool,
 
+    /// End each printed filename with a NUL byte instead of a newline, for
+    /// feeding to `xargs -0` and friends. This is safe for any filename, even
+    /// ones containing newlines. Sets of duplicates are separated by an extra
+    /// NUL, unless `--omit-first` is given.
+    #[clap(short('0'), long, conflicts_with_all = ["summarize", "json", "json_lines", "csv"])]
+    null: bool,
+
+    /// Like `-0`, but guarantees that which files belong together can be read
+    /// back, whatever their names. Every path is followed by a NUL, and every
+    /// set of duplicates by one more, so each set ends with two NULs in a row.
+    /// Since a path can't be empty or contain a NUL, that can't be mistaken for
+    /// anything else.
+    #[clap(long, conflicts_with_all = [
+        "omit_first", "quiet", "summarize", "json", "json_lines", "csv",
+        "unique_to", "checksum_output", "manifest",
+    ])]
+    print0_groups: bool,
+
+    /// Print a header line before each set of duplicates, saying how many
+    /// files it has, how big they are, and how much space deleting all but
+    /// the first would free up. Ignored for output that isn't plain text.
+    #[clap(long)]
+    headers: bool,
+
+    /// Don't print blank lines between sets of duplicates (or, with `-0`, the
+    /// extra NUL), so that the output is nothing but paths. Has no effect on
+    /// `--summarize`, `--json`, or `--csv`.
+    #[clap(short, long, conflicts_with = "headers")]
+    quiet: bool,
+
+    /// Point out sets of duplicates whose files have different extensions
+    /// (like `.jpg` and `.jpeg`, or `.bak`), with a comment line before each,
+    /// since those copies are often there on purpose. Only for the plain list
+    /// of duplicates.
+    #[clap(long, conflicts_with_all = [
+        "null", "print0_groups", "summarize", "stats_json", "json", "json_lines",
+        "csv", "table", "dirs", "unique_to", "checksum_output", "manifest",
+    ])]
+    note_extension_mismatch: bool,
+
     /// Instead of listing duplicates, print a summary of what was found.
     #[clap(short('m'), long)]
     summarize: bool,
 
+    /// With `--summarize`, also list the sets of duplicates that would free up
+    /// the most space, largest first, in the same form as `--table`. Shows up
+    /// to this many sets (10, if no number is given).
+    #[clap(long, value_name = "ROWS", num_args = 0..=1, default_missing_value = "10",
+        requires = "summarize")]
+    breakdown: Option<usize>,
+
+    /// With `--summarize`, also break down the space that could be reclaimed
+    /// by type of file, going by each duplicate's extension.
+    #[clap(long, requires = "summarize")]
+    by_type: bool,
+
+    /// Like `--summarize`, but print the numbers as a JSON object, with sizes
+    /// in bytes, for other programs to read. As well as the counts, this
+    /// includes `elapsed_ms`, how long the run took in milliseconds.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "json", "json_lines", "csv", "null", "print0_groups",
+        "dirs", "unique_to", "checksum_output", "manifest",
+    ])]
+    stats_json: bool,
+
+    /// Instead of listing duplicates, print nothing but the number of
+    /// duplicate files found, not counting the one in each set that would be
+    /// kept, e.g. for a monitoring check.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "stats_json", "json", "json_lines", "csv", "table", "null",
+        "print0_groups", "headers", "limit", "dirs", "unique_to", "checksum_output",
+        "manifest",
+    ])]
+    count_only: bool,
+
+    /// Instead of listing duplicates as text, print them as a JSON array of
+    /// objects with `hash`, `size`, and `paths` fields. Paths that aren't valid
+    /// UTF-8 will have the offending parts replaced with U+FFFD, so don't feed
+    /// those back into anything that deletes files.
+    #[clap(long, conflicts_with = "summarize")]
+    json: bool,
+
+    /// Like `--json`, but print each set of duplicates as a separate object on
+    /// its own line (i.e. JSON Lines), so that it can be processed a line at a
+    /// time instead of all at once. Each set is printed as soon as it's been
+    /// found, so they come in no particular order, unless `--sort`, `--limit`,
+    /// `--same-name`, or `--text-normalize` is given, which need them all
+    /// first.
+    #[clap(long, conflicts_with_all = ["summarize", "json"])]
+    json_lines: bool,
+
+    /// Instead of listing duplicates as text, print them as CSV, one row per
+    /// file, with `hash`, `size`, `path`, and `is_keeper` columns. The keeper
+    /// is the file in each group that `--delete` and friends would leave in
+    /// place. Paths are mangled the same way as with `--json`.
+    #[clap(long, conflicts_with_all = ["summarize", "json", "json_lines"])]
+    csv: bool,
+
+    /// Instead of listing every file, print a table with a row for each set
+    /// of duplicates, giving the number of copies, their size, how much space
+    /// removing the extras would free, and the path of the one that would be
+    /// kept. Long paths are shortened to fit the terminal.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "stats_json", "json", "json_lines", "csv", "null",
+        "print0_groups", "omit_first", "headers", "quiet", "dirs", "unique_to",
+        "checksum_output", "manifest",
+    ])]
+    table: bool,
+
+    /// With `--table`, also list the rest of the files in each set, under the
+    /// one that would be kept.
+    #[clap(long, requires = "table")]
+    expand: bool,
+
+    /// After listing the duplicates, print a line to standard error saying
+    /// how many files were scanned, and how many duplicates were listed.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "stats_json", "dirs", "unique_to", "checksum_output", "manifest",
+    ])]
+    footer: bool,
+
+    /// Order in which to print groups of duplicates. By default, they're
+    /// sorted by path, or by hash with `--json`, `--json-lines`, and `--csv`.
+    #[clap(long, value_enum, value_name = "KEY")]
+    sort: Option<SortKey>,
+
+    /// Order in which to list the files within each set of duplicates. Files
+    /// that tie are listed alphabetically. Since the first file in each set is
+    /// the one that's kept, this also decides which file that is, unless
+    /// `--keep`, `--prefer-dir`, or the like say otherwise.
+    #[clap(long, value_enum, value_name = "ORDER", default_value = "alpha")]
+    sort_paths: PathOrder,
+
+    /// Only show the first N groups of duplicates (after sorting, so this
+    /// combines well with `--sort size`). If you're deleting, trashing, or
+    /// linking, only the groups shown are acted on; the rest are left alone.
+    #[clap(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Only show groups of duplicates with at least N files in them, e.g. to
+    /// find files that have been copied over and over. If you're deleting,
+    /// trashing, or linking, smaller groups are left alone. The default is 2,
+    /// which shows every group.
+    #[clap(long, value_name = "N", default_value_t = 2,
+        conflicts_with_all = ["dirs", "unique_to"])]
+    min_group_size: usize,
+
+    /// Print paths relative to this directory, to keep them short. Paths that
+    /// aren't under it are printed as they are. This only changes how paths
+    /// are shown in the report, in any format.
+    #[clap(long, value_name = "DIR")]
+    relative_to: Option<PathBuf>,
+
+    /// Write the report (the list of duplicates, or whatever else was asked
+    /// for) to this file, instead of standard output. The file only appears
+    /// once the report is complete. Refuses to replace an existing file
+    /// unless `--force` is given.
+    #[clap(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Allow `--output` or `--script` to replace an existing file.
+    #[clap(long)]
+    force: bool,
+
     /// Engages "paranoid mode" and performs byte-for-byte comparisons of files,
     /// in case you've found the first real-world BLAKE3 hash collision (please
-    /// publish it if so)
+    /// publish it if so). Groups that turn out not to match aren't reported.
     #[clap(short, long)]
     paranoid: bool,
 
+    /// Like `--paranoid`, but only compare about this percentage of each file,
+    /// in chunks spread evenly from start to end, as a quicker spot check.
+    /// Files that only differ outside those chunks won't be caught, so before
+    /// deleting or linking anything, files are still compared in full (unless
+    /// `--trust-hash` is given).
+    #[clap(long, value_name = "PERCENT", value_parser = parse_percent,
+        conflicts_with = "paranoid")]
+    verify_sample: Option<f64>,
+
+    /// Don't compare duplicates byte-for-byte before deleting, trashing, or
+    /// linking them; trust that files with the same BLAKE3 hash are the same.
+    /// This is faster, since each file is only read once, but a little less
+    /// safe.
+    #[clap(long)]
+    trust_hash: bool,
+
+    /// Treat text files of up to this size (1M, if no size is given) as
+    /// duplicates if they only differ in line endings (CRLF or LF), whitespace
+    /// at the ends of lines, or blank lines at the end. A file counts as text
+    /// if it has no NUL bytes in it. Such copies aren't byte-for-byte the
+    /// same, so this gives up the guarantee that `--paranoid` offers; before
+    /// deleting or linking, text files are compared again once normalized,
+    /// rather than byte-for-byte.
+    #[clap(long, value_name = "MAX_SIZE", num_args = 0..=1, require_equals = true,
+        default_missing_value = "1M", value_parser = parse_size,
+        conflicts_with_all = ["paranoid", "verify_sample", "dirs", "unique_to",
+            "checksum_output", "manifest", "write_manifest"])]
+    text_normalize: Option<u64>,
+
     /// Try to delete all duplicates but one, skipping any files that cannot be
-    /// deleted for whatever reason.
+    /// deleted for whatever reason. This asks for confirmation first, unless
+    /// `--yes` is given.
     #[clap(long)]
     delete: bool,
 
+    /// Don't ask before deleting with `--delete`. This is needed when not
+    /// running from a terminal, e.g. in a script.
+    #[clap(short, long, requires = "delete")]
+    yes: bool,
+
+    /// Like `--delete`, but move duplicates to the trash (or recycle bin),
+    /// instead of deleting them outright, so that you can get them back if
+    /// you change your mind.
+    #[clap(long, conflicts_with = "delete")]
+    trash: bool,
+
+    /// Instead of actually deleting, trashing, or linking anything, just print
+    /// what would be done.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Replace all duplicates but one with hardlinks to the one, which
+    /// reclaims their space while leaving every path in place. Duplicates on a
+    /// different filesystem from the file being kept are skipped, since they
+    /// can't be linked.
+    #[clap(long, conflicts_with_all = ["delete", "trash"])]
+    link: bool,
+
+    /// Make all duplicates but one share their storage with the one, using
+    /// copy-on-write "reflinks." Unlike `--link`, the files stay independent:
+    /// changing one later won't change the others. This needs a filesystem
+    /// that supports it, like Btrfs or XFS, and is currently Linux-only.
+    #[clap(long, conflicts_with_all = ["delete", "trash", "link"])]
+    reflink: bool,
+
+    /// Go through each set of duplicates, asking which files to delete. Only
+    /// works when run from a terminal.
+    #[clap(long, conflicts_with_all = ["delete", "trash", "link", "reflink"])]
+    interactive: bool,
+
+    /// Instead of deleting duplicates, write a shell script to this file that
+    /// would delete all but one in each set, so that you can look it over (or
+    /// edit it) and run it yourself. Like `--output`, this refuses to replace
+    /// an existing file unless `--force` is given.
+    #[clap(long, value_name = "FILE",
+        conflicts_with_all = ["delete", "trash", "link", "reflink", "interactive"])]
+    script: Option<PathBuf>,
+
+    /// After deleting or trashing duplicates, also remove any directories
+    /// that were left empty, working upwards until reaching a directory that
+    /// still has something in it, or one of the directories being searched
+    /// (which are never removed).
+    #[clap(long, conflicts_with = "files_from")]
+    prune_empty_dirs: bool,
+
+    /// As each duplicate is deleted or trashed, add a line to this file saying
+    /// which file it was and which copy was kept, so that there's a record of
+    /// what was removed even if drupes is interrupted partway through. Each
+    /// line is a JSON object, and the file is appended to if it exists.
+    #[clap(long, value_name = "FILE", conflicts_with = "dry_run")]
+    delete_log: Option<PathBuf>,
+
+    /// Follow symbolic links to files and directories, instead of ignoring
+    /// them. Directories that are reached more than once this way (including
+    /// through links that form a loop) are only searched the first time.
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// Report files that are hardlinks to the same underlying file as
+    /// duplicates of one another. By default, only one name for each such file
+    /// is considered, since they don't actually take up any extra space (and
+    /// "deleting" one would reclaim nothing). Only has an effect on Unix.
+    #[clap(long)]
+    keep_hardlinks: bool,
+
+    /// With `--keep-hardlinks`, mark each file that's a hardlink to the file
+    /// that's kept with `(hardlink)` after its path, since getting rid of it
+    /// won't free up any space. Only for the plain list of duplicates.
+    #[clap(long, requires = "keep_hardlinks", conflicts_with_all = [
+        "null", "print0_groups", "summarize", "stats_json", "count_only", "json",
+        "json_lines", "csv", "table", "dirs", "unique_to", "checksum_output",
+        "manifest", "relative_to",
+    ])]
+    annotate_hardlinks: bool,
+
+    /// Don't search directories on a different filesystem from the directory
+    /// being searched, like `find -xdev`. Handy for keeping a search of `/`
+    /// out of `/proc` and network mounts. Only has an effect on Unix.
+    #[clap(short('x'), long)]
+    same_filesystem: bool,
+
+    /// Only search this many levels deep in each directory being searched:
+    /// with 1, only the files directly inside it are looked at, and none of
+    /// its subdirectories are searched.
+    #[clap(long, value_name = "N", conflicts_with = "files_from")]
+    max_depth: Option<usize>,
+
+    /// Only look at the files directly inside each directory being searched,
+    /// without searching any of its subdirectories. This is the same as
+    /// `--max-depth 1`.
+    #[clap(long, conflicts_with_all = ["max_depth", "files_from"])]
+    no_recursion: bool,
+
+    /// Also search hidden files and directories, whose names start with `.`
+    /// (or, on Windows, which have the hidden attribute set), including `.git`
+    /// directories. These are skipped by default.
+    #[clap(long)]
+    hidden: bool,
+
+    /// Skip hidden files and directories, without searching inside hidden
+    /// directories at all. This is the default, so this is only needed to
+    /// undo an earlier `--hidden`, e.g. one in a shell alias.
+    #[clap(long, overrides_with = "hidden")]
+    exclude_hidden: bool,
+
+    /// Skip files and directories listed in `.gitignore` and `.ignore` files,
+    /// in the repository's `.git/info/exclude`, and in your global git ignore
+    /// file (`core.excludesFile`, or `~/.config/git/ignore` by default), as
+    /// well as `.git` directories themselves.
+    /// Ignore files above the directories being searched are read too, up to
+    /// the top of the git repository they're in.
+    #[clap(long)]
+    respect_gitignore: bool,
+
+    /// Choose which file in each set of duplicates is kept (and printed first).
+    /// By default, it's the first one in alphabetical order.
+    #[clap(long, value_enum, value_name = "STRATEGY")]
+    keep: Option<Keep>,
+
+    /// Prefer to keep files under this directory: in each set of duplicates,
+    /// a file under one of these directories is kept over any that aren't.
+    /// This takes priority over `--keep`, which is used to choose between files
+    /// that are equally preferred. May be given more than once.
+    #[clap(long, value_name = "PATH")]
+    prefer_dir: Vec<PathBuf>,
+
+    /// Prefer to keep files under whichever of the directories being searched
+    /// was given first: in each set of duplicates, a file under the
+    /// earliest-listed root is kept, and the copies under later ones are the
+    /// ones deleted or linked. So `drupes --root-priority --delete archive/
+    /// incoming/` removes whatever in `incoming/` is already in `archive/`.
+    /// This comes after `--prefer-dir` and the pattern options, and before
+    /// `--keep`.
+    #[clap(long)]
+    root_priority: bool,
+
+    /// Prefer to keep files whose paths match this glob, like
+    /// `**/originals/**`, in the same way as `--prefer-dir`: in each set of
+    /// duplicates, the file that's kept is one that matches, if any does, and
+    /// the others are the ones deleted or linked. May be given more than once.
+    #[clap(long, value_name = "GLOB")]
+    keep_pattern: Vec<String>,
+
+    /// The opposite of `--keep-pattern`: prefer to keep files whose paths
+    /// *don't* match this glob, so that the ones that do are the ones deleted
+    /// or linked. May be given more than once.
+    #[clap(long, value_name = "GLOB", conflicts_with = "keep_pattern")]
+    delete_pattern: Vec<String>,
+
+    /// Only report sets of duplicates that have files under more than one of
+    /// the directories being searched, ignoring those where all the copies are
+    /// under the same one.
+    #[clap(long)]
+    cross_root_only: bool,
+
+    /// Only treat files as duplicates if they have the same name, as well as
+    /// the same contents, e.g. to find photos that were copied to more than
+    /// one folder, without also turning up unrelated files that happen to
+    /// match.
+    #[clap(long)]
+    same_name: bool,
+
+    /// With `--same-name`, ignore differences in case between names, so that
+    /// `IMG.JPG` and `img.jpg` count as the same name, the way they would on
+    /// most Windows and macOS filesystems.
+    #[clap(long, requires = "same_name")]
+    case_insensitive_names: bool,
+
+    /// Instead of listing duplicates, list the files under this directory
+    /// (which must be, or be inside, one of the directories being searched)
+    /// that have no copy anywhere else, e.g. to see what a backup has that an
+    /// archive doesn't. A file with copies only under the same directory still
+    /// counts as unique to it.
+    #[clap(long, value_name = "ROOT", conflicts_with_all = [
+        "summarize", "json", "json_lines", "csv", "delete", "trash", "link",
+        "reflink", "interactive", "script", "cross_root_only",
+    ])]
+    unique_to: Option<PathBuf>,
+
+    /// Instead of looking for duplicates, print the hash of every file found,
+    /// in the format used by `b3sum`, `sha256sum`, and so on (depending on
+    /// `--hash`), so that the output can be checked later with their `-c`
+    /// option.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "json", "json_lines", "csv", "null", "delete", "trash",
+        "link", "reflink", "interactive", "script", "cross_root_only",
+        "unique_to",
+    ])]
+    checksum_output: bool,
+
+    /// Instead of looking for duplicates, list the files whose contents are
+    /// already in this manifest of known hashes, e.g. one made earlier with
+    /// `--checksum-output` for an archive that isn't around any more. Lines
+    /// are read in the format that writes, though anything after the hash is
+    /// ignored, so a plain list of hashes works too. The hashes need to have
+    /// been made with the same `--hash` algorithm.
+    #[clap(long, value_name = "FILE", conflicts_with_all = [
+        "summarize", "json", "json_lines", "csv", "delete", "trash", "link",
+        "reflink", "interactive", "script", "cross_root_only", "unique_to",
+        "checksum_output",
+    ])]
+    manifest: Option<PathBuf>,
+
+    /// As well as whatever else was asked for, write the hash and size of
+    /// every file found to this file, for reading back later with
+    /// `--manifest`. Every file is hashed in full for this, even ones that
+    /// can't have duplicates, so it takes longer than a plain search. Refuses
+    /// to replace an existing file unless `--force` is given.
+    #[clap(long, value_name = "FILE")]
+    write_manifest: Option<PathBuf>,
+
+    /// Include each file's path in the manifest written by `--write-manifest`.
+    #[clap(long, requires = "write_manifest")]
+    manifest_paths: bool,
+
+    /// Instead of listing duplicate files, list directories whose contents
+    /// are entirely duplicated elsewhere: every file in them, and in their
+    /// subdirectories, has a copy with the same name in the same place in
+    /// the other directory, with nothing left over. Only the topmost such
+    /// directories are listed, not every matching subdirectory within them.
+    #[clap(long, conflicts_with_all = [
+        "summarize", "csv", "headers", "sort", "limit", "delete", "trash",
+        "link", "reflink", "interactive", "script", "keep", "prefer_dir",
+        "root_priority", "keep_pattern", "delete_pattern",
+        "cross_root_only", "same_name", "unique_to", "checksum_output", "manifest",
+        "files_from",
+    ])]
+    dirs: bool,
+
+    /// Number of bytes at the start of each file to check before reading the
+    /// whole thing. Files whose first bytes differ don't need to be read any
+    /// further, so if your files tend to start the same way (log files with
+    /// long headers, say), raising this can help. Accepts the same formats as
+    /// `--min-size`.
+    #[clap(long, value_name = "BYTES", value_parser = parse_prehash_size, default_value = "4K")]
+    prehash_size: usize,
+
+    /// Don't check the start of each file first; just read every file that's
+    /// the same size as another in full. For a few hundred small files, this
+    /// can be quicker than opening each of them twice.
+    #[clap(long, conflicts_with = "prehash_size")]
+    no_prehash: bool,
+
+    /// Hash algorithm to compare files with. BLAKE3 is fast and strong;
+    /// SHA-256 is slower, but gives hashes you can check against `sha256sum`
+    /// and the like in `--json` and `--csv` output; XXH3 is faster still, but
+    /// not cryptographic, so it's best combined with `--paranoid`.
+    #[clap(long, value_name = "ALGO", default_value = "blake3", value_parser =
+        PossibleValuesParser::new(HashAlgorithm::NAMES)
+            .map(|s| s.parse::<HashAlgorithm>().unwrap()))]
+    hash: HashAlgorithm,
+
+    /// Read large files in the usual way when hashing them, instead of
+    /// memory-mapping them. Mapping is usually faster, but not always, e.g. on
+    /// some network filesystems.
+    #[clap(long)]
+    no_mmap: bool,
+
+    /// Remember the hashes of files in this file, and reuse them on later
+    /// runs for files whose size and modification time haven't changed,
+    /// instead of reading them again. The file is created if it doesn't exist.
+    #[clap(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+
+    /// How many more times to try reading a file that fails with an error
+    /// that might be temporary, like a network filesystem timing out, before
+    /// giving up on it. Errors that aren't going to go away, like a file
+    /// having been deleted, are never retried.
+    #[clap(long, value_name = "N", default_value_t = 2)]
+    retries: u32,
+
+    /// Stop with an error if any directory or file can't be read while
+    /// searching, instead of skipping it with a warning.
+    #[clap(long)]
+    strict: bool,
+
+    /// Exit with status 1 if any duplicates were found, and 0 if not, so that
+    /// scripts can tell the difference. Errors then exit with status 2. This
+    /// goes by what was found, even if `--limit` or `--min-group-size` leave
+    /// some or all of it out of what's shown.
+    #[clap(long, conflicts_with_all = ["unique_to", "checksum_output", "manifest"])]
+    exit_code: bool,
+
     /// Enable additional output about what the program is doing.
     #[clap(short, long)]
     verbose: bool,
 
+    /// Show progress bars on stderr while searching for duplicates. This does
+    /// nothing if stderr isn't a terminal.
+    #[clap(long)]
+    progress: bool,
+
+    /// When finished, print how long the run took to stderr, along with how
+    /// much was read from the files being checked and how quickly, e.g. to
+    /// compare one disk with another. With `--summarize`, this is the last
+    /// line of the summary instead, and with `--stats-json`, it adds a
+    /// `bytes_read` field.
+    #[clap(long)]
+    timing: bool,
+
+    /// When finished, print how long each part of the run took to stderr:
+    /// walking the filesystem, prehashing, hashing files in full, and
+    /// comparing them byte-for-byte, if that was done.
+    #[clap(long)]
+    profile: bool,
+
+    /// Number of threads to use for hashing and comparing files, or 0 to use
+    /// one per CPU. On network filesystems, where lots of reads at once tend
+    /// to slow things down, using fewer can help.
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    threads: usize,
+
+    /// Read additional directories to search from this file, one per line, or
+    /// from standard input if the filename is `-`. These are searched in
+    /// addition to any given on the command line.
+    #[clap(long, value_name = "FILE")]
+    roots_from: Option<PathBuf>,
+
+    /// Instead of searching directories, consider only the files listed in
+    /// this file, one per line (or on standard input, if the filename is
+    /// `-`). Listed paths that don't exist or aren't files are skipped, with a
+    /// warning.
+    #[clap(long, value_name = "FILE", conflicts_with_all = [
+        "roots", "roots_from", "unique_to", "cross_root_only",
+    ])]
+    files_from: Option<PathBuf>,
+
     /// List of directories to search, recursively, for duplicate files; if
     /// omitted, the current directory is searched.
     roots: Vec<PathBuf>,
 }
 
-fn main() -> anyhow::Result<()> {
+/// Parses a file size from the command line.
+///
+/// This accepts anything the `size` crate understands (e.g. `200kb`, `1.5
+/// GiB`), plus bare single-letter suffixes like `10M`, which the `size` crate
+/// doesn't handle but which is what everyone actually types. Those are taken
+/// to mean binary units (so `1K` is 1024 bytes), like `du` and friends.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let unit = match s.chars().last() {
+        Some('k' | 'K') => Some("KiB"),
+        Some('m' | 'M') => Some("MiB"),
+        Some('g' | 'G') => Some("GiB"),
+        Some('t' | 'T') => Some("TiB"),
+        _ => None,
+    };
+    let expanded = match unit {
+        Some(unit) => format!("{}{unit}", &s[..s.len() - 1]),
+        None => s.to_string(),
+    };
+    let size = Size::from_str(&expanded)
+        .map_err(|_| format!("not a valid size: {s}"))?;
+    u64::try_from(size.bytes())
+        .map_err(|_| format!("size can't be negative: {s}"))
+}
+
+/// Parses the `--prehash-size`, which is like any other size but needs to be
+/// nonzero and fit in memory.
+fn parse_prehash_size(s: &str) -> Result<usize, String> {
+    let size = parse_size(s)?;
+    if size == 0 {
+        return Err("prehash size must be greater than zero".to_string());
+    }
+    usize::try_from(size).map_err(|_| format!("prehash size too large: {s}"))
+}
+
+/// Parses a percentage for `--verify-sample`, which has to be more than 0 and
+/// no more than 100.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let percent: f64 = s.trim().trim_end_matches('%').parse()
+        .map_err(|_| format!("not a percentage: {s}"))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!("percentage must be more than 0 and at most 100: {s}"));
+    }
+    Ok(percent)
+}
+
+/// Parses a point in time from the command line: either a duration before
+/// now, like `7d`, or a UTC date and optional time, like `2024-01-31` or
+/// `2024-01-31T12:00`.
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Some(ago) = parse_duration(s) {
+        return SystemTime::now().checked_sub(ago)
+            .ok_or_else(|| format!("too long ago: {s}"));
+    }
+    parse_date(s).ok_or_else(|| format!("not a valid duration or date: {s}"))
+}
+
+/// Parses a number followed by a single-letter unit, like `24h`.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = s[..s.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(n.checked_mul(unit)?))
+}
+
+/// Parses `YYYY-MM-DD`, optionally followed by `T` (or a space) and `HH:MM` or
+/// `HH:MM:SS`, as a time in UTC.
+fn parse_date(s: &str) -> Option<SystemTime> {
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    let mut secs = 0;
+    if let Some(time) = time {
+        let mut parts = time.splitn(3, ':');
+        let hour: i64 = parts.next()?.parse().ok()?;
+        let minute: i64 = parts.next()?.parse().ok()?;
+        let second: i64 = parts.next().map_or(Some(0), |s| s.parse().ok())?;
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        secs = hour * 3600 + minute * 60 + second;
+    }
+
+    // Days since 1970-01-01, by way of Howard Hinnant's `days_from_civil`,
+    // which counts years from March so that leap days come last.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + secs;
+    match u64::try_from(secs) {
+        Ok(secs) => SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+        Err(_) => SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    }
+}
+
+/// Reads a list of paths, one per line, from the file at `list`, or from
+/// standard input if it's `-`. `what` says what they are, for errors.
+fn read_path_list(list: &Path, what: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let text = if list == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+            .with_context(|| format!("problem reading {what} from stdin"))?
+    } else {
+        std::fs::read_to_string(list)
+            .with_context(|| format!("problem reading {what} from {}",
+                    list.display()))?
+    };
+    // Trimming trailing whitespace also takes care of the CR at the end of
+    // each line if the file came from Windows.
+    Ok(text.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// The first line of a manifest written by `--write-manifest`, before the
+/// name of the hash algorithm.
+const MANIFEST_HEADER: &str = "# drupes manifest: ";
+
+/// The hashes read from a manifest, for `--manifest`.
+struct Manifest {
+    hashes: HashSet<Digest>,
+    /// The sizes of the files the hashes are of, if the manifest says, which
+    /// only the ones written by `--write-manifest` do.
+    sizes: Option<HashSet<u64>>,
+}
+
+/// Reads a manifest for `--manifest`, which should have hashes made with
+/// `algorithm`. That's either the output of `--checksum-output`, with a hash
+/// at the start of each line, or of `--write-manifest`, which also has a size
+/// after each hash. Blank lines and lines starting with `#` are skipped.
+fn read_manifest(path: &Path, algorithm: HashAlgorithm) -> anyhow::Result<Manifest> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("problem reading manifest {}", path.display()))?;
+    let has_sizes = match text.lines().next().and_then(|line| line.strip_prefix(MANIFEST_HEADER)) {
+        Some(name) if name.trim() != algorithm.to_string() => {
+            bail!("{} has {} hashes, so it needs --hash {}", path.display(), name.trim(),
+                name.trim());
+        }
+        Some(_) => true,
+        None => false,
+    };
+    let mut manifest = Manifest {
+        hashes: HashSet::new(),
+        sizes: has_sizes.then(HashSet::new),
+    };
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // A leading backslash means the path was escaped; see
+        // `escaped_line`.
+        let line = line.strip_prefix('\\').unwrap_or(line);
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()
+            .and_then(|hex| Digest::from_hex(&hex.to_ascii_lowercase()))
+            .with_context(|| format!("not a hash, at line {} of {}", i + 1, path.display()))?;
+        manifest.hashes.insert(hash);
+        if let Some(sizes) = &mut manifest.sizes {
+            let size = fields.next()
+                .and_then(|size| size.parse().ok())
+                .with_context(|| format!("no size, at line {} of {}", i + 1, path.display()))?;
+            sizes.insert(size);
+        }
+    }
+    Ok(manifest)
+}
+
+/// Writes the manifest for `--write-manifest`: a header line, and then a line
+/// for each of `files` with its hash and size, and its path if `with_paths`
+/// is set.
+fn write_manifest(
+    w: &mut impl Write,
+    files: &[(&Path, u64, Digest)],
+    algorithm: HashAlgorithm,
+    with_paths: bool,
+) -> std::io::Result<()> {
+    writeln!(w, "{MANIFEST_HEADER}{algorithm}")?;
+    for (f, size, hash) in files {
+        let fields = format!("{hash}  {size}");
+        if with_paths {
+            writeln!(w, "{}", escaped_line(&fields, f))?;
+        } else {
+            writeln!(w, "{fields}")?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Drupes::parse();
+    let exit_code = args.exit_code;
+    match run(args) {
+        Ok(true) if exit_code => ExitCode::from(1),
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(if exit_code { 2 } else { 1 })
+        }
+    }
+}
+
+/// Does everything, once the arguments are parsed. Returns whether any
+/// duplicates were found, for `--exit-code`.
+fn run(mut args: Drupes) -> anyhow::Result<bool> {
     let start = Instant::now();
 
-    let mut args = Drupes::parse();
+    // Checking no bytes at the start of each file is the same thing as not
+    // checking at all, and `pass_two` knows not to bother reading anything.
+    if args.no_prehash {
+        args.prehash_size = 0;
+    }
+
+    // Everything below that hashes or compares files runs on this pool. The
+    // walk doesn't, since jwalk runs its own show (and will deadlock if asked
+    // to do that from inside a pool with only one thread). Rayon treats 0
+    // threads as "pick for me," which is one per CPU.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build()?;
+
+    // Check this now, rather than after a potentially long scan.
+    if args.interactive {
+        if !std::io::stdout().is_terminal() {
+            bail!("--interactive needs to be run from a terminal");
+        }
+        if args.roots_from.as_deref() == Some(Path::new("-")) {
+            bail!("--interactive reads answers from standard input, so it \
+                can't be used with --roots-from -");
+        }
+        if args.files_from.as_deref() == Some(Path::new("-")) {
+            bail!("--interactive reads answers from standard input, so it \
+                can't be used with --files-from -");
+        }
+    }
+
+    let confirm_delete = confirms_deletion(&args);
+    if confirm_delete {
+        if !std::io::stdout().is_terminal() {
+            bail!("--delete asks for confirmation, so it needs --yes when not \
+                run from a terminal");
+        }
+        if args.roots_from.as_deref() == Some(Path::new("-"))
+            || args.files_from.as_deref() == Some(Path::new("-"))
+        {
+            bail!("--delete asks for confirmation on standard input, so it needs \
+                --yes when reading paths from there");
+        }
+    }
+
+    if args.prune_empty_dirs && !(args.delete || args.trash || args.interactive) {
+        bail!("--prune-empty-dirs only works with --delete, --trash, or --interactive");
+    }
+
+    if let Some(list) = &args.roots_from {
+        args.roots.extend(read_path_list(list, "roots")?);
+    }
+    let files = match &args.files_from {
+        Some(list) => Some(read_path_list(list, "files")?),
+        None => None,
+    };
+
+    // Paths are compared against this in absolute form, so that it doesn't
+    // matter how either of them is spelled.
+    let relative_to = args.relative_to.as_deref()
+        .map(std::path::absolute)
+        .transpose()
+        .context("problem finding --relative-to directory")?;
 
     if args.roots.is_empty() {
         // Search the current directory by default.
         args.roots.push(".".into());
     }
 
-    // PASS ONE
-    //
-    // Traverse the requested parts of the filesystem, collating files by size
-    // (i.e. producing a map with file sizes as keys, and lists of files as
-    // values).
-    //
-    // Any value in the map with more than one path represents a "file size
-    // group," which is a potential duplicate group. On the other hand, any
-    // value in the map containing only _one_ path need not be considered
-    // further.
-    //
-    // We do this because, generally speaking, getting the size of a file is
-    // much cheaper than reading its contents, and in practice file sizes are
-    // _relatively_ unique.
-    let mut paths: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
-    for root in &args.roots {
-        if args.verbose {
-            eprintln!("{:?} starting walk of {}",
-                start.elapsed(), root.display());
-        }
-
-        for entry in WalkDir::new(root) {
-            let entry = entry
-                .with_context(|| format!("problem reading dirent in {}", root.display()))?;
-            let meta = entry.metadata()
-                .with_context(|| format!("problem getting metadata for {}",
-                        entry.path().display()))?;
-            if meta.is_file() && (meta.len() > 0 || args.empty) {
-                paths.entry(meta.len())
-                    .or_default()
-                    .push(entry.path().to_owned());
-            }
+    // Paths are compared as they're spelled, so for this to work, the
+    // directory has to be spelled the same way as (or start with) a root.
+    if let Some(root) = &args.unique_to {
+        if !args.roots.iter().any(|r| root.starts_with(r)) {
+            bail!("--unique-to directory {} isn't one of the directories being \
+                searched", root.display());
         }
     }
 
-    if args.verbose {
-        eprintln!("{:?} pass one complete, found {} size-groups",
-            start.elapsed(), paths.len());
+    if let Some(max) = args.max_size {
+        if args.min_size > max {
+            bail!("--min-size ({}) is larger than --max-size ({max}), so no \
+                files could possibly match",
+                args.min_size);
+        }
     }
 
-    // Drop all file size groups that contain no duplicates (have only one
-    // member).
-    //
-    // This saves about 10% of runtime.
-    paths.retain(|_size, paths| paths.len() > 1);
+    if let (Some(newer), Some(older)) = (args.newer_than, args.older_than) {
+        if newer > older {
+            bail!("--newer-than is later than --older-than, so no files could \
+                possibly match");
+        }
+    }
 
-    if args.verbose {
-        eprintln!("...of which {} had more than one member", paths.len());
+    if args.quiet && (args.summarize || args.stats_json || args.json || args.json_lines
+        || args.csv)
+    {
+        eprintln!("warning: --quiet only affects the plain list of duplicates, \
+            and will be ignored");
     }
 
-    // PASS TWO
-    //
-    // We've reduced the data set to files whose sizes are not unique. This pass
-    // takes those files and hashes the first `PREHASH_SIZE` bytes of each. If
-    // two files have different hashes for the first `PREHASH_SIZE` bytes, they
-    // cannot possibly be duplicates, so we can use this to avoid reading the
-    // full contents of files.
-    //
-    // This is a significant performance improvement for directories of large
-    // files like photos or videos (~50%).
-    //
-    // This is constructed as a Rayon pipeline because (1) I find it reasonably
-    // clear this way once I got used to it and (2) it's by far the
-    // easiest-to-reach "go faster button."
-    let hashed_files: HashMap<blake3::Hash, Vec<&Path>> = paths.par_iter()
-        // Flatten the map into a list of paths to hash, discarding the size
-        // information.
-        .flat_map(|(_size, paths)| paths)
-        // Hash each path, producing a (path, hash) pair. Note that this can
-        // fail to access the filesystem.
-        //
-        // We use `map_with` here to allocate exactly one I/O buffer per backing
-        // Rayon thread, instead of one per closure, because I'm neurotic.
-        .map_with(vec![0u8; PREHASH_SIZE], |buf, path| {
-            let mut f = File::open(path)
-                .with_context(|| format!("unable to open: {}", path.display()))?;
-
-            // Read up to `PREHASH_SIZE` bytes, or fewer if the file is shorter
-            // than that. (It's odd that there's no operation for this in the
-            // standard library.)
-            let mut total = 0;
-            while total < buf.len() {
-                match f.read(&mut buf[total..]) {
-                    Ok(0) => break,
-                    Ok(n) => total += n,
-                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e).context(
-                        format!("unable to read path: {}", path.display())
-                    ),
+    if args.same_filesystem && cfg!(not(unix)) {
+        eprintln!("warning: --same-filesystem isn't supported on this \
+            platform, and will be ignored");
+    }
+
+    if args.force && args.output.is_none() && args.script.is_none()
+        && args.write_manifest.is_none()
+    {
+        bail!("--force only makes sense with --output, --script, or --write-manifest");
+    }
+    if args.delete_log.is_some() && !(args.delete || args.trash || args.interactive) {
+        bail!("--delete-log only makes sense with --delete, --trash, or --interactive");
+    }
+    let mut delete_log = match &args.delete_log {
+        Some(path) => Some(DeleteLog::open(path)?),
+        None => None,
+    };
+    let mut out = Report::open(args.output.as_deref(), args.force)?;
+    let script = match &args.script {
+        Some(path) => Some(Report::open(Some(path), args.force)?),
+        None => None,
+    };
+    let manifest_out = match &args.write_manifest {
+        Some(path) => Some(Report::open(Some(path), args.force)?),
+        None => None,
+    };
+
+    let manifest = match &args.manifest {
+        Some(path) => Some(read_manifest(path, args.hash)?),
+        None => None,
+    };
+    let keep_patterns = glob_set(&args.keep_pattern, "--keep-pattern")?;
+    let delete_patterns = glob_set(&args.delete_pattern, "--delete-pattern")?;
+
+    let opts = args.exclude.iter().fold(ScanOptions::new(), |opts, pattern| opts.exclude(pattern));
+    let opts = args.exclude_from.iter().fold(opts, |opts, list| opts.exclude_from(list));
+    let cache = match &args.cache {
+        Some(path) => Some(Arc::new(HashCache::load(path, args.hash, args.prehash_size)?)),
+        None => None,
+    };
+
+    let opts = args.include_ext.iter().fold(opts, |opts, ext| opts.include_ext(ext));
+    let opts = args.exclude_ext.iter().fold(opts, |opts, ext| opts.exclude_ext(ext));
+    let opts = args.exclude_size.iter().fold(opts, |opts, &size| opts.exclude_size(size))
+        .include_empty(args.empty || args.count_empty)
+        .min_size(args.min_size)
+        .max_size(args.max_size)
+        .newer_than(args.newer_than)
+        .older_than(args.older_than)
+        .follow_symlinks(args.follow_symlinks)
+        .keep_hardlinks(args.keep_hardlinks)
+        .same_filesystem(args.same_filesystem)
+        .max_depth(if args.no_recursion { Some(1) } else { args.max_depth })
+        .include_hidden(args.hidden)
+        .respect_gitignore(args.respect_gitignore)
+        .prehash_size(args.prehash_size)
+        .hash_algorithm(args.hash)
+        .mmap(!args.no_mmap)
+        .cache(cache.clone())
+        .retries(args.retries)
+        .strict(args.strict)
+        .verbose(args.verbose);
+
+    // Progress bars, if requested, go to stderr alongside our other
+    // diagnostics. Each pass gets its own, which is cleared as soon as it's
+    // done, so that any verbose output that follows doesn't end up tacked onto
+    // the end of it.
+    let show_progress = args.progress && std::io::stderr().is_terminal();
+    let bar = |bar: ProgressBar, template: &str| if show_progress {
+        bar.with_style(ProgressStyle::with_template(template).unwrap())
+    } else {
+        ProgressBar::hidden()
+    };
+
+    // We can't know how many files the walk will turn up, so this is just a
+    // counter. It's also skipped in verbose mode, because the walk prints
+    // messages as it goes, which would trample it.
+    let walk_bar = if args.verbose {
+        ProgressBar::hidden()
+    } else {
+        bar(ProgressBar::new_spinner(), "{spinner} walking: {pos} files")
+    };
+    walk_bar.enable_steady_tick(Duration::from_millis(100));
+    // Each pass keeps a count of the files it had to give up on, which we
+    // report at the end, so that it's obvious when a scan didn't see
+    // everything. These are atomics because the progress callbacks for the
+    // later passes are called from several threads at once, in no particular
+    // order, so we keep the largest count we've been told about.
+    let walk_errors = AtomicU64::new(0);
+    let prehash_errors = AtomicU64::new(0);
+    let hash_errors = AtomicU64::new(0);
+    let unreadable = || {
+        walk_errors.load(Ordering::Relaxed)
+            + prehash_errors.load(Ordering::Relaxed)
+            + hash_errors.load(Ordering::Relaxed)
+    };
+    // Similarly, the number of bytes read by each pass, and by any
+    // byte-for-byte comparisons, for `--verbose`.
+    let prehash_bytes = AtomicU64::new(0);
+    let hash_bytes = AtomicU64::new(0);
+    let verify_bytes = AtomicU64::new(0);
+    let bytes_read = || {
+        prehash_bytes.load(Ordering::Relaxed)
+            + hash_bytes.load(Ordering::Relaxed)
+            + verify_bytes.load(Ordering::Relaxed)
+    };
+    // How long each phase took, for `--profile`. Files can be compared more
+    // than once, so the times for a phase are added up.
+    let phase_times = RefCell::new([
+        ("walk", Duration::ZERO),
+        ("prehash", Duration::ZERO),
+        ("fullhash", Duration::ZERO),
+        ("verify", Duration::ZERO),
+    ]);
+    let phase_done = |phase: &str, since: Instant| {
+        let mut times = phase_times.borrow_mut();
+        if let Some((_, time)) = times.iter_mut().find(|(name, _)| *name == phase) {
+            *time += since.elapsed();
+        }
+    };
+    // Set if the timing was already printed as part of `--summarize`.
+    let timing_shown = Cell::new(false);
+    let print_timing = || {
+        if args.timing && !timing_shown.get() {
+            let elapsed = start.elapsed();
+            let rate = bytes_read() as f64 / elapsed.as_secs_f64();
+            eprintln!("finished in {elapsed:.2?}, after reading {} ({}/s)",
+                Size::from_bytes(bytes_read()),
+                Size::from_bytes(rate as u64));
+        }
+        if args.profile {
+            let times: Vec<String> = phase_times.borrow().iter()
+                .map(|(phase, time)| format!("{phase}: {time:.2?}"))
+                .collect();
+            eprintln!("{}", times.join(", "));
+        }
+    };
+    let warn_unreadable = || if unreadable() > 0 {
+        eprintln!("warning: {} files could not be read and were excluded",
+            unreadable());
+    };
+
+    let on_walk_progress = |p: Progress| {
+        walk_bar.set_position(p.files);
+        walk_errors.fetch_max(p.errors, Ordering::Relaxed);
+    };
+    let phase_start = Instant::now();
+    let mut paths = match &files {
+        Some(files) => walk_files(files, &opts, start, on_walk_progress)?,
+        None => walk(&args.roots, &opts, start, on_walk_progress)?,
+    };
+    phase_done("walk", phase_start);
+    walk_bar.finish_and_clear();
+    // For `--count-empty`, the walk was told to include empty files so that
+    // we can count them, but they don't go any further than that.
+    let empty_files = if args.count_empty {
+        paths.remove(&0).map_or(0, |files| files.len())
+    } else {
+        0
+    };
+    let scanned_bytes: u64 = paths.iter()
+        .map(|(size, files)| size * files.len() as u64)
+        .sum();
+    let scanned_files = empty_files + paths.values().map(Vec::len).sum::<usize>();
+
+    // `--checksum-output` and both kinds of manifest need a full, plain hash
+    // of every file, so none of the passes below are any help with those.
+    let plain_hashes = args.checksum_output || manifest.is_some() || manifest_out.is_some();
+    let mut hashed: Vec<(&Path, u64, Digest)> = vec![];
+    if plain_hashes {
+        let total: usize = paths.values().map(Vec::len).sum();
+        if manifest_out.is_some() && !args.checksum_output && manifest.is_none() {
+            eprintln!("note: --write-manifest means hashing all {total} files in full, \
+                not just possible duplicates, which is {} of reading",
+                Size::from_bytes(scanned_bytes));
+        }
+        // A manifest that lists sizes lets us skip any file that isn't one of
+        // those sizes, unless we need to hash everything anyway.
+        let sizes = manifest.as_ref()
+            .and_then(|manifest| manifest.sizes.as_ref())
+            .filter(|_| !args.checksum_output && manifest_out.is_none());
+        let mut files: Vec<(&Path, u64)> = paths.iter()
+            .filter(|(size, _)| sizes.is_none_or(|sizes| sizes.contains(size)))
+            .flat_map(|(size, files)| files.iter().map(|f| (f.as_path(), *size)))
+            .collect();
+        files.sort();
+        let phase_start = Instant::now();
+        let hashes: Vec<_> = pool.install(|| {
+            files.par_iter()
+                .map(|(f, _)| hash_file(f, args.hash))
+                .collect()
+        });
+        phase_done("fullhash", phase_start);
+        for ((f, size), hash) in files.into_iter().zip(hashes) {
+            match hash {
+                Ok(hash) => {
+                    hash_bytes.fetch_add(size, Ordering::Relaxed);
+                    hashed.push((f, size, hash));
                 }
-            }
-            // Hash the first chunk of the file.
-            Ok((blake3::hash(buf), path))
-        })
-        // Squawk about any reads that failed, and remove them from further
-        // consideration.
-        .filter_map(|result| {
-            match result {
-                Ok(data) => Some(data),
                 Err(e) => {
                     eprintln!("{e:?}");
-                    None
+                    hash_errors.fetch_add(1, Ordering::Relaxed);
                 }
             }
-        })
-        // Take the stream of (hash, path) pairs and collate them by hash,
-        // producing "hash groups."
-        //
-        // Rayon's fold is a little surprising: this produces, not a single map,
-        // but a _stream_ of maps, because (roughly speaking) each thread
-        // calculates its own.
-        //
-        // Many hash-groups will only contain one path, and will be filtered out
-        // below. Any group containing multiple paths needs to be hashed more
-        // fully in the next pass.
-        .fold(HashMap::<blake3::Hash, Vec<&Path>>::new, |mut map, (hash, path)| {
-            map.entry(hash).or_default().push(path);
-            map
-        })
-        // Collapse the stream of hashmaps into one, merging hash groups as
-        // required.
-        .reduce(HashMap::new, |mut a, b| {
-            for (k, v) in b {
-                a.entry(k).or_default().extend(v);
-            }
-            a
-        });
+        }
 
-    let unique_prehash_groups = hashed_files.len();
+        if let Some(mut w) = manifest_out {
+            write_manifest(&mut w, &hashed, args.hash, args.manifest_paths)?;
+            w.finish()?;
+        }
 
-    if args.verbose {
-        eprintln!("{:?} pass two complete, found {unique_prehash_groups} \
-            unique first blocks",
-            start.elapsed());
-        let dupesets = hashed_files.values()
-            .filter(|paths| paths.len() > 1)
-            .count();
-        eprintln!("...of which {dupesets} are present in more than one file");
-        let dupes = hashed_files.values()
-            .map(|paths| paths.len().saturating_sub(1))
-            .sum::<usize>();
-        eprintln!("...for a total of {dupes} possibly redundant files");
-    }
-
-    // PASS THREE
-    //
-    // For any files whose first `PREHASH_SIZE` bytes match at least one other
-    // file, hash the entire contents to scan for differences later on.
-    let mut hashed_files = hashed_files.into_par_iter()
-        // Ignore groups with only one member.
-        .filter(|(_, paths)| paths.len() > 1)
-        // Flatten the `prehash => vec of paths` map to a stream of `prehash,
-        // path` pairs. Since the prehash has no (straightforward) relation to
-        // the hash of the overall file, we don't need to maintain the group
-        // structure.
-        //
-        // We do, however, forward the prehash value on, so that we can use it
-        // for keying below.
-        .flat_map(|(hash, paths)| paths.into_par_iter().map(move |p| (hash, p)))
-        // Hash the tail of each file to produce `(path, hash)` pairs. Note that
-        // this can fail to access the filesystem (again).
-        //
-        // This takes the prehash as input, and uses it as the key for a keyed
-        // hash of the rest of the file. This is important for correctness: if
-        // we just hashed the tail end of every file, we could detect two files
-        // as "identical" even if their first `PREHASH_SIZE` bytes differed! By
-        // incorporating the prehash as key we chain the two hashes and prevent
-        // this.
-        //
-        // For files smaller than `PREHASH_SIZE`, we immediately finalize the
-        // keyed hash without reading anything.
-        .map(|(prehash, path)| {
-            let mut f = File::open(path)
-                .with_context(|| format!("unable to open: {}", path.display()))?;
-            let mut hasher = blake3::Hasher::new_keyed(prehash.as_bytes());
-
-            // Small files have already been completely hashed. Skip them.
-            if f.metadata()?.len() > PREHASH_SIZE as u64 {
-                f.seek(std::io::SeekFrom::Start(PREHASH_SIZE as u64))?;
-                hasher.update_reader(f)?;
-            }
-            Ok::<_, anyhow::Error>((hasher.finalize(), path))
-        })
-        // Squawk about any reads that failed, and remove them from further
-        // consideration.
-        .filter_map(|result| {
-            match result {
-                Ok(data) => Some(data),
-                Err(e) => {
-                    eprintln!("{e}");
-                    None
+        if let Some(manifest) = &manifest {
+            let mut archived = 0;
+            for (f, _, hash) in &hashed {
+                if !manifest.hashes.contains(hash) {
+                    continue;
+                }
+                archived += 1;
+                let f = display_path(f, relative_to.as_deref());
+                if args.null {
+                    out.write_all(f.as_os_str().as_encoded_bytes())?;
+                    out.write_all(b"\0")?;
+                } else {
+                    writeln!(out, "{}", f.display())?;
                 }
             }
-        })
-        // Collect groups of (path, hash) pairs and collate them by hash. This
-        // is identical to the end of Pass Two.
-        .fold(HashMap::<_, Vec<&Path>>::new, |mut map, (hash, path)| {
-            map.entry(hash).or_default().push(path);
-            map
-        })
-        // Collapse the stream of hashmaps into one, merging hash groups as
-        // required. This is also identical to the end of Pass Two.
-        .reduce(HashMap::new, |mut a, b| {
-            for (k, v) in b {
-                a.entry(k).or_default().extend(v);
+            out.finish()?;
+            eprintln!("{archived} of {total} files are already archived, according to {}",
+                args.manifest.as_deref().unwrap_or(Path::new("")).display());
+            warn_unreadable();
+            print_timing();
+            return Ok(false);
+        }
+        if args.checksum_output {
+            // The walk keeps every name a file has, so each hardlink gets a
+            // line of its own, as it would from `b3sum` and the like.
+            for (f, _, hash) in &hashed {
+                let f = display_path(f, relative_to.as_deref());
+                writeln!(out, "{}", checksum_line(hash, &f))?;
             }
-            a
-        });
-
-    if args.verbose {
-        eprintln!("{:?} pass three complete, generating results",
-            start.elapsed());
+            out.finish()?;
+            warn_unreadable();
+            print_timing();
+            return Ok(false);
+        }
     }
 
-    if args.paranoid {
-        // Given our map of collated hash-groups from the previous step, let's
-        // check our work.
-        //
-        // This takes each hash-group containing at least two paths and reads
-        // the contents of each file, comparing them to one another. The files
-        // are not kept in memory, so this works fine on very large files
-        // (keeping files in memory is the operating system's job).
-        //
-        // Note that if this ever finds anything, it is **almost certainly** a
-        // bug in this program. If it isn't a bug in this program, it's probably
-        // a file being modified out from under us. BLAKE3 is
-        // collision-resistant, and finding two files with the same length, same
-        // BLAKE3 hash, and different contents would be a newsworthy event. It's
-        // certainly possible, but rather unlikely.
-        eprintln!("paranoid mode: verifying file contents");
-        hashed_files.par_iter()
-            .filter(|(_, files)| files.len() > 1)
-            .try_for_each(|(_, files)| {
-                // Arbitrarily choose the first file in each group as a
-                // "representative."
-                let first = &files[0];
-                let first_f = File::open(first)?;
-                let first_meta = first_f.metadata()?;
-                let mut first_f = BufReader::new(first_f);
-
-                // Compare it to every other file in the group, one at a time.
-                for other in &files[1..] {
-                    // ...starting from the beginning of the first file, please.
-                    first_f.rewind()?;
-
-                    let other_f = File::open(other)?;
-                    let other_meta = other_f.metadata()?;
-                    let mut other_f = BufReader::new(other_f);
-
-                    // This provides some _very basic_ protection against files
-                    // being modified while this program is running, but in
-                    // general, this program is not written with that situation
-                    // in mind.
-                    if first_meta.len() != other_meta.len() {
-                        bail!("files no longer have same length:\n{}\n{}",
-                            first.display(),
-                            other.display());
-                    }
+    // For `--unique-to`, we need to know about every file under the root in
+    // question, including the ones whose size alone makes them unique, so grab
+    // those before they're discarded.
+    let unique_candidates: Vec<PathBuf> = match &args.unique_to {
+        Some(root) => paths.values()
+            .flatten()
+            .filter(|f| f.starts_with(root))
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+    // Similarly, `--dirs` needs to know about every file, since any file
+    // without a copy stops its directory from being a duplicate.
+    let all_files: Vec<PathBuf> = if args.dirs {
+        paths.values().flatten().cloned().collect()
+    } else {
+        vec![]
+    };
+    // Text files are grouped by their normalized contents, which needn't be
+    // the same size, so take them out before sizes are compared.
+    let text_groups = match args.text_normalize {
+        Some(max_size) => group_text_files(&mut paths, max_size, &opts),
+        None => HashMap::new(),
+    };
+    let text_files: HashSet<&Path> = text_groups.values()
+        .flatten()
+        .map(PathBuf::as_path)
+        .collect();
+    discard_unique_sizes(&mut paths, &opts);
 
-                    // Read one byte at a time from each file, comparing each
-                    // byte. Single byte reads are the easiest thing to
-                    // implement, and are reasonably fast because BufReader
-                    // converts them into larger reads under the hood. No need
-                    // to reimplement the standard library!
-                    let mut buf1 = [0u8];
-                    let mut buf2 = [0u8];
-                    for _ in 0..first_meta.len() {
-                        first_f.read_exact(&mut buf1)?;
-                        other_f.read_exact(&mut buf2)?;
-                        if buf1 != buf2 {
-                            bail!("files differ (blake3 collision found?):\n{}\n{}",
-                                first.display(),
-                                other.display());
-                        }
-                    }
-                }
-                Ok(())
-            })?;
-        eprintln!("files really are duplicates");
+    let total = paths.values().map(|v| v.len() as u64).sum();
+    let prehash_bar = bar(ProgressBar::new(total),
+        "prehashing: {wide_bar} {pos}/{len} files");
+    let phase_start = Instant::now();
+    let hashed_files = pool.install(|| pass_two(&paths, &opts, start, |p| {
+        prehash_bar.set_position(p.files);
+        prehash_errors.fetch_max(p.errors, Ordering::Relaxed);
+        prehash_bytes.fetch_max(p.bytes_hashed, Ordering::Relaxed);
+        if p.files == total {
+            prehash_bar.finish_and_clear();
+        }
+    }));
+    prehash_bar.finish_and_clear();
+    phase_done("prehash", phase_start);
+    let unique_prehash_groups = hashed_files.len();
+
+    // Sparse files, like VM images, are often mostly zeros at the start, so
+    // their prehashes match and they all end up being read in full, which can
+    // take much longer than their size on disk would suggest.
+    let sparse = hashed_files.iter()
+        .filter(|((size, _), files)| files.len() > 1 && *size > args.prehash_size as u64)
+        .flat_map(|(_, files)| files)
+        .filter(|f| std::fs::metadata(f).is_ok_and(|meta| is_sparse(&meta)))
+        .count();
+    if sparse > 0 {
+        eprintln!("note: {sparse} of the files to be hashed look like sparse files; \
+            they'll be read in full, holes and all, which may be slow");
     }
 
-    if args.summarize {
-        // Work out some statistics, instead of printing filenames.
+    let total = hashed_files.values()
+        .filter(|files| files.len() > 1)
+        .map(|files| files.len() as u64)
+        .sum();
+    let hash_bar = bar(ProgressBar::new(total),
+        "hashing: {wide_bar} {pos}/{len} files, {msg} read");
+    let on_hash_progress = |p: Progress| {
+        hash_bar.set_position(p.files);
+        hash_errors.fetch_max(p.errors, Ordering::Relaxed);
+        hash_bytes.fetch_max(p.bytes_hashed, Ordering::Relaxed);
+        hash_bar.set_message(Size::from_bytes(p.bytes_hashed).to_string());
+        if p.files == total {
+            hash_bar.finish_and_clear();
+        }
+    };
 
-        // How many unique size classes did we discover in the first pass?
-        let unique_size_classes = paths.len();
-        // How many files did we find in our recursive scan?
-        let total_files_checked = paths.values().map(|v| v.len()).sum::<usize>();
-
-        // How many hash-groups containing duplicates did we discover?
-        let set_count = hashed_files.values()
-            .filter(|files| files.len() > 1)
-            .count();
-        // And how many duplicates, beyond the first in each group, did we find?
-        let dupe_count = hashed_files.values()
-            .filter_map(|files| files.len().checked_sub(1))
-            .sum::<usize>();
-        // How large are the duplicates on disk?
-        let dupe_size = hashed_files.values()
-            .filter(|files| files.len() > 1)
-            .try_fold(0, |sum, files| {
-                std::fs::metadata(files[0])
-                    .map(|meta| sum + meta.len() * (files.len() as u64 - 1))
-            })?;
-        // Convenient unit formatting:
-        let dupe_size = Size::from_bytes(dupe_size);
-
-        println!("{dupe_count} duplicate files (in {set_count} sets), \
-            occupying {dupe_size}");
-        println!("checked {total_files_checked} files in \
-            {unique_size_classes} size classes");
-        println!("prehashing identified {unique_prehash_groups} groups");
-    } else {
-        // Print filenames of each duplicate-group.
-        for files in hashed_files.values_mut() {
-            if files.len() > 1 {
-                // Our files have arrived in a nondeterministic order due to our
-                // use of concurrency. Let's fix that.
-                files.sort();
-
-                let mut files = files.iter();
-                // Implement the omit-first flag by skipping:
-                if args.omit_first {
-                    files.next();
-                }
+    // Everything that's done to each group of files with the same contents,
+    // once it's been found. This returns whether the group is still worth
+    // reporting. It has to be safe to call from several threads at once,
+    // since with `--json-lines`, it's called on each group as soon as it's
+    // been hashed, so that the group can be printed right away.
+    let verifying = args.paranoid || args.verify_sample.is_some();
+    let announce_verifying = || match args.verify_sample {
+        Some(percent) => eprintln!("verifying {percent}% of file contents"),
+        None if verifying => eprintln!("paranoid mode: verifying file contents"),
+        None => (),
+    };
+    let failed_checks = AtomicUsize::new(0);
+    let unmatched = AtomicUsize::new(0);
+    let is_preferred = |f: &Path| {
+        args.prefer_dir.iter().any(|dir| f.starts_with(dir))
+            || keep_patterns.is_match(f)
+            || (!args.delete_pattern.is_empty() && !delete_patterns.is_match(f))
+    };
+    let finish = |files: &mut Vec<&Path>| {
+        // If the same file was found more than once, by different paths,
+        // make sure it only appears once in its group, so that nothing below
+        // treats it as a duplicate of itself. This is done in path order, so
+        // that the path that's left is the same from run to run.
+        //
+        // That also fixes the order our files arrived in, which is
+        // nondeterministic due to our use of concurrency, so that which file
+        // in each group is treated as the "first" one (and kept, if we're
+        // deleting things) doesn't change from run to run.
+        files.sort();
+        dedup_same_files(files, &opts);
+        if files.len() < 2 {
+            return false;
+        }
 
-                for f in files {
-                    println!("{}", f.display());
-                }
-                if !args.omit_first {
-                    println!();
-                }
+        if args.cross_root_only {
+            // Do this before anything below has a chance to waste time on
+            // groups we're not going to report.
+            let first = root_of(files[0], &args.roots);
+            if files[1..].iter().all(|f| root_of(f, &args.roots) == first) {
+                return false;
             }
         }
-    }
 
-    if args.delete {
-        // The scary delete mode!
-        for files in hashed_files.values() {
-            if files.len() > 1 {
-                for f in &files[1..] {
-                    println!("deleting: {}", f.display());
-                    if let Err(e) = std::fs::remove_file(f) {
-                        eprintln!("error deleting {}: {e}", f.display());
+        // Check our work, if we've been asked to. A group that doesn't check
+        // out gets dropped, so it won't be reported or acted on.
+        if verifying && !group_checks_out(files, args.verify_sample, &verify_bytes) {
+            failed_checks.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // If the user has asked for a different file to be kept, move it to
+        // the front, where everything below expects to find it.
+        sort_paths(files, args.sort_paths);
+        if (!args.keep_pattern.is_empty() && !files.iter().any(|f| keep_patterns.is_match(f)))
+            || (!args.delete_pattern.is_empty()
+                && files.iter().all(|f| delete_patterns.is_match(f)))
+        {
+            unmatched.fetch_add(1, Ordering::Relaxed);
+        }
+        // Move any preferred files to the front. This is a stable sort, so
+        // they're still in order otherwise.
+        files.sort_by_key(|f| !is_preferred(f));
+        let preferred = files.iter().filter(|f| is_preferred(f)).count();
+        // ...and then pick from among those, if there are any.
+        let mut candidates = if preferred > 0 {
+            &mut files[..preferred]
+        } else {
+            &mut files[..]
+        };
+        if args.root_priority {
+            // A file counts as under the first root it's under, even if a
+            // later one is nested inside that. Files that aren't under any
+            // root (from `--files-from`, say) come last.
+            let rank = |f: &Path| {
+                args.roots.iter().position(|root| f.starts_with(root)).unwrap_or(usize::MAX)
+            };
+            candidates.sort_by_key(|f| rank(f));
+            let first = rank(candidates[0]);
+            let earliest = candidates.iter().filter(|f| rank(f) == first).count();
+            candidates = &mut candidates[..earliest];
+        }
+        if let Some(keep) = args.keep {
+            choose_keeper(candidates, keep);
+        }
+        true
+    };
+
+    // For `--unique-to`, a file has a copy elsewhere if any other path with
+    // the same contents is outside the root, even if that's another hardlink
+    // to the very same file, so that's worked out before those are collapsed.
+    let mut copied: HashSet<&Path> = HashSet::new();
+    let streaming = streams_json_lines(&args);
+    let phase_start = Instant::now();
+    // From here on, the groups are kept in a list, since splitting a group up
+    // by name leaves several groups with the same size and hash.
+    let hashed_files: Vec<((u64, Digest), Vec<&Path>)> = if streaming {
+        announce_verifying();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (opts, pool, finish) = (&opts, &pool, &finish);
+        let found = std::thread::scope(|scope| {
+            scope.spawn(move || pool.install(|| {
+                // Sending only fails if we've stopped listening, because
+                // writing the output failed, in which case we can stop too.
+                pass_three_each(hashed_files, opts, start, on_hash_progress, |key, mut files| {
+                    if finish(&mut files) {
+                        tx.send((key, files))?;
                     }
+                    Ok::<_, std::sync::mpsc::SendError<_>>(())
+                })
+            }));
+            let mut found = vec![];
+            for ((size, hash), files) in rx {
+                if files.len() >= args.min_group_size {
+                    let group = DuplicateGroup {
+                        hash,
+                        size,
+                        paths: files.iter()
+                            .map(|f| display_path(f, relative_to.as_deref()))
+                            .collect(),
+                    };
+                    write_json_lines(&mut out, std::slice::from_ref(&group), args.hash)?;
                 }
+                found.push(((size, hash), files));
             }
+            anyhow::Ok(found)
+        })?;
+        hash_bar.finish_and_clear();
+        // Checking each group happened along with the hashing, so it's all
+        // counted as hashing.
+        phase_done("fullhash", phase_start);
+        found
+    } else {
+        let mut hashed_files = pool.install(|| {
+            pass_three(hashed_files, &opts, start, on_hash_progress)
+        });
+        for (key, files) in &text_groups {
+            hashed_files.entry(*key).or_default().extend(files.iter().map(PathBuf::as_path));
         }
 
-    }
+        let mut hashed_files: Vec<((u64, Digest), Vec<&Path>)> = if args.same_name {
+            hashed_files.into_iter()
+                .flat_map(|(key, files)| {
+                    split_by_name(files, args.case_insensitive_names).into_iter()
+                        .map(move |files| (key, files))
+                })
+                .collect()
+        } else {
+            hashed_files.into_iter().collect()
+        };
 
-    Ok(())
+        if let Some(root) = &args.unique_to {
+            for (_, files) in &hashed_files {
+                if files.iter().any(|f| !f.starts_with(root)) {
+                    copied.extend(files.iter().filter(|f| f.starts_with(root)));
+                }
+            }
+        }
+        hash_bar.finish_and_clear();
+        phase_done("fullhash", phase_start);
+
+        announce_verifying();
+        let phase_start = Instant::now();
+        hashed_files = pool.install(|| {
+            hashed_files.into_par_iter()
+                .filter_map(|(key, mut files)| finish(&mut files).then_some((key, files)))
+                .collect()
+        });
+        if verifying {
+            phase_done("verify", phase_start);
+        }
+        hashed_files
+    };
+    if verifying && failed_checks.load(Ordering::Relaxed) == 0 {
+        match args.verify_sample {
+            Some(_) => eprintln!("files match, as far as we checked"),
+            None => eprintln!("files really are duplicates"),
+        }
+    }
+
+    if let (Some(cache), Some(path)) = (&cache, &args.cache) {
+        cache.save(path)?;
+    }
+
+    let unmatched = unmatched.into_inner();
+    if unmatched > 0 {
+        if args.keep_pattern.is_empty() {
+            eprintln!("warning: in {unmatched} sets of duplicates, every file matches \
+                --delete-pattern, so the file to keep was chosen as usual");
+        } else {
+            eprintln!("warning: {unmatched} sets of duplicates have no file matching \
+                --keep-pattern, so the file to keep was chosen as usual");
+        }
+    }
+
+    if args.unique_to.is_some() {
+        // Anything under the root that has a copy outside it isn't unique to
+        // it.
+        let mut unique: Vec<&Path> = unique_candidates.iter()
+            .map(PathBuf::as_path)
+            .filter(|f| !copied.contains(f))
+            .collect();
+        unique.sort();
+
+        for f in unique {
+            let f = display_path(f, relative_to.as_deref());
+            if args.null {
+                out.write_all(f.as_os_str().as_encoded_bytes())?;
+                out.write_all(b"\0")?;
+            } else {
+                writeln!(out, "{}", f.display())?;
+            }
+        }
+        out.finish()?;
+        warn_unreadable();
+        print_timing();
+        return Ok(false);
+    }
+
+    if args.dirs {
+        let hashes: HashMap<&Path, (u64, Digest)> = hashed_files.iter()
+            .filter(|(_, files)| files.len() > 1)
+            .flat_map(|(key, files)| files.iter().map(move |f| (*f, *key)))
+            .collect();
+        let files: Vec<_> = all_files.iter()
+            .map(|f| (f.as_path(), hashes.get(f.as_path()).copied()))
+            .collect();
+        let mut groups = find_duplicate_dirs(&args.roots, &files, args.hash);
+        for group in &mut groups {
+            for dir in &mut group.paths {
+                *dir = display_path(dir, relative_to.as_deref());
+            }
+        }
+        if args.json {
+            write_json(&mut out, &groups, args.hash)?;
+        } else if args.json_lines {
+            write_json_lines(&mut out, &groups, args.hash)?;
+        } else {
+            write_groups(&mut out, &groups, &ListOptions {
+                omit_first: args.omit_first,
+                headers: false,
+                quiet: args.quiet,
+                null: args.null || args.print0_groups,
+                note_mixed_extensions: false,
+                annotate_hardlinks: false,
+            })?;
+        }
+        out.finish()?;
+        warn_unreadable();
+        print_timing();
+        return Ok(!groups.is_empty());
+    }
+
+    // Gather up the groups that actually contain duplicates, along with the
+    // size of each file in the group, for the output modes below.
+    let mut groups: Vec<_> = hashed_files.iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|((size, hash), files)| (hash, *size, files.as_slice()))
+        .collect();
+    // For `--exit-code`, which says whether there are duplicates at all, not
+    // just whether any were shown.
+    let found_any = !groups.is_empty();
+    let too_small = groups.len();
+    groups.retain(|(_, _, files)| files.len() >= args.min_group_size);
+    let too_small = too_small - groups.len();
+
+    // The hash map hands us groups in arbitrary order. Put them in the order
+    // that was asked for, or if nobody asked, sort them by path (or by hash,
+    // for the machine-readable formats), so that the same tree always produces
+    // the same output. Ties are broken by path.
+    match args.sort {
+        Some(SortKey::Size) => sort_by_reclaimable(&mut groups),
+        Some(SortKey::Count) => groups.sort_by_key(|(_, _, files)| {
+            (Reverse(files.len()), *files)
+        }),
+        Some(SortKey::Path) => groups.sort_by_key(|(_, _, files)| *files),
+        // Digests compare byte by byte, which is the same order as their hex.
+        None if args.json || args.json_lines || args.csv => groups.sort_by_key(|(hash, _, files)| (**hash, *files)),
+        None => groups.sort_by_key(|(_, _, files)| *files),
+    }
+
+    let omitted = match args.limit {
+        Some(limit) if limit < groups.len() => {
+            let omitted = groups.len() - limit;
+            groups.truncate(limit);
+            omitted
+        }
+        _ => 0,
+    };
+
+    if args.count_only {
+        let count: usize = groups.iter().map(|(_, _, files)| files.len() - 1).sum();
+        writeln!(out, "{count}")?;
+    } else if args.summarize || args.stats_json {
+        // Work out some statistics, instead of printing filenames.
+        let mut stats = summarize(&paths, unique_prehash_groups,
+            groups.iter().map(|(_, size, files)| (*size, files.len())));
+        stats.unreadable = unreadable();
+        // Text files from `--text-normalize` were taken out of `paths` before
+        // it got this far, but they were still checked.
+        stats.total_files += text_files.len();
+        // Empty files are all the same size, so they count the same way as
+        // any other size class, if there's more than one of them.
+        if empty_files > 1 {
+            stats.total_files += empty_files;
+            stats.size_classes += 1;
+        }
+        if args.timing {
+            stats.timing = Some((start.elapsed(), bytes_read()));
+            timing_shown.set(true);
+        }
+        if args.stats_json {
+            write_stats_json(&mut out, &stats, start.elapsed())?;
+        } else {
+            writeln!(out, "{stats}")?;
+        }
+        let mut notes = vec![];
+        if too_small > 0 {
+            notes.push(format!("({too_small} smaller sets not counted, due to \
+                --min-group-size)"));
+        }
+        if omitted > 0 {
+            notes.push(format!("({omitted} more sets not counted, due to --limit)"));
+        }
+        for note in notes {
+            // Keep the JSON clean, but still say so.
+            if args.stats_json {
+                eprintln!("{note}");
+            } else {
+                writeln!(out, "{note}")?;
+            }
+        }
+        if let Some(rows) = args.breakdown {
+            let mut largest = groups.clone();
+            sort_by_reclaimable(&mut largest);
+            let largest: Vec<DuplicateGroup> = largest.iter().take(rows)
+                .map(|(hash, size, files)| DuplicateGroup {
+                    hash: **hash,
+                    size: *size,
+                    paths: files.iter()
+                        .map(|f| display_path(f, relative_to.as_deref()))
+                        .collect(),
+                })
+                .collect();
+            writeln!(out, "\nlargest sets, by space that could be reclaimed:")?;
+            write_table(&mut out, &largest, report_width(args.output.as_deref()), false)?;
+        }
+        if args.by_type {
+            writeln!(out, "\nspace that could be reclaimed, by type:")?;
+            let types = reclaimable_by_type(&groups);
+            let width = types.iter().map(|(ext, _, _)| ext.chars().count()).max().unwrap_or(0);
+            for (ext, count, bytes) in types {
+                writeln!(out, "{ext:>width$}: {} ({count} files)", Size::from_bytes(bytes))?;
+            }
+        }
+    } else {
+        let shown: Vec<DuplicateGroup> = groups.iter()
+            .map(|(hash, size, files)| DuplicateGroup {
+                hash: **hash,
+                size: *size,
+                paths: files.iter()
+                    .map(|f| display_path(f, relative_to.as_deref()))
+                    .collect(),
+            })
+            .collect();
+        if args.json {
+            write_json(&mut out, &shown, args.hash)?;
+        } else if args.json_lines {
+            // If they were streamed, they've been written already.
+            if !streaming {
+                write_json_lines(&mut out, &shown, args.hash)?;
+            }
+        } else if args.csv {
+            write_csv(&mut out, &shown)?;
+        } else if args.table {
+            write_table(&mut out, &shown, report_width(args.output.as_deref()),
+                args.expand)?;
+        } else {
+            write_groups(&mut out, &shown, &ListOptions {
+                omit_first: args.omit_first,
+                headers: args.headers,
+                quiet: args.quiet,
+                null: args.null || args.print0_groups,
+                note_mixed_extensions: args.note_extension_mismatch,
+                annotate_hardlinks: args.annotate_hardlinks,
+            })?;
+        }
+    }
+    out.finish()?;
+    if args.footer {
+        let stats = summarize(&paths, unique_prehash_groups,
+            groups.iter().map(|(_, size, files)| (*size, files.len())));
+        eprintln!("scanned {scanned_files} files, and found {} duplicates (in {} sets), \
+            occupying {}", stats.dupe_count, stats.set_count,
+            Size::from_bytes(stats.reclaimable_bytes));
+    }
+
+    // The modes below all act on the duplicate groups one at a time, but only
+    // the ones that were shown, if `--limit` hid some. Do that in path order,
+    // regardless of `--sort`, so that the output of a `--dry-run` matches what
+    // the real thing would do.
+    //
+    // Empty files are skipped, unless we've been told otherwise (or we're
+    // going to ask), since they aren't really copies of one another.
+    let modifying = args.delete || args.trash || args.link || args.reflink
+        || args.interactive || args.script.is_some();
+    let skip_empties = modifying && !args.interactive && !args.delete_empties;
+    // Each with the size of its files, as found by the scan, for the
+    // confirmation below.
+    let mut dupe_groups: Vec<(u64, &[&Path])> = groups.iter()
+        .filter(|(_, size, _)| *size > 0 || !skip_empties)
+        .map(|(_, size, files)| (*size, *files))
+        .collect();
+    dupe_groups.sort_by_key(|&(_, files)| files);
+    if let Some((_, _, empties)) = groups.iter().find(|(_, size, _)| *size == 0) {
+        if skip_empties {
+            eprintln!("leaving {} empty files alone; use --delete-empties to \
+                include them", empties.len());
+        }
+    }
+
+    // Before we go changing anything on disk, make sure the files really are
+    // duplicates, unless we already did that in paranoid mode or we've been
+    // told not to bother. Do this for dry runs too, so that they skip the same
+    // groups the real thing would.
+    if modifying && !args.paranoid && !args.trust_hash {
+        let phase_start = Instant::now();
+        dupe_groups = pool.install(|| {
+            dupe_groups.into_par_iter()
+                .filter(|(_, files)| if text_files.contains(files[0]) {
+                    text_group_checks_out(files)
+                } else {
+                    group_checks_out(files, None, &verify_bytes)
+                })
+                .collect()
+        });
+        phase_done("verify", phase_start);
+    }
+
+    if confirm_delete {
+        let count: usize = dupe_groups.iter().map(|(_, files)| files.len() - 1).sum();
+        let bytes: u64 = dupe_groups.iter()
+            .map(|&(size, files)| size * (files.len() as u64 - 1))
+            .sum();
+        let question = format!("about to delete {count} files, reclaiming {}; continue?",
+            Size::from_bytes(bytes));
+        if count > 0
+            && !confirm(&question, &mut std::io::stdin().lock(), &mut std::io::stdout())?
+        {
+            eprintln!("not deleting anything");
+            print_timing();
+            return Ok(true);
+        }
+    }
+    let dupe_groups: Vec<&[&Path]> = dupe_groups.into_iter().map(|(_, files)| files).collect();
+
+    // Everything that's been (or would have been) deleted or trashed, for
+    // `--prune-empty-dirs`.
+    let mut removed = vec![];
+    if let Some(removal) = Removal::from_args(&args) {
+        let (verb, dry_run_verb, action) = removal.verbs();
+        removed = remove_dupes(&dupe_groups, verb, dry_run_verb, args.dry_run,
+            delete_log.as_mut().map(|log| (log, action)),
+            |f| removal.remove(f))?;
+    }
+
+    if let (Some(mut script), Some(path)) = (script, &args.script) {
+        write_script(&mut script, &dupe_groups)?;
+        script.finish()?;
+        make_executable(path)?;
+        let count: usize = dupe_groups.iter().map(|files| files.len() - 1).sum();
+        eprintln!("wrote a script to delete {count} duplicates to {}", path.display());
+    }
+
+    if args.interactive {
+        let mut stdin = std::io::stdin().lock();
+        let mut stdout = std::io::stdout();
+        for (i, files) in dupe_groups.iter().enumerate() {
+            writeln!(stdout, "\nset {} of {}:", i + 1, dupe_groups.len())?;
+            let choice = prompt_for_deletions(files, &mut stdin, &mut stdout)?;
+            match choice {
+                Choice::Delete(indices) => {
+                    let keeper = kept_copy(files, &indices);
+                    for f in indices.into_iter().map(|i| files[i]) {
+                        if args.dry_run {
+                            println!("would delete: {}", f.display());
+                            removed.push(f);
+                            continue;
+                        }
+                        println!("deleting: {}", f.display());
+                        match std::fs::remove_file(f) {
+                            Ok(()) => removed.push(f),
+                            Err(e) => {
+                                eprintln!("error deleting {}: {e}", f.display());
+                                continue;
+                            }
+                        }
+                        if let (Some(log), Some(keeper)) = (&mut delete_log, keeper) {
+                            log.record("deleted", f, keeper)?;
+                        }
+                    }
+                }
+                Choice::KeepAll => (),
+                Choice::Quit => break,
+            }
+        }
+    }
+
+    if args.prune_empty_dirs {
+        prune_empty_dirs(&removed, &args.roots, args.dry_run);
+    }
+
+    if args.link {
+        for files in &dupe_groups {
+            let keeper = files[0];
+            let keeper_dev = device_of(keeper);
+            let id_of = |path: &Path| std::fs::metadata(path).ok().as_ref().and_then(file_id);
+            let keeper_id = id_of(keeper);
+            for f in &files[1..] {
+                // With `--keep-hardlinks`, this can already be a link to the
+                // keeper, and renaming a link over another link to the same
+                // file does nothing at all, which would leave ours behind.
+                if keeper_id.is_some() && id_of(f) == keeper_id {
+                    continue;
+                }
+                if device_of(f) != keeper_dev {
+                    eprintln!("not linking {}: on a different filesystem from {}",
+                        f.display(), keeper.display());
+                    continue;
+                }
+                if args.dry_run {
+                    println!("would link: {} => {}", f.display(), keeper.display());
+                    continue;
+                }
+                println!("linking: {} => {}", f.display(), keeper.display());
+                if let Err(e) = replace_with_link(keeper, f) {
+                    eprintln!("error linking {}: {e:?}", f.display());
+                }
+            }
+        }
+    }
+
+    if args.reflink {
+        for files in &dupe_groups {
+            let keeper = files[0];
+            for f in &files[1..] {
+                if args.dry_run {
+                    println!("would reflink: {} => {}",
+                        f.display(), keeper.display());
+                    continue;
+                }
+                println!("reflinking: {} => {}", f.display(), keeper.display());
+                if let Err(e) = reflink(keeper, f) {
+                    eprintln!("error reflinking {}: {e:?}", f.display());
+                }
+            }
+        }
+    }
+
+    if args.verbose {
+        let read = bytes_read();
+        let percent = if scanned_bytes == 0 {
+            0.0
+        } else {
+            read as f64 / scanned_bytes as f64 * 100.0
+        };
+        eprintln!("{:?} read {} of {} scanned ({percent:.0}%)",
+            start.elapsed(),
+            Size::from_bytes(read),
+            Size::from_bytes(scanned_bytes));
+    }
+
+    // (This is already part of the summary, in that mode.)
+    if !(args.summarize || args.stats_json) {
+        warn_unreadable();
+    }
+    print_timing();
+
+    Ok(found_any)
+}
+
+/// Whether to ask before deleting anything: only `--delete` asks, and not if
+/// it's been told `--yes`, or if it's only a dry run.
+fn confirms_deletion(args: &Drupes) -> bool {
+    args.delete && !args.yes && !args.dry_run
+}
+
+/// Whether `--json-lines` output should be written a group at a time, as each
+/// is found, instead of all at the end. That's the point of it, but it can't
+/// be done if something needs every group before any can be shown: sorting
+/// them, limiting how many are shown, splitting them up by name (which is done
+/// to all of them at once), merging in text files, or showing something other
+/// than the groups.
+fn streams_json_lines(args: &Drupes) -> bool {
+    args.json_lines && args.sort.is_none() && args.limit.is_none() && !args.same_name
+        && args.text_normalize.is_none() && !args.dirs && !args.count_only
+        && !args.stats_json
+}
+
+/// Compares the files in a group of duplicates byte-for-byte, reporting any
+/// that don't match (or can't be read) so that the caller can leave them be.
+/// If `sample` is given, only that percentage of each file is compared. The
+/// number of bytes read is added to `bytes_read`.
+fn group_checks_out(files: &[&Path], sample: Option<f64>, bytes_read: &AtomicU64) -> bool {
+    let on_read = |n| {
+        bytes_read.fetch_add(n, Ordering::Relaxed);
+    };
+    let mismatch = match sample {
+        Some(percent) => first_mismatch_sampled(files, percent, on_read),
+        None => first_mismatch_with_progress(files, on_read),
+    };
+    match mismatch {
+        Ok(None) => true,
+        Ok(Some(other)) => {
+            eprintln!("files differ (hash collision found?), skipping group:\n{}\n{}",
+                files[0].display(),
+                other.display());
+            false
+        }
+        Err(e) => {
+            eprintln!("unable to verify duplicates, skipping: {e:?}");
+            false
+        }
+    }
+}
+
+/// Like [`group_checks_out`], for a group of text files from
+/// `--text-normalize`, which only have to match once normalized.
+fn text_group_checks_out(files: &[&Path]) -> bool {
+    match texts_match(files) {
+        Ok(true) => true,
+        Ok(false) => {
+            eprintln!("text files differ (hash collision found?), skipping group:\n{}",
+                files[0].display());
+            false
+        }
+        Err(e) => {
+            eprintln!("unable to verify duplicates, skipping: {e:?}");
+            false
+        }
+    }
+}
+
+/// Where the report of what was found goes: standard output, or the file given
+/// with `--output`.
+///
+/// A file is written under a temporary name next to where it belongs, and only
+/// renamed into place by `finish`, so that nobody sees a half-written report.
+/// If we stop before then, the temporary file is removed instead.
+struct Report {
+    out: Box<dyn Write>,
+    /// The temporary file being written, and where it should end up.
+    pending: Option<(PathBuf, PathBuf)>,
+}
+
+impl Report {
+    fn open(output: Option<&Path>, force: bool) -> anyhow::Result<Self> {
+        let Some(dest) = output else {
+            return Ok(Self { out: Box::new(std::io::stdout().lock()), pending: None });
+        };
+        // Check this now, rather than after a potentially long scan.
+        if !force && dest.exists() {
+            bail!("{} already exists; use --force to replace it", dest.display());
+        }
+        let name = dest.file_name()
+            .with_context(|| format!("not a valid output file: {}", dest.display()))?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".drupes-tmp");
+        let tmp = dest.with_file_name(tmp_name);
+        let file = File::create(&tmp)
+            .with_context(|| format!("unable to create {}", tmp.display()))?;
+        Ok(Self {
+            out: Box::new(std::io::BufWriter::new(file)),
+            pending: Some((tmp, dest.to_owned())),
+        })
+    }
+
+    /// Finishes off the report, moving it into place if it's going to a file.
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        if let Some((tmp, dest)) = &self.pending {
+            std::fs::rename(tmp, dest)
+                .with_context(|| format!("unable to write {}", dest.display()))?;
+        }
+        self.pending = None;
+        Ok(())
+    }
+}
+
+impl Write for Report {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.out.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for Report {
+    fn drop(&mut self) {
+        if let Some((tmp, _)) = &self.pending {
+            std::fs::remove_file(tmp).ok();
+        }
+    }
+}
+
+/// Sorts groups of duplicates so that the ones that would free up the most
+/// space come first, with ties broken by path.
+fn sort_by_reclaimable(groups: &mut [(&Digest, u64, &[&Path])]) {
+    groups.sort_by_key(|(_, size, files)| {
+        (Reverse(size * (files.len() as u64 - 1)), *files)
+    });
+}
+
+/// Adds up how much space the duplicates in `groups` take for each extension,
+/// for `--by-type`. Each duplicate counts under its own extension, which
+/// needn't be the same as the file that's kept. Extensions are lowercased, so
+/// `JPG` and `jpg` count together.
+///
+/// Returns each extension, with the number of duplicates and their total
+/// size, with the most space first.
+fn reclaimable_by_type(groups: &[(&Digest, u64, &[&Path])]) -> Vec<(String, usize, u64)> {
+    let mut types: HashMap<String, (usize, u64)> = HashMap::new();
+    for (_, size, files) in groups {
+        for f in &files[1..] {
+            let ext = match f.extension() {
+                Some(ext) => ext.to_string_lossy().to_lowercase(),
+                None => "(none)".to_string(),
+            };
+            let entry = types.entry(ext).or_default();
+            entry.0 += 1;
+            entry.1 += size;
+        }
+    }
+    let mut types: Vec<_> = types.into_iter()
+        .map(|(ext, (count, bytes))| (ext, count, bytes))
+        .collect();
+    types.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    types
+}
+
+/// How wide a table in the report can be: the width of the terminal, if
+/// that's where the report is going, or 80 columns otherwise.
+fn report_width(output: Option<&Path>) -> usize {
+    match (output, terminal_size::terminal_size()) {
+        (None, Some((terminal_size::Width(width), _))) => width.into(),
+        _ => 80,
+    }
+}
+
+/// Compiles the globs given with `flag`.
+fn glob_set(patterns: &[String], flag: &str) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)
+            .with_context(|| format!("bad {flag} pattern: {pattern}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Puts a group of duplicates, which is already in alphabetical order, in the
+/// order asked for with `--sort-paths`. Ties keep their alphabetical order.
+fn sort_paths(files: &mut [&Path], order: PathOrder) {
+    match order {
+        PathOrder::Alpha => (),
+        // Files whose mtime we can't get sort last.
+        PathOrder::Mtime => files.sort_by_cached_key(|f| {
+            let t = std::fs::metadata(f).and_then(|meta| meta.modified()).ok();
+            (t.is_none(), t)
+        }),
+        PathOrder::Depth => files.sort_by_key(|f| f.components().count()),
+    }
+}
+
+/// Moves the file that should be kept, according to `keep`, to the front of a
+/// group of duplicates. The others stay in the same order. If several files
+/// are equally good choices, the one closest to the front wins.
+fn choose_keeper(files: &mut [&Path], keep: Keep) {
+    fn position_of_min<K: Ord>(files: &[&Path], key: impl Fn(&Path) -> K) -> usize {
+        files.iter()
+            .enumerate()
+            .min_by_key(|(_, f)| key(f))
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+    fn mtime(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    let index = match keep {
+        // Files whose mtime we can't get sort last either way.
+        Keep::Oldest => position_of_min(files, |f| {
+            let t = mtime(f);
+            (t.is_none(), t)
+        }),
+        Keep::Newest => position_of_min(files, |f| Reverse(mtime(f))),
+        Keep::ShortestPath => position_of_min(files, |f| f.as_os_str().len()),
+        Keep::LongestPath => position_of_min(files, |f| Reverse(f.as_os_str().len())),
+    };
+    files[..=index].rotate_right(1);
+}
+
+/// Formats a line of `--checksum-output`, as `<hash>  <path>`.
+///
+/// Like the coreutils tools, if the path has a backslash or newline in it,
+/// those get escaped, and the line gets a leading backslash to say so.
+fn checksum_line(hash: &Digest, path: &Path) -> String {
+    escaped_line(&hash.to_string(), path)
+}
+
+/// Formats `<fields>  <path>`, escaping the path the same way as
+/// [`checksum_line`].
+fn escaped_line(fields: &str, path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if path.contains(['\\', '\n']) {
+        let escaped = path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{fields}  {escaped}")
+    } else {
+        format!("{fields}  {path}")
+    }
+}
+
+/// The form of `path` to show in the report: relative to `base`, for
+/// `--relative-to`, if it's under `base`, and as it is otherwise. `base` must
+/// be absolute.
+fn display_path(path: &Path, base: Option<&Path>) -> PathBuf {
+    let relative = base.and_then(|base| {
+        let path = std::path::absolute(path).ok()?;
+        Some(path.strip_prefix(base).ok()?.to_path_buf())
+    });
+    relative.unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Splits a group of duplicates up by filename, for `--same-name`, dropping
+/// any file whose name no other file in the group shares. If `ignore_case` is
+/// set, names that only differ in case count as the same.
+fn split_by_name(files: Vec<&Path>, ignore_case: bool) -> Vec<Vec<&Path>> {
+    let mut by_name: BTreeMap<_, Vec<&Path>> = BTreeMap::new();
+    for f in files {
+        let name = f.file_name().map(|name| match name.to_str() {
+            Some(name) if ignore_case => OsString::from(name.to_lowercase()),
+            // Names that aren't Unicode can't be lowercased, so they have to
+            // match exactly.
+            _ => name.to_os_string(),
+        });
+        by_name.entry(name).or_default().push(f);
+    }
+    by_name.into_values().filter(|files| files.len() > 1).collect()
+}
+
+/// Works out which of `roots` a path was found under, by index. If
+/// some roots are inside others, the innermost one wins.
+fn root_of(path: &Path, roots: &[PathBuf]) -> Option<usize> {
+    roots.iter()
+        .enumerate()
+        .filter(|(_, root)| path.starts_with(root))
+        .max_by_key(|(_, root)| root.components().count())
+        .map(|(i, _)| i)
+}
+
+/// What to do with a group of duplicates, according to the user.
+#[derive(Debug, PartialEq, Eq)]
+enum Choice {
+    /// Delete the files at these indices in the group.
+    Delete(Vec<usize>),
+    /// Leave the group alone.
+    KeepAll,
+    /// Leave this group and all the rest alone.
+    Quit,
+}
+
+/// Asks `question` on `output`, and reads a yes or no answer from `input`.
+/// Anything but a yes, including running out of input, counts as no.
+fn confirm(question: &str, input: &mut impl BufRead, output: &mut impl Write)
+    -> std::io::Result<bool>
+{
+    write!(output, "{question} [y/N] ")?;
+    output.flush()?;
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes" | "Yes" | "YES"))
+}
+
+/// The file in a group of duplicates that's kept when the user picks the ones
+/// at `deleting` to delete, for the delete log. The user can pick any of them,
+/// even the first, so it's the first one they didn't pick, if there is one.
+fn kept_copy<'a>(files: &[&'a Path], deleting: &[usize]) -> Option<&'a Path> {
+    (0..files.len()).find(|i| !deleting.contains(i)).map(|i| files[i])
+}
+
+/// Lists the files in a group of duplicates on `output`, numbered from 1, and
+/// asks which ones to delete, reading the answer from `input`.
+///
+/// Keeps asking until it gets something sensible: a list of numbers separated
+/// by spaces or commas, `a` to keep them all, or `q` to quit. Answers that
+/// would delete every file in the group are refused. Running out of input
+/// counts as quitting.
+fn prompt_for_deletions(
+    files: &[&Path],
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::io::Result<Choice> {
+    for (i, f) in files.iter().enumerate() {
+        writeln!(output, "  [{}] {}", i + 1, f.display())?;
+    }
+    loop {
+        write!(output, "delete which? (numbers, `a` to keep all, `q` to quit) ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(Choice::Quit);
+        }
+        let answer = line.trim();
+        match answer {
+            "a" | "A" => return Ok(Choice::KeepAll),
+            "q" | "Q" => return Ok(Choice::Quit),
+            _ => (),
+        }
+
+        let mut indices = vec![];
+        let mut bad = None;
+        for word in answer.split(|c: char| c == ',' || c.is_whitespace()) {
+            if word.is_empty() {
+                continue;
+            }
+            match word.parse::<usize>() {
+                Ok(n) if (1..=files.len()).contains(&n) => indices.push(n - 1),
+                _ => {
+                    bad = Some(word);
+                    break;
+                }
+            }
+        }
+        indices.sort_unstable();
+        indices.dedup();
+
+        if let Some(word) = bad {
+            writeln!(output, "not a number from 1 to {}: {word}", files.len())?;
+        } else if indices.is_empty() {
+            writeln!(output, "please pick some files, or `a` to keep them all")?;
+        } else if indices.len() == files.len() {
+            writeln!(output, "refusing to delete every copy; keep at least one")?;
+        } else {
+            return Ok(Choice::Delete(indices));
+        }
+    }
+}
+
+/// How `--delete` and `--trash` get rid of duplicates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Removal {
+    /// The scary delete mode!
+    Delete,
+    /// The somewhat less scary delete mode.
+    Trash,
+}
+
+impl Removal {
+    /// Which kind of removal was asked for, if any.
+    fn from_args(args: &Drupes) -> Option<Self> {
+        if args.delete {
+            Some(Self::Delete)
+        } else if args.trash {
+            Some(Self::Trash)
+        } else {
+            None
+        }
+    }
+
+    /// What to print before each file that's removed, what to print instead
+    /// for `--dry-run`, and what to say happened in the `--delete-log`.
+    fn verbs(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Self::Delete => ("deleting", "would delete", "deleted"),
+            Self::Trash => ("trashing", "would trash", "trashed"),
+        }
+    }
+
+    fn remove(self, f: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Delete => std::fs::remove_file(f)?,
+            Self::Trash => trash::delete(f)?,
+        }
+        Ok(())
+    }
+}
+
+/// Gets rid of all duplicates but one in each group, using `remove`.
+///
+/// Each path is printed, prefixed by `verb`, before it's removed. Any file that
+/// can't be removed is reported and skipped.
+///
+/// If `dry_run` is set, this prints each path prefixed by `dry_run_verb`
+/// instead, and doesn't actually remove anything.
+///
+/// If there's a `log`, each file that's removed is recorded in it, with the
+/// given action. Failing to write to it is an error, which stops everything.
+///
+/// Returns the files that were removed (or would have been).
+fn remove_dupes<'a, E: Display>(
+    dupe_groups: &[&[&'a Path]],
+    verb: &str,
+    dry_run_verb: &str,
+    dry_run: bool,
+    mut log: Option<(&mut DeleteLog, &str)>,
+    remove: impl Fn(&Path) -> Result<(), E>,
+) -> anyhow::Result<Vec<&'a Path>> {
+    let mut removed = vec![];
+    for files in dupe_groups {
+        for f in &files[1..] {
+            if dry_run {
+                println!("{dry_run_verb}: {}", f.display());
+                removed.push(*f);
+                continue;
+            }
+            println!("{verb}: {}", f.display());
+            match remove(f) {
+                Ok(()) => removed.push(*f),
+                Err(e) => {
+                    eprintln!("error {verb} {}: {e}", f.display());
+                    continue;
+                }
+            }
+            if let Some((log, action)) = &mut log {
+                log.record(action, f, files[0])?;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// The file given with `--delete-log`, which gets a line for each file that's
+/// deleted or trashed, as soon as it's gone.
+struct DeleteLog {
+    file: File,
+    path: PathBuf,
+}
+
+/// A line of a [`DeleteLog`].
+#[derive(serde::Serialize)]
+struct DeleteLogEntry<'a> {
+    /// What happened to the file: "deleted" or "trashed".
+    action: &'a str,
+    path: std::borrow::Cow<'a, str>,
+    /// The copy that was kept, which `path` was a duplicate of.
+    keeper: std::borrow::Cow<'a, str>,
+    /// When it happened, in seconds since the Unix epoch.
+    time: u64,
+}
+
+impl DeleteLog {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("unable to open delete log: {}", path.display()))?;
+        Ok(Self { file, path: path.to_owned() })
+    }
+
+    /// Adds a line saying that `path`, a duplicate of `keeper`, is gone. The
+    /// line is written in one go and synced to disk before this returns, so
+    /// that the log is never behind what's actually been removed.
+    ///
+    /// If this fails, nothing else should be removed, since the log would be
+    /// missing it.
+    fn record(&mut self, action: &str, path: &Path, keeper: &Path) -> anyhow::Result<()> {
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let mut line = serde_json::to_vec(&DeleteLogEntry {
+            action,
+            path: path.to_string_lossy(),
+            keeper: keeper.to_string_lossy(),
+            time,
+        })?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("unable to write to delete log {}, so stopping \
+                after {}", self.path.display(), path.display()))
+    }
+}
+
+/// Writes a shell script that deletes all but the first file in each of
+/// `dupe_groups`, for `--script`. The paths are as they were found, so the
+/// script starts by changing to the current directory.
+fn write_script(w: &mut impl Write, dupe_groups: &[&[&Path]]) -> anyhow::Result<()> {
+    let cwd = std::env::current_dir()?;
+    w.write_all(b"#!/bin/sh\n")?;
+    w.write_all(b"# Written by drupes, to delete all but one file in each set of \
+        duplicates.\n")?;
+    w.write_all(b"cd -- ")?;
+    w.write_all(&shell_quote(&cwd))?;
+    w.write_all(b" || exit 1\n")?;
+    for (i, files) in dupe_groups.iter().enumerate() {
+        // Comments are written with `{:?}`, so that a newline in a path can't
+        // end the comment early.
+        writeln!(w, "\n# set {} of {}: keeping {:?}", i + 1, dupe_groups.len(), files[0])?;
+        for f in &files[1..] {
+            w.write_all(b"rm -f -- ")?;
+            w.write_all(&shell_quote(f))?;
+            w.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `path` for a POSIX shell, by putting it in single quotes. Nothing is
+/// special inside those, except a single quote itself, which has to be closed,
+/// escaped, and reopened.
+fn shell_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in path.as_os_str().as_encoded_bytes() {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Marks the script written by `--script` as executable, where that's a thing.
+#[cfg(unix)]
+fn make_executable(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = std::fs::metadata(path)?.permissions();
+    // Executable by whoever can read it.
+    perms.set_mode(perms.mode() | (perms.mode() & 0o444) >> 2);
+    std::fs::set_permissions(path, perms)
+        .with_context(|| format!("unable to make {} executable", path.display()))
+}
+
+/// Marks the script written by `--script` as executable, which isn't a thing
+/// here.
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Removes the directories that were left empty by removing the files in
+/// `removed`, for `--prune-empty-dirs`. Only directories under one of `roots`
+/// are touched, and never the roots themselves.
+///
+/// Each directory is printed before it's removed, and any that can't be
+/// removed are reported and skipped. If `dry_run` is set, this prints what it
+/// would remove instead, counting the files in `removed` as already gone.
+fn prune_empty_dirs(removed: &[&Path], roots: &[PathBuf], dry_run: bool) {
+    let mut gone: HashSet<PathBuf> = removed.iter().map(|f| f.to_path_buf()).collect();
+    let mut dirs: Vec<&Path> = removed.iter()
+        .filter_map(|f| f.parent())
+        .flat_map(Path::ancestors)
+        .filter(|dir| roots.iter().any(|root| dir.starts_with(root) && dir != root))
+        .collect();
+    // Deepest first, so that a directory's subdirectories have been dealt
+    // with before we check whether it's empty.
+    dirs.sort_by(|a, b| {
+        b.components().count().cmp(&a.components().count()).then(a.cmp(b))
+    });
+    dirs.dedup();
+
+    for dir in dirs {
+        let empty = std::fs::read_dir(dir).is_ok_and(|mut entries| {
+            entries.all(|entry| entry.is_ok_and(|entry| gone.contains(&entry.path())))
+        });
+        if !empty {
+            continue;
+        }
+        if dry_run {
+            println!("would remove empty directory: {}", dir.display());
+            gone.insert(dir.to_path_buf());
+            continue;
+        }
+        println!("removing empty directory: {}", dir.display());
+        match std::fs::remove_dir(dir) {
+            Ok(()) => {
+                gone.insert(dir.to_path_buf());
+            }
+            Err(e) => eprintln!("error removing empty directory {}: {e}", dir.display()),
+        }
+    }
+}
+
+/// Makes `dupe` share its on-disk storage with `keeper`, using the `FICLONE`
+/// ioctl.
+///
+/// This clones `keeper`'s contents over `dupe` in place, so `dupe` keeps its
+/// own inode, permissions, and so on. The kernel either does the whole clone
+/// or leaves `dupe` untouched, so this can't lose data if it fails.
+#[cfg(target_os = "linux")]
+fn reflink(keeper: &Path, dupe: &Path) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src = File::open(keeper)
+        .with_context(|| format!("unable to open: {}", keeper.display()))?;
+    let dest = std::fs::OpenOptions::new()
+        .write(true)
+        .open(dupe)
+        .with_context(|| format!("unable to open for writing: {}", dupe.display()))?;
+
+    // Safety: FICLONE takes a file descriptor as its argument and doesn't
+    // retain it, and both descriptors are open for the duration of the call.
+    let result = unsafe {
+        libc::ioctl(dest.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd())
+    };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        // Translate the errors people are actually likely to hit into
+        // something more helpful than "Invalid argument."
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::EINVAL) => {
+                bail!("filesystem doesn't support reflinks: {}", dupe.display());
+            }
+            Some(libc::EXDEV) => {
+                bail!("can't reflink across filesystems: {} and {}",
+                    keeper.display(), dupe.display());
+            }
+            _ => return Err(e).context("FICLONE failed"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_keeper: &Path, _dupe: &Path) -> anyhow::Result<()> {
+    bail!("reflinks are only supported on Linux");
+}
+
+/// Returns the device number of the filesystem containing `path`, if we can
+/// figure it out.
+fn device_of(path: &Path) -> Option<u64> {
+    let meta = std::fs::metadata(path).ok()?;
+    file_id(&meta).map(|(dev, _ino)| dev)
+}
+
+/// Replaces `dupe` with a hardlink to `keeper`.
+///
+/// To avoid losing `dupe` if something goes wrong partway through, this creates
+/// the new link under a temporary name next to `dupe`, and then renames it into
+/// place. The rename replaces `dupe` atomically, so at every point, there's a
+/// file at `dupe`'s path with the right contents.
+fn replace_with_link(keeper: &Path, dupe: &Path) -> anyhow::Result<()> {
+    let mut temp_name = dupe.file_name()
+        .with_context(|| format!("no filename in {}", dupe.display()))?
+        .to_owned();
+    temp_name.push(".drupes-link");
+    let temp = dupe.with_file_name(temp_name);
+
+    std::fs::hard_link(keeper, &temp)
+        .with_context(|| format!("unable to create link {}", temp.display()))?;
+    if let Err(e) = std::fs::rename(&temp, dupe) {
+        // Don't leave the extra link lying around; the original file is still
+        // intact, so this is all the cleanup we need.
+        std::fs::remove_file(&temp).ok();
+        return Err(e).with_context(|| format!("unable to replace {}",
+                dupe.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `args` as a command line, after the program name.
+    fn parse(args: &[&str]) -> Drupes {
+        Drupes::try_parse_from(std::iter::once("drupes").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn removal_follows_the_flag() {
+        assert_eq!(Removal::from_args(&parse(&["--delete"])), Some(Removal::Delete));
+        assert_eq!(Removal::from_args(&parse(&["--trash"])), Some(Removal::Trash));
+        assert_eq!(Removal::from_args(&parse(&["--link"])), None);
+        assert_eq!(Removal::Delete.verbs().0, "deleting");
+        assert_eq!(Removal::Trash.verbs().0, "trashing");
+
+        let dir = tempfile::tempdir().unwrap();
+        let f = dir.path().join("f");
+        std::fs::write(&f, "gone soon").unwrap();
+        Removal::Delete.remove(&f).unwrap();
+        assert!(!f.exists());
+    }
+
+    #[test]
+    fn remove_dupes_removes_all_but_the_first() {
+        let (a, b, c, d) = (Path::new("a"), Path::new("b"), Path::new("c"), Path::new("d"));
+        let groups: [&[&Path]; 2] = [&[a, b, c], &[d, a]];
+        let asked = RefCell::new(vec![]);
+        let removed = remove_dupes(&groups, "removing", "would remove", false, None, |f| {
+            asked.borrow_mut().push(f.to_owned());
+            // Pretend this one can't be removed.
+            if f == c { Err("nope") } else { Ok(()) }
+        }).unwrap();
+        assert_eq!(asked.into_inner(), [b, c, a]);
+        assert_eq!(removed, [b, a]);
+    }
+
+    /// Sets the modification time of `path` to `secs` after the epoch.
+    fn set_mtime(path: &Path, secs: u64) {
+        File::options().write(true).open(path).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn choose_keeper_follows_each_strategy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mid, old, new) = (dir.path().join("mid"), dir.path().join("sub/old"),
+            dir.path().join("new-long"));
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for (f, secs) in [(&mid, 2_000_000), (&old, 1_000_000), (&new, 3_000_000)] {
+            std::fs::write(f, "same").unwrap();
+            set_mtime(f, secs);
+        }
+        let files = [mid.as_path(), old.as_path(), new.as_path()];
+
+        let keeper = |keep| {
+            let mut files = files;
+            choose_keeper(&mut files, keep);
+            files
+        };
+        assert_eq!(keeper(Keep::Oldest), [&old, &mid, &new]);
+        assert_eq!(keeper(Keep::Newest), [&new, &mid, &old]);
+        assert_eq!(keeper(Keep::ShortestPath), [&mid, &old, &new]);
+        assert_eq!(keeper(Keep::LongestPath), [&new, &mid, &old]);
+    }
+
+    #[test]
+    fn progress_flag_parses() {
+        assert!(parse(&["--progress", "."]).progress);
+        assert!(!parse(&["."]).progress);
+    }
+
+    #[test]
+    fn last_of_hidden_and_exclude_hidden_wins() {
+        assert!(!parse(&["."]).hidden);
+        assert!(parse(&["--hidden", "."]).hidden);
+        assert!(!parse(&["--exclude-hidden", "."]).hidden);
+        assert!(parse(&["--exclude-hidden", "--hidden", "."]).hidden);
+        assert!(!parse(&["--hidden", "--exclude-hidden", "."]).hidden);
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote(Path::new("plain")), b"'plain'");
+        assert_eq!(shell_quote(Path::new("it's a file")), b"'it'\\''s a file'");
+        assert_eq!(shell_quote(Path::new("$HOME/`x` \"y\"")), b"'$HOME/`x` \"y\"'");
+        assert_eq!(shell_quote(Path::new("''")), b"''\\'''\\'''");
+    }
+
+    #[test]
+    fn write_script_removes_all_but_the_first() {
+        let first = [Path::new("keep me"), Path::new("dir/Bob's copy"), Path::new("plain")];
+        let second = [Path::new("a"), Path::new("b")];
+        let mut out = vec![];
+        write_script(&mut out, &[&first, &second]).unwrap();
+        let cwd = String::from_utf8(shell_quote(&std::env::current_dir().unwrap())).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("\
+            #!/bin/sh\n\
+            # Written by drupes, to delete all but one file in each set of duplicates.\n\
+            cd -- {cwd} || exit 1\n\
+            \n\
+            # set 1 of 2: keeping \"keep me\"\n\
+            rm -f -- 'dir/Bob'\\''s copy'\n\
+            rm -f -- 'plain'\n\
+            \n\
+            # set 2 of 2: keeping \"a\"\n\
+            rm -f -- 'b'\n"));
+    }
+
+    #[test]
+    fn delete_log_records_only_what_was_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        for name in ["a1", "a2", "a3", "b1", "b2", "c1", "c2"] {
+            std::fs::write(path(name), "copy").unwrap();
+        }
+        // One duplicate's already gone by the time we get to it.
+        std::fs::remove_file(path("a3")).unwrap();
+        let files: Vec<PathBuf> = ["a1", "a2", "a3", "b1", "b2", "c1", "c2"].map(path).into();
+        let files: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+        let groups: [&[&Path]; 3] = [&files[0..3], &files[3..5], &files[5..7]];
+        let log_path = path("deleted.jsonl");
+
+        // The run is interrupted just as it gets to `b2`.
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut log = DeleteLog::open(&log_path).unwrap();
+            remove_dupes(&groups, "deleting", "would delete", false, Some((&mut log, "deleted")),
+                |f| {
+                    if f.ends_with("b2") {
+                        panic!("interrupted");
+                    }
+                    std::fs::remove_file(f)
+                })
+        }));
+        assert!(interrupted.is_err());
+        let logged = || -> Vec<(String, String, String)> {
+            std::fs::read_to_string(&log_path).unwrap().lines()
+                .map(|line| {
+                    let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                    let field = |name: &str| entry[name].as_str().unwrap().to_owned();
+                    assert!(entry["time"].as_u64().unwrap() > 0);
+                    (field("action"), field("path"), field("keeper"))
+                })
+                .collect()
+        };
+        let entry = |name, keeper| ("deleted".to_owned(),
+            path(name).to_string_lossy().into_owned(), path(keeper).to_string_lossy().into_owned());
+        assert_eq!(logged(), [entry("a2", "a1")]);
+        assert!(path("b2").exists());
+
+        // Running again carries on from there, adding to the same log.
+        let mut log = DeleteLog::open(&log_path).unwrap();
+        let removed = remove_dupes(&groups[1..], "deleting", "would delete", false,
+            Some((&mut log, "deleted")), |f| std::fs::remove_file(f)).unwrap();
+        assert_eq!(removed, [files[4], files[6]]);
+        assert_eq!(logged(), [entry("a2", "a1"), entry("b2", "b1"), entry("c2", "c1")]);
+    }
+
+    #[test]
+    fn kept_copy_is_the_first_not_picked() {
+        let files = [Path::new("a"), Path::new("b"), Path::new("c")];
+        assert_eq!(kept_copy(&files, &[1, 2]), Some(Path::new("a")));
+        assert_eq!(kept_copy(&files, &[0]), Some(Path::new("b")));
+        assert_eq!(kept_copy(&files, &[0, 1]), Some(Path::new("c")));
+        assert_eq!(kept_copy(&files, &[0, 1, 2]), None);
+    }
+
+    /// What `confirm` makes of `input`, and what it printed.
+    fn answer(input: &str) -> (bool, String) {
+        let mut output = vec![];
+        let yes = confirm("about to delete 2 files; continue?", &mut input.as_bytes(), &mut output)
+            .unwrap();
+        (yes, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn confirm_goes_ahead_on_yes() {
+        let (yes, output) = answer("y\n");
+        assert!(yes);
+        assert_eq!(output, "about to delete 2 files; continue? [y/N] ");
+        for input in ["Y\n", "yes\n", "  yes  \n", "YES"] {
+            assert!(answer(input).0, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn confirm_stops_on_anything_else() {
+        for input in ["n\n", "no\n", "\n", "", "yep\n", "what?\ny\n"] {
+            assert!(!answer(input).0, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn yes_skips_confirming() {
+        let confirms = |args: &[&str]| confirms_deletion(&parse(args));
+        assert!(confirms(&["--delete", "."]));
+        assert!(!confirms(&["--delete", "--yes", "."]));
+        assert!(!confirms(&["--delete", "-y", "."]));
+        assert!(!confirms(&["--delete", "--dry-run", "."]));
+        assert!(!confirms(&["--trash", "."]));
+        // It only goes with `--delete`.
+        assert!(Drupes::try_parse_from(["drupes", "--yes", "."]).is_err());
+    }
+
+    #[test]
+    fn timing_flag_parses() {
+        assert!(parse(&["--timing", "."]).timing);
+        assert!(parse(&["--timing", "--summarize", "."]).timing);
+        assert!(!parse(&["."]).timing);
+    }
+
+    #[test]
+    fn text_normalize_only_takes_a_size_after_equals() {
+        let args = parse(&["--text-normalize", "."]);
+        assert_eq!(args.text_normalize, Some(1 << 20));
+        assert_eq!(args.roots, [PathBuf::from(".")]);
+        assert_eq!(parse(&["--text-normalize=2K", "."]).text_normalize, Some(2048));
+        assert_eq!(parse(&["."]).text_normalize, None);
+    }
+
+    #[test]
+    fn profile_flag_parses() {
+        assert!(parse(&["--profile", "."]).profile);
+        assert!(parse(&["--profile", "--json-lines", "."]).profile);
+        assert!(!parse(&["."]).profile);
+    }
+
+    #[test]
+    fn parse_time_takes_durations_and_dates() {
+        let epoch = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(parse_time("2024-01-31"), Ok(epoch(1706659200)));
+        assert_eq!(parse_time("2024-02-29T12:30"), Ok(epoch(1709209800)));
+        assert_eq!(parse_time("2024-02-29 12:30:15"), Ok(epoch(1709209815)));
+        assert_eq!(parse_time("1970-01-01"), Ok(epoch(0)));
+        assert!(parse_time("2023-02-29").is_err());
+        assert!(parse_time("2024-01-31T24:00").is_err());
+        assert!(parse_time("7y").is_err());
+
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("24h"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_duration("2w"), Some(Duration::from_secs(14 * 24 * 60 * 60)));
+        let week_ago = parse_time("7d").unwrap();
+        let elapsed = SystemTime::now().duration_since(week_ago).unwrap();
+        assert!(elapsed >= Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(elapsed < Duration::from_secs(7 * 24 * 60 * 60 + 60));
+    }
+
+    /// Runs `prompt_for_deletions` on three files, with `input` as what the
+    /// user types, and returns the choice and everything that was printed.
+    fn prompt(input: &str) -> (Choice, String) {
+        let files = [Path::new("a"), Path::new("b"), Path::new("c")];
+        let mut output = vec![];
+        let choice = prompt_for_deletions(&files, &mut input.as_bytes(), &mut output).unwrap();
+        (choice, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn prompt_takes_numbers() {
+        let (choice, output) = prompt("3, 2\n");
+        assert_eq!(choice, Choice::Delete(vec![1, 2]));
+        assert!(output.starts_with("  [1] a\n  [2] b\n  [3] c\ndelete which?"), "{output}");
+        assert_eq!(prompt("a\n").0, Choice::KeepAll);
+        assert_eq!(prompt("q\n").0, Choice::Quit);
+        // Running out of input is the same as quitting.
+        assert_eq!(prompt("").0, Choice::Quit);
+    }
+
+    #[test]
+    fn prompt_asks_again_until_it_makes_sense() {
+        let (choice, output) = prompt("4\nfoo\n\n1 2 3\n2 2\n");
+        assert_eq!(choice, Choice::Delete(vec![1]));
+        assert!(output.contains("not a number from 1 to 3: 4\n"), "{output}");
+        assert!(output.contains("not a number from 1 to 3: foo\n"), "{output}");
+        assert!(output.contains("please pick some files"), "{output}");
+        assert!(output.contains("refusing to delete every copy"), "{output}");
+        assert_eq!(output.matches("delete which?").count(), 5);
+    }
 }
@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Printing groups of duplicates, in the formats `drupes` offers, to anything
+//! that implements `Write`.
+//!
+//! In each group, the first path is taken to be the one that's kept, and the
+//! rest are its duplicates. Groups are written in the order they're given.
+//!
+//! For `--summarize`, see the `Display` impl on [`ScanStats`].
+
+use std::{io::Write, time::Duration};
+
+use serde::Serialize;
+
+use crate::{file_id, DuplicateGroup, HashAlgorithm, ScanStats};
+
+/// How [`write_groups`] should lay out its list of filenames.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ListOptions {
+    /// Leave out the first file in each group, so that every path printed is
+    /// a duplicate that could be removed.
+    pub omit_first: bool,
+    /// Print a comment line before each group, saying how many files it has
+    /// and how much space they take up.
+    pub headers: bool,
+    /// Don't separate groups from one another at all, so that the output is
+    /// nothing but paths.
+    pub quiet: bool,
+    /// End each path with a NUL byte, instead of a newline, and write paths
+    /// exactly as they're spelled, instead of converting them for display.
+    /// Groups are also separated by a NUL, and `headers` is ignored.
+    pub null: bool,
+    /// Print a comment line before each group whose files don't all have the
+    /// same extension (ignoring case), listing the extensions, since a copy
+    /// with a different extension is often there on purpose. Ignored if
+    /// `null` is set.
+    pub note_mixed_extensions: bool,
+    /// Follow each path that's a hardlink to the first file in its group with
+    /// `(hardlink)`, since removing it won't reclaim any space. The paths are
+    /// looked up as they're given, so they have to lead to the files. Ignored
+    /// if `null` is set.
+    pub annotate_hardlinks: bool,
+}
+
+/// Writes the files in each group, one per line, with a blank line after each
+/// group (unless `omit_first` or `quiet` is set). This is `drupes`'s normal
+/// output.
+///
+/// A group of empty files always starts with a comment line saying so, unless
+/// `omit_first`, `quiet`, or `null` is set, whether or not `headers` is.
+pub fn write_groups(
+    w: &mut impl Write,
+    groups: &[DuplicateGroup],
+    opts: &ListOptions,
+) -> std::io::Result<()> {
+    let skip = usize::from(opts.omit_first);
+    let separate = !opts.omit_first && !opts.quiet;
+    for (i, group) in groups.iter().enumerate() {
+        if opts.null {
+            for f in &group.paths[skip..] {
+                w.write_all(f.as_os_str().as_encoded_bytes())?;
+                w.write_all(b"\0")?;
+            }
+            if separate {
+                w.write_all(b"\0")?;
+            }
+            continue;
+        }
+
+        if group.size == 0 && separate {
+            // This one is labelled even without headers, since it's rarely
+            // what anyone means by duplicates.
+            let number = if opts.headers { format!("group {}: ", i + 1) } else { String::new() };
+            writeln!(w, "# {number}{} empty files, which only match because \
+                they're empty", group.paths.len())?;
+        } else if opts.headers {
+            let dupes = group.paths.len() - 1;
+            writeln!(w, "# group {}: {} files (1 kept, {dupes} duplicate{}), \
+                {} each, {} reclaimable",
+                i + 1,
+                group.paths.len(),
+                if dupes == 1 { "" } else { "s" },
+                size::Size::from_bytes(group.size),
+                size::Size::from_bytes(group.size * dupes as u64))?;
+        }
+        if opts.note_mixed_extensions {
+            let extensions = extensions(group);
+            if extensions.len() > 1 {
+                writeln!(w, "# mixed extensions: {}", extensions.join(", "))?;
+            }
+        }
+        let keeper_id = group.paths.first()
+            .filter(|_| opts.annotate_hardlinks)
+            .and_then(|f| file_id(&std::fs::metadata(f).ok()?));
+        for (i, f) in group.paths.iter().enumerate().skip(skip) {
+            let is_hardlink = i > 0 && keeper_id.is_some()
+                && std::fs::metadata(f).ok().as_ref().and_then(file_id) == keeper_id;
+            if is_hardlink {
+                writeln!(w, "{} (hardlink)", f.display())?;
+            } else {
+                writeln!(w, "{}", f.display())?;
+            }
+        }
+        if separate {
+            writeln!(w)?;
+        }
+    }
+    w.flush()
+}
+
+/// The different extensions of the files in `group`, lowercased and sorted,
+/// for [`ListOptions::note_mixed_extensions`].
+fn extensions(group: &DuplicateGroup) -> Vec<String> {
+    let mut extensions: Vec<String> = group.paths.iter()
+        .map(|f| match f.extension() {
+            Some(ext) => ext.to_string_lossy().to_lowercase(),
+            None => "(none)".to_string(),
+        })
+        .collect();
+    extensions.sort();
+    extensions.dedup();
+    extensions
+}
+
+/// Paths in a [`write_table`] are never cut down to fewer characters than
+/// this, however narrow the table has to be.
+const MIN_PATH_WIDTH: usize = 16;
+
+/// Writes the groups as a table, one row per group, with columns for the
+/// number of copies, the size of each, the space that removing all but one
+/// would reclaim, and the path of the one that's kept. Sizes are in
+/// human-readable units.
+///
+/// The table is kept within `width` characters by shortening long paths from
+/// the front, keeping the end, which tends to be the interesting part. If
+/// `expand` is set, the rest of the files in each group are listed under the
+/// path that's kept.
+pub fn write_table(
+    w: &mut impl Write,
+    groups: &[DuplicateGroup],
+    width: usize,
+    expand: bool,
+) -> std::io::Result<()> {
+    const HEADERS: [&str; 4] = ["#", "copies", "size", "reclaimable"];
+    let rows: Vec<[String; 4]> = groups.iter().enumerate()
+        .map(|(i, group)| {
+            let dupes = group.paths.len() as u64 - 1;
+            [
+                (i + 1).to_string(),
+                group.paths.len().to_string(),
+                size::Size::from_bytes(group.size).to_string(),
+                size::Size::from_bytes(group.size * dupes).to_string(),
+            ]
+        })
+        .collect();
+    let mut widths = HEADERS.map(|header| header.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    // Each column is followed by two spaces, and then the path gets whatever
+    // room is left.
+    let indent: usize = widths.iter().map(|width| width + 2).sum();
+    let path_width = width.saturating_sub(indent).max(MIN_PATH_WIDTH);
+
+    for (header, width) in HEADERS.iter().zip(widths) {
+        write!(w, "{header:>width$}  ")?;
+    }
+    writeln!(w, "keeper")?;
+    for (row, group) in rows.iter().zip(groups) {
+        for (cell, width) in row.iter().zip(widths) {
+            write!(w, "{cell:>width$}  ")?;
+        }
+        writeln!(w, "{}", fit(&group.paths[0].display().to_string(), path_width))?;
+        if expand {
+            for f in &group.paths[1..] {
+                writeln!(w, "{:indent$}{}", "", fit(&f.display().to_string(), path_width))?;
+            }
+        }
+    }
+    w.flush()
+}
+
+/// Shortens `path` to at most `width` characters for [`write_table`], by
+/// replacing the start of it with an ellipsis if need be.
+fn fit(path: &str, width: usize) -> std::borrow::Cow<'_, str> {
+    let len = path.chars().count();
+    if len <= width {
+        return path.into();
+    }
+    let tail: String = path.chars().skip(len - width + 1).collect();
+    format!("…{tail}").into()
+}
+
+/// A group of duplicate files, in the form we print for `--json` and
+/// `--json-lines`.
+#[derive(Serialize)]
+struct JsonGroup<'a> {
+    /// Name of the algorithm used for `hash`.
+    algorithm: String,
+    /// Hex hash of the contents of each file.
+    hash: String,
+    /// Size of each file in bytes.
+    size: u64,
+    paths: Vec<std::borrow::Cow<'a, str>>,
+}
+
+impl<'a> JsonGroup<'a> {
+    fn new(group: &'a DuplicateGroup, algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm: algorithm.to_string(),
+            hash: group.hash.to_hex(),
+            size: group.size,
+            paths: group.paths.iter().map(|f| f.to_string_lossy()).collect(),
+        }
+    }
+}
+
+/// Writes the groups as a pretty-printed JSON array, followed by a newline.
+/// `algorithm` is the one that produced the groups' hashes.
+pub fn write_json(
+    w: &mut impl Write,
+    groups: &[DuplicateGroup],
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<()> {
+    let groups: Vec<JsonGroup> = groups.iter()
+        .map(|group| JsonGroup::new(group, algorithm))
+        .collect();
+
+    serde_json::to_writer_pretty(&mut *w, &groups)?;
+    writeln!(w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes each group as a JSON object on a line of its own, in the same form
+/// as [`write_json`], so that they can be read back one at a time.
+pub fn write_json_lines(
+    w: &mut impl Write,
+    groups: &[DuplicateGroup],
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<()> {
+    for group in groups {
+        serde_json::to_writer(&mut *w, &JsonGroup::new(group, algorithm))?;
+        writeln!(w)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// One duplicate file, in the form we print for `--csv`.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    /// Hex hash of the file's contents, shared by its group.
+    hash: &'a str,
+    /// Size of the file in bytes.
+    size: u64,
+    path: std::borrow::Cow<'a, str>,
+    /// Whether this is the file in its group that would be kept.
+    is_keeper: bool,
+}
+
+/// Writes the groups as CSV, with a header row and then one row per file.
+/// Within each group, the rows are sorted by path, with the file that's kept
+/// marked in the `is_keeper` column.
+pub fn write_csv(w: &mut impl Write, groups: &[DuplicateGroup]) -> anyhow::Result<()> {
+    let mut out = csv::Writer::from_writer(w);
+    for group in groups {
+        let hash = group.hash.to_hex();
+        let keeper = &group.paths[0];
+        let mut files: Vec<_> = group.paths.iter().collect();
+        files.sort();
+        for f in files {
+            out.serialize(CsvRow {
+                hash: &hash,
+                size: group.size,
+                path: f.to_string_lossy(),
+                is_keeper: f == keeper,
+            })?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// A [`ScanStats`], in the form we print for `--stats-json`.
+#[derive(Serialize)]
+struct StatsJson<'a> {
+    #[serde(flatten)]
+    stats: &'a ScanStats,
+    /// How long the whole run took, in milliseconds.
+    elapsed_ms: u64,
+    /// How many bytes were read from files, if `stats.timing` says.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_read: Option<u64>,
+}
+
+/// Writes `stats` as a JSON object, on one line, with sizes in bytes. The
+/// fields are those of `ScanStats`, plus `elapsed_ms`, which is `elapsed` in
+/// milliseconds, and `bytes_read`, if [`ScanStats::timing`] is filled in.
+pub fn write_stats_json(
+    w: &mut impl Write,
+    stats: &ScanStats,
+    elapsed: Duration,
+) -> anyhow::Result<()> {
+    let elapsed_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+    let bytes_read = stats.timing.map(|(_, bytes_read)| bytes_read);
+    serde_json::to_writer(&mut *w, &StatsJson { stats, elapsed_ms, bytes_read })?;
+    writeln!(w)?;
+    w.flush()?;
+    Ok(())
+}
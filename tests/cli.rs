@@ -0,0 +1,1222 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests that run the `drupes` binary itself.
+
+mod common;
+
+use common::{run, tree};
+
+#[test]
+fn json_reads_back_as_groups() {
+    let dir = tree(&[
+        ("a/one", b"first contents"),
+        ("b/one", b"first contents"),
+        ("c/one", b"first contents"),
+        ("a/two", b"second, longer contents"),
+        ("b/two", b"second, longer contents"),
+        ("unique", b"nothing like it"),
+    ]);
+    let out = run(dir.path(), &["--json", "."]);
+    let groups: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let groups = groups.as_array().unwrap();
+    assert_eq!(groups.len(), 2);
+
+    let mut found = vec![];
+    for group in groups {
+        assert_eq!(group["algorithm"], "blake3");
+        assert_eq!(group["hash"].as_str().unwrap().len(), 64);
+        let paths: Vec<&str> = group["paths"].as_array().unwrap().iter()
+            .map(|path| path.as_str().unwrap())
+            .collect();
+        found.push((group["size"].as_u64().unwrap(), paths));
+    }
+    found.sort();
+    assert_eq!(found, [
+        (14, vec!["./a/one", "./b/one", "./c/one"]),
+        (23, vec!["./a/two", "./b/two"]),
+    ]);
+}
+
+#[test]
+fn roots_from_adds_to_a_positional_root() {
+    let dir = tree(&[
+        ("a/x", b"in a and b"),
+        ("b/x", b"in a and b"),
+        ("c/y", b"in b and c"),
+        ("b/y", b"in b and c"),
+        ("d/z", b"only searched if d is"),
+        ("a/z", b"only searched if d is"),
+        ("roots.txt", b"a\nc\n"),
+    ]);
+    let out = run(dir.path(), &["--roots-from", "roots.txt", "b"]);
+    assert_eq!(out, "a/x\nb/x\n\nb/y\nc/y\n\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn link_leaves_duplicates_sharing_an_inode() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tree(&[("a", b"linked up"), ("b", b"linked up"), ("c/d", b"linked up")]);
+    let out = run(dir.path(), &["--link", "."]);
+    assert_eq!(out.lines().filter(|line| line.starts_with("linking: ")).count(), 2);
+
+    let ino = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().ino();
+    assert_eq!(ino("a"), ino("b"));
+    assert_eq!(ino("a"), ino("c/d"));
+    assert_eq!(std::fs::read(dir.path().join("c/d")).unwrap(), b"linked up");
+    // Nothing was left behind from the swap.
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn link_skips_what_is_already_linked() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tree(&[("a", b"linked up"), ("c", b"linked up")]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let out = run(dir.path(), &["--keep-hardlinks", "--link", "."]);
+    // Only the real copy needed linking.
+    assert_eq!(out.lines().filter(|line| line.starts_with("linking: ")).collect::<Vec<_>>(),
+        ["linking: ./c => ./a"]);
+    let mut left: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    left.sort();
+    assert_eq!(left, ["a", "b", "c"]);
+    let ino = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap().ino();
+    assert!(ino("a") == ino("b") && ino("a") == ino("c"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reflink_keeps_contents_and_separate_files() {
+    use std::os::unix::fs::MetadataExt;
+
+    let contents = vec![7; 64 * 1024];
+    let dir = tree(&[("a", &contents), ("b", &contents)]);
+    let out = common::drupes(dir.path(), &["--reflink", "."]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.success(), "{stderr}");
+    // Whether or not it worked, both files read back the same as before.
+    assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), contents);
+    assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), contents);
+    if stderr.contains("doesn't support reflinks") {
+        eprintln!("skipping the rest: the temporary directory's filesystem can't reflink");
+        return;
+    }
+    assert!(!stderr.contains("error reflinking"), "{stderr}");
+
+    let meta = |name: &str| std::fs::metadata(dir.path().join(name)).unwrap();
+    assert_ne!(meta("a").ino(), meta("b").ino());
+}
+
+#[test]
+fn delete_dry_run_leaves_everything_in_place() {
+    let dir = tree(&[("a", b"dupe"), ("b", b"dupe"), ("sub/c", b"dupe"), ("d", b"other")]);
+    let out = run(dir.path(), &["--delete", "--dry-run", "."]);
+    assert!(out.contains("would delete: ./b\n"), "{out}");
+    assert!(out.contains("would delete: ./sub/c\n"), "{out}");
+    assert!(!out.contains("deleting:"), "{out}");
+    for name in ["a", "b", "sub/c", "d"] {
+        assert!(dir.path().join(name).exists(), "{name} is gone");
+    }
+}
+
+#[test]
+fn prefer_dir_keeps_the_copy_under_it() {
+    let dir = tree(&[
+        ("archive/photo.jpg", b"pixels"),
+        ("incoming/photo.jpg", b"pixels"),
+        ("incoming/nested/photo.jpg", b"pixels"),
+    ]);
+    let out = run(dir.path(), &["--prefer-dir", "incoming/nested", "archive", "incoming"]);
+    assert_eq!(out, "incoming/nested/photo.jpg\narchive/photo.jpg\nincoming/photo.jpg\n\n");
+
+    run(dir.path(), &["--prefer-dir", "incoming/nested", "--delete", "--yes",
+        "archive", "incoming"]);
+    assert!(dir.path().join("incoming/nested/photo.jpg").exists());
+    assert!(!dir.path().join("archive/photo.jpg").exists());
+    assert!(!dir.path().join("incoming/photo.jpg").exists());
+}
+
+#[test]
+fn progress_is_silent_without_a_terminal() {
+    let dir = tree(&[("a", b"dupe"), ("b", b"dupe")]);
+    let out = common::drupes(dir.path(), &["--progress", "."]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"./a\n./b\n\n");
+    // No bars, and none of the escape codes that draw and clear them.
+    assert!(!out.stderr.iter().any(|&b| b == b'\x1b' || b == b'\r'),
+        "{:?}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn csv_has_one_keeper_per_group() {
+    let dir = tree(&[
+        ("a/one", b"first"), ("b/one", b"first"), ("c/one", b"first"),
+        ("a/two", b"second"), ("b/two", b"second"),
+        ("alone", b"third, alone"),
+    ]);
+    let out = run(dir.path(), &["--csv", "--keep", "longest-path", "."]);
+    let mut reader = csv::Reader::from_reader(out.as_bytes());
+    assert_eq!(reader.headers().unwrap(), vec!["hash", "size", "path", "is_keeper"]);
+
+    let mut groups: std::collections::BTreeMap<String, Vec<(String, bool)>> = Default::default();
+    for row in reader.records() {
+        let row = row.unwrap();
+        assert_eq!(row[0].len(), 64);
+        let size: u64 = row[1].parse().unwrap();
+        assert!(size == 5 || size == 6);
+        groups.entry(row[0].to_string()).or_default()
+            .push((row[2].to_string(), row[3].parse().unwrap()));
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    groups.sort();
+    assert_eq!(groups.len(), 2);
+    for group in &groups {
+        assert_eq!(group.iter().filter(|(_, keeper)| *keeper).count(), 1, "{group:?}");
+    }
+    let paths: Vec<Vec<&str>> = groups.iter()
+        .map(|group| group.iter().map(|(path, _)| path.as_str()).collect())
+        .collect();
+    assert_eq!(paths, [vec!["./a/one", "./b/one", "./c/one"], vec!["./a/two", "./b/two"]]);
+}
+
+#[test]
+fn sort_size_puts_the_most_reclaimable_first() {
+    let dir = tree(&[
+        // 10 bytes reclaimable.
+        ("ten/a", b"0123456789"), ("ten/b", b"0123456789"),
+        // 12 bytes, from more, smaller files.
+        ("three/a", b"abc"), ("three/b", b"abc"), ("three/c", b"abc"), ("three/d", b"abc"),
+        ("three/e", b"abc"),
+        // 20 bytes.
+        ("twenty/a", b"01234567890123456789"), ("twenty/b", b"01234567890123456789"),
+    ]);
+    let out = run(dir.path(), &["--sort", "size", "."]);
+    let firsts: Vec<&str> = common::groups(&out).iter().map(|group| group[0]).collect();
+    assert_eq!(firsts, ["./twenty/a", "./three/a", "./ten/a"]);
+}
+
+#[test]
+fn limit_shows_only_that_many_groups() {
+    let mut files = vec![];
+    for i in 0..5 {
+        let contents = format!("contents of set {i}").into_bytes();
+        files.push((format!("{i}/a"), contents.clone()));
+        files.push((format!("{i}/b"), contents));
+    }
+    let files: Vec<(&str, &[u8])> = files.iter()
+        .map(|(name, contents)| (name.as_str(), contents.as_slice()))
+        .collect();
+    let dir = tree(&files);
+
+    let out = run(dir.path(), &["--limit", "2", "."]);
+    assert_eq!(common::groups(&out), [["./0/a", "./0/b"], ["./1/a", "./1/b"]]);
+    let out = run(dir.path(), &["--limit", "2", "--json-lines", "."]);
+    assert_eq!(out.lines().count(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn null_output_survives_a_newline_in_a_name() {
+    let dir = tree(&[("plain", b"same"), ("two\nlines", b"same"), ("other1", b"else"),
+        ("other2", b"else")]);
+    let out = common::drupes(dir.path(), &["-0", "--sort", "path", "."]).output().unwrap();
+    assert!(out.status.success());
+    let groups: Vec<Vec<&[u8]>> = out.stdout.split(|&b| b == 0)
+        .collect::<Vec<_>>()
+        .split(|part| part.is_empty())
+        .filter(|group| !group.is_empty())
+        .map(<[&[u8]]>::to_vec)
+        .collect();
+    assert_eq!(groups, [
+        vec![&b"./other1"[..], b"./other2"],
+        vec![&b"./plain"[..], b"./two\nlines"],
+    ]);
+}
+
+#[test]
+fn cross_root_only_drops_groups_within_one_root() {
+    let dir = tree(&[
+        ("a/x", b"only in a"), ("a/y", b"only in a"),
+        ("a/z", b"in both"), ("b/z", b"in both"),
+        ("b/p", b"a and b, twice"), ("b/q", b"a and b, twice"), ("a/p", b"a and b, twice"),
+    ]);
+    let out = run(dir.path(), &["a", "b"]);
+    assert_eq!(common::groups(&out).len(), 3);
+    let out = run(dir.path(), &["--cross-root-only", "a", "b"]);
+    assert_eq!(common::groups(&out), [vec!["a/p", "b/p", "b/q"], vec!["a/z", "b/z"]]);
+}
+
+#[test]
+fn unique_to_with_no_copies_elsewhere() {
+    let dir = tree(&[("a/x", b"ex"), ("a/y", b"why"), ("a/y2", b"why"), ("b/z", b"zed")]);
+    // A copy under the same root doesn't stop a file being unique to it.
+    let out = run(dir.path(), &["--unique-to", "a", "a", "b"]);
+    assert_eq!(out, "a/x\na/y\na/y2\n");
+}
+
+#[test]
+fn unique_to_counts_copies_and_hardlinks_elsewhere() {
+    let dir = tree(&[("a/x", b"linked"), ("a/y", b"copied"), ("b/y", b"copied"),
+        ("a/z", b"only in a"), ("b/w", b"only in b")]);
+    std::fs::hard_link(dir.path().join("a/x"), dir.path().join("b/x")).unwrap();
+    let out = run(dir.path(), &["--unique-to", "a", "a", "b"]);
+    assert_eq!(out, "a/z\n");
+    let out = run(dir.path(), &["--unique-to", "b", "a", "b"]);
+    assert_eq!(out, "b/w\n");
+}
+
+#[test]
+fn checksum_output_matches_other_tools() {
+    use sha2::Digest as _;
+
+    let dir = tree(&[("a", b"first file"), ("sub/b", b"second file"), ("sub/c", b"first file")]);
+    let out = run(dir.path(), &["--checksum-output", "."]);
+    let expected: String = [("./a", &b"first file"[..]), ("./sub/b", b"second file"),
+        ("./sub/c", b"first file")].iter()
+        .map(|(path, contents)| format!("{}  {path}\n", blake3::hash(contents).to_hex()))
+        .collect();
+    assert_eq!(out, expected);
+
+    let out = run(dir.path(), &["--checksum-output", "--hash", "sha256", "sub"]);
+    let sha256 = |contents: &[u8]| {
+        sha2::Sha256::digest(contents).iter().map(|b| format!("{b:02x}")).collect::<String>()
+    };
+    assert_eq!(out, format!("{}  sub/b\n{}  sub/c\n", sha256(b"second file"),
+        sha256(b"first file")));
+}
+
+#[test]
+fn checksum_output_lists_hardlinks_relative_to() {
+    let dir = tree(&[("data/a", b"linked")]);
+    std::fs::hard_link(dir.path().join("data/a"), dir.path().join("data/b")).unwrap();
+    let out = run(dir.path(), &["--checksum-output", "--relative-to", "data", "data"]);
+    let hash = blake3::hash(b"linked").to_hex();
+    assert_eq!(out, format!("{hash}  a\n{hash}  b\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_file_is_counted() {
+    let dir = tree(&[("a", b"same size"), ("b", b"same size"), ("c", b"same size")]);
+    let locked = dir.path().join("c");
+    if !common::lock(&locked) {
+        common::unlock(&locked, 0o644);
+        eprintln!("skipping: permissions don't keep us out (running as root?)");
+        return;
+    }
+    let summary = common::drupes(dir.path(), &["--summarize", "."]).output().unwrap();
+    let list = common::drupes(dir.path(), &["."]).output().unwrap();
+    common::unlock(&locked, 0o644);
+
+    let summary = String::from_utf8(summary.stdout).unwrap();
+    assert!(summary.contains("\n1 files could not be read and were excluded"), "{summary}");
+    assert_eq!(list.stdout, b"./a\n./b\n\n");
+    let stderr = String::from_utf8(list.stderr).unwrap();
+    assert!(stderr.contains("warning: 1 files could not be read and were excluded"), "{stderr}");
+}
+
+#[test]
+fn mtime_window_leaves_out_files_outside_it() {
+    let dir = tree(&[("old1", b"same"), ("old2", b"same"), ("mid1", b"same"),
+        ("mid2", b"same"), ("new1", b"same"), ("new2", b"same")]);
+    // 2020-01-01, 2022-01-01, and 2024-01-01, all at midnight UTC.
+    for (name, secs) in [("old1", 1577836800), ("old2", 1577836800), ("mid1", 1640995200),
+        ("mid2", 1640995200), ("new1", 1704067200), ("new2", 1704067200)] {
+        common::set_mtime(&dir.path().join(name), secs);
+    }
+    let out = run(dir.path(), &["--newer-than", "2021-06-01", "--older-than", "2023-06-01", "."]);
+    assert_eq!(out, "./mid1\n./mid2\n\n");
+    // Both ends of the window are included.
+    let out = run(dir.path(), &["--sort", "path", "--newer-than", "2022-01-01T00:00",
+        "--older-than", "2024-01-01 00:00:00", "."]);
+    assert_eq!(out, "./mid1\n./mid2\n./new1\n./new2\n\n");
+    let out = run(dir.path(), &["--older-than", "2021-06-01", "."]);
+    assert_eq!(out, "./old1\n./old2\n\n");
+    // Every file here is much older than a week.
+    let out = run(dir.path(), &["--newer-than", "7d", "."]);
+    assert_eq!(out, "");
+}
+
+#[test]
+fn output_file_holds_exactly_the_report() {
+    let dir = tree(&[("a", b"same"), ("b", b"same"), ("c", b"different")]);
+    let elsewhere = tempfile::tempdir().unwrap();
+    let report = elsewhere.path().join("report.txt");
+    let report_arg = report.to_str().unwrap();
+
+    let out = run(dir.path(), &["--output", report_arg, "."]);
+    assert_eq!(out, "");
+    assert_eq!(std::fs::read_to_string(&report).unwrap(), "./a\n./b\n\n");
+
+    // It won't replace the report without --force, and leaves it alone.
+    let out = common::drupes(dir.path(), &["--output", report_arg, "--json-lines", "."])
+        .output().unwrap();
+    assert!(!out.status.success());
+    assert_eq!(std::fs::read_to_string(&report).unwrap(), "./a\n./b\n\n");
+
+    run(dir.path(), &["--output", report_arg, "--force", "--csv", "."]);
+    let csv = std::fs::read_to_string(&report).unwrap();
+    assert!(csv.starts_with("hash,size,path,is_keeper\n"), "{csv}");
+    assert_eq!(csv.lines().count(), 3);
+    // Nothing's left behind under a temporary name.
+    assert_eq!(std::fs::read_dir(elsewhere.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn quiet_output_is_only_paths() {
+    let dir = tree(&[("a1", b"first"), ("a2", b"first"), ("a3", b"first"),
+        ("b1", b"second one"), ("b2", b"second one"), ("c", b"unique")]);
+    let out = run(dir.path(), &["--quiet", "--sort", "path", "."]);
+    assert_eq!(out, "./a1\n./a2\n./a3\n./b1\n./b2\n");
+    assert!(!out.lines().any(str::is_empty));
+
+    let out = run(dir.path(), &["--quiet", "--omit-first", "--sort", "path", "."]);
+    assert_eq!(out, "./a2\n./a3\n./b2\n");
+
+    // It doesn't change JSON, but does say so.
+    let out = common::drupes(dir.path(), &["--quiet", "--json-lines", "."]).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap().lines().count(), 2);
+    assert!(String::from_utf8(out.stderr).unwrap().contains("warning: --quiet"));
+}
+
+#[test]
+fn output_is_the_same_every_run() {
+    let mut files = vec![];
+    for i in 0..20 {
+        files.push((format!("a/{i}"), format!("contents {}", i % 7)));
+        files.push((format!("b/{}/copy", 19 - i), format!("contents {}", i % 7)));
+    }
+    let files: Vec<(&str, &[u8])> = files.iter()
+        .map(|(name, contents)| (name.as_str(), contents.as_bytes()))
+        .collect();
+    let dir = tree(&files);
+    // Plain `--json-lines` is written as the groups are found, so it's only
+    // in a fixed order if it's sorted.
+    for mode in [&[][..], &["--json"], &["--json-lines", "--sort", "path"], &["--csv"],
+        &["--null"], &["--omit-first"], &["--headers"], &["--table"]]
+    {
+        let args = [mode, &["."]].concat();
+        let first = run(dir.path(), &args);
+        assert!(!first.is_empty());
+        for _ in 0..3 {
+            assert_eq!(run(dir.path(), &args), first, "{mode:?} changed between runs");
+        }
+    }
+}
+
+#[test]
+fn verbose_counts_the_bytes_read() {
+    let mut c = vec![0; 8192];
+    c[0] = b'x';
+    let dir = tree(&[("a", &[0; 8192]), ("b", &[0; 8192]), ("c", &c), ("d", &[0; 1024])]);
+    // `d` is never read, since it's the only file its size. The others have
+    // their first 4 KiB read to prehash them, and then `a` and `b` have the
+    // other 4 KiB read, since their prehashes match: 20 KiB in all.
+    let read = |args: &[&str]| {
+        let out = common::drupes(dir.path(), args).output().unwrap();
+        assert!(out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let line = stderr.lines().find(|line| line.contains(" read ")).unwrap();
+        line.split_once(" read ").unwrap().1.to_string()
+    };
+    assert_eq!(read(&["--verbose", "."]), "20.0 KiB of 25.0 KiB scanned (80%)");
+    // Checking `a` and `b` byte by byte reads all of both again.
+    assert_eq!(read(&["--verbose", "--paranoid", "."]), "36.0 KiB of 25.0 KiB scanned (144%)");
+    // Without a prehash, `c` can't be ruled out early, so it's the whole of
+    // all three.
+    assert_eq!(read(&["--verbose", "--no-prehash", "."]), "24.0 KiB of 25.0 KiB scanned (96%)");
+}
+
+#[test]
+fn count_empty_counts_empty_files_without_listing_them() {
+    let dir = tree(&[("e1", b""), ("e2", b""), ("sub/e3", b""), ("a", b"same"), ("b", b"same")]);
+    let out = run(dir.path(), &["--summarize", "."]);
+    assert!(out.contains("\nchecked 2 files in 1 size classes\n"), "{out}");
+    let out = run(dir.path(), &["--summarize", "--count-empty", "."]);
+    assert!(out.starts_with("1 duplicate files (in 1 sets), occupying 4 bytes\n"), "{out}");
+    assert!(out.contains("\nchecked 5 files in 2 size classes\n"), "{out}");
+
+    assert_eq!(run(dir.path(), &["--count-empty", "."]), "./a\n./b\n\n");
+    let out = run(dir.path(), &["--count-empty", "--delete", "--dry-run", "."]);
+    assert_eq!(out.matches("would delete:").count(), 1, "{out}");
+    assert!(out.contains("would delete: ./b\n"), "{out}");
+    // Whereas --empty lists them as duplicates of one another.
+    assert_eq!(common::groups(&run(dir.path(), &["--empty", "--sort", "path", "."])).len(), 2);
+}
+
+#[test]
+fn json_lines_parse_one_at_a_time() {
+    let mut files = vec![("unique".to_string(), "nothing like it".to_string())];
+    for i in 0..30 {
+        for copy in 0..(2 + i % 3) {
+            files.push((format!("{copy}/{i}"), format!("contents {i}")));
+        }
+    }
+    let files: Vec<(&str, &[u8])> = files.iter()
+        .map(|(name, contents)| (name.as_str(), contents.as_bytes()))
+        .collect();
+    let dir = tree(&files);
+    for args in [&["--json-lines", "."][..], &["--json-lines", "--paranoid", "."],
+        &["--json-lines", "--sort", "size", "."]]
+    {
+        let out = run(dir.path(), args);
+        let mut found: Vec<(usize, Vec<String>)> = out.lines()
+            .map(|line| {
+                let group: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(group["algorithm"], "blake3");
+                assert_eq!(group["hash"].as_str().unwrap().len(), 64);
+                let paths: Vec<String> = group["paths"].as_array().unwrap().iter()
+                    .map(|path| path.as_str().unwrap().to_string())
+                    .collect();
+                assert_eq!(group["size"].as_u64(), Some(std::fs::metadata(
+                    dir.path().join(&paths[0])).unwrap().len()));
+                let i = paths[0].rsplit('/').next().unwrap().parse().unwrap();
+                (i, paths)
+            })
+            .collect();
+        found.sort();
+        let expected: Vec<(usize, Vec<String>)> = (0..30)
+            .map(|i| (i, (0..(2 + i % 3)).map(|copy| format!("./{copy}/{i}")).collect()))
+            .collect();
+        assert_eq!(found, expected, "{args:?}");
+    }
+}
+
+#[test]
+fn json_lines_keeps_options_that_act_on_each_group() {
+    let dir = tree(&[("a/x", b"same"), ("b/x", b"same"), ("b/y", b"same"), ("b/z", b"other"),
+        ("b/w", b"other"), ("c/p", b"three of these"), ("c/q", b"three of these"),
+        ("c/r", b"three of these")]);
+    let out = run(dir.path(), &["--json-lines", "--cross-root-only", "--prefer-dir", "b",
+        "--relative-to", "a", "a", "b", "c"]);
+    let group: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+    assert_eq!(out.lines().count(), 1);
+    assert_eq!(group["paths"], serde_json::json!(["b/x", "b/y", "x"]));
+
+    let out = run(dir.path(), &["--json-lines", "--min-group-size", "3", "."]);
+    assert_eq!(out.lines().count(), 2);
+}
+
+#[test]
+fn files_from_looks_only_at_the_listed_files() {
+    let dir = tree(&[("a/1", b"same"), ("a/2", b"same"), ("a/sub/3", b"same"),
+        ("b/1", b"also same"), ("b/2", b"also same"), ("unlisted", b"also same")]);
+    std::fs::write(dir.path().join("list"), "a/1\na/2\nb\nmissing\nb/1\n").unwrap();
+    let out = common::drupes(dir.path(), &["--files-from", "list"]).output().unwrap();
+    assert!(out.status.success());
+    // The copies in `a/sub` and at the top aren't listed, and the listed
+    // directory isn't searched.
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "a/1\na/2\n\n");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("warning: skipping: not a file: b\n"), "{stderr}");
+    assert!(stderr.contains("warning: skipping: problem getting metadata for missing"),
+        "{stderr}");
+
+    // The list can come from standard input too.
+    let mut child = common::drupes(dir.path(), &["--files-from", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn().unwrap();
+    use std::io::Write as _;
+    child.stdin.take().unwrap().write_all(b"b/1\nunlisted\na/sub/3\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "b/1\nunlisted\n\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn print0_groups_can_be_read_back_whatever_the_names() {
+    let dir = tree(&[("a\nb", b"first"), ("a", b"first"), ("b\n", b"first"),
+        ("plain", b"second"), ("\nleading", b"second"), ("x", b"unique")]);
+    let out = common::drupes(dir.path(), &["--print0-groups", "--sort", "path", "."])
+        .output().unwrap();
+    assert!(out.status.success());
+
+    // Each path ends with a NUL, and each group with another.
+    let mut groups: Vec<Vec<&[u8]>> = vec![];
+    let mut group = vec![];
+    let mut rest = &out.stdout[..];
+    while !rest.is_empty() {
+        let end = rest.iter().position(|&b| b == 0).expect("path without a NUL after it");
+        group.push(&rest[..end]);
+        rest = &rest[end + 1..];
+        if rest.first() == Some(&0) {
+            groups.push(std::mem::take(&mut group));
+            rest = &rest[1..];
+        }
+    }
+    assert!(group.is_empty(), "last group isn't ended");
+    assert_eq!(groups, [
+        vec![&b"./\nleading"[..], b"./plain"],
+        vec![&b"./a"[..], b"./a\nb", b"./b\n"],
+    ]);
+}
+
+#[test]
+fn exclude_from_works_from_the_current_directory() {
+    let dir = tree(&[("a.txt", b"same"), ("b.txt", b"same"), ("c.log", b"same"),
+        ("build/d.txt", b"same"), ("keep.log", b"same")]);
+    let lists = tempfile::tempdir().unwrap();
+    let list = lists.path().join("excludes");
+    std::fs::write(&list, "# comment\n*.log\n!keep.log\nbuild/\n").unwrap();
+    let list = list.to_str().unwrap();
+    let out = run(dir.path(), &["--exclude-from", list, "."]);
+    assert_eq!(out, "./a.txt\n./b.txt\n./keep.log\n\n");
+
+    std::fs::write(dir.path().join("files"), "a.txt\nbuild/d.txt\nc.log\nkeep.log\n").unwrap();
+    let out = run(dir.path(), &["--exclude-from", list, "--files-from", "files"]);
+    assert_eq!(out, "a.txt\nkeep.log\n\n");
+}
+
+#[test]
+fn same_name_keeps_only_copies_with_the_same_name() {
+    let dir = tree(&[
+        ("a/photo.jpg", b"the same photo"), ("b/photo.jpg", b"the same photo"),
+        ("a/notes.txt", b"same text, different names"), ("b/renamed.txt", b"same text, different names"),
+        ("a/x", b"three copies"), ("b/x", b"three copies"), ("c/y", b"three copies"),
+    ]);
+    let out = run(dir.path(), &["a", "b", "c"]);
+    assert_eq!(common::groups(&out).len(), 3);
+    // The split leaves no singletons behind, so `c/y` goes and the renamed
+    // pair goes altogether.
+    let out = run(dir.path(), &["--same-name", "a", "b", "c"]);
+    assert_eq!(common::groups(&out), [vec!["a/photo.jpg", "b/photo.jpg"], vec!["a/x", "b/x"]]);
+}
+
+#[test]
+fn dirs_reports_the_topmost_identical_subtrees() {
+    let dir = tree(&[
+        ("a/photos/x.jpg", b"first photo"), ("a/photos/trip/y.jpg", b"second photo"),
+        ("b/photos/x.jpg", b"first photo"), ("b/photos/trip/y.jpg", b"second photo"),
+        // The same files, plus one more, so not the same directory.
+        ("c/photos/x.jpg", b"first photo"), ("c/photos/trip/y.jpg", b"second photo"),
+        ("c/photos/trip/z.jpg", b"third photo"),
+        ("a/only-in-a", b"keeps a and b apart"), ("b/only-in-b", b"keeps them apart too"),
+    ]);
+    let out = run(dir.path(), &["--dirs", "a", "b", "c"]);
+    // Only the `photos` directories, not the `trip` ones inside them.
+    assert_eq!(common::groups(&out), [vec!["a/photos", "b/photos"]]);
+}
+
+#[test]
+fn relative_to_shortens_only_paths_under_it() {
+    let dir = tree(&[("deep/a/x", b"same"), ("deep/a/sub/y", b"same"), ("other/z", b"same")]);
+    let deep = dir.path().join("deep");
+    let other = dir.path().join("other");
+    let roots = [deep.to_str().unwrap(), other.to_str().unwrap()];
+    let outside = other.join("z").to_str().unwrap().to_string();
+
+    let out = run(dir.path(), &[&["--relative-to", "deep/a"][..], &roots].concat());
+    assert_eq!(common::groups(&out), [vec!["sub/y", "x", &outside]]);
+
+    let out = run(dir.path(), &[&["--json", "--relative-to", "deep/a"][..], &roots].concat());
+    let groups: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(groups[0]["paths"], serde_json::json!(["sub/y", "x", outside]));
+
+    let out = run(dir.path(), &[&["--csv", "--relative-to", "deep/a"][..], &roots].concat());
+    let paths: Vec<String> = csv::Reader::from_reader(out.as_bytes()).records()
+        .map(|row| row.unwrap()[2].to_string())
+        .collect();
+    assert_eq!(paths, [&outside, "sub/y", "x"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn file_reachable_from_two_roots_is_not_its_own_duplicate() {
+    let dir = tree(&[("a/x", b"only one of these"), ("a/y", b"a real copy"), ("b/y", b"a real copy")]);
+    std::os::unix::fs::symlink(dir.path().join("a/x"), dir.path().join("b/x")).unwrap();
+    let out = run(dir.path(), &["--follow-symlinks", "--delete", "--dry-run", "a", "b"]);
+    assert_eq!(out.matches("would delete:").count(), 1, "{out}");
+    assert!(out.contains("would delete: b/y\n"), "{out}");
+
+    let out = run(dir.path(), &["--follow-symlinks", "--delete", "--yes", "a", "b"]);
+    assert!(!out.contains("a/x") && !out.contains("b/x"), "{out}");
+    assert_eq!(std::fs::read(dir.path().join("a/x")).unwrap(), b"only one of these");
+    assert!(!dir.path().join("b/y").exists());
+}
+
+#[test]
+fn prune_empty_dirs_leaves_a_non_empty_sibling() {
+    let dir = tree(&[
+        ("keep/photo.jpg", b"pixels"),
+        ("old/2019/photo.jpg", b"pixels"), ("old/2019/deeper/copy.jpg", b"pixels"),
+        ("old/2020/photo.jpg", b"pixels"), ("old/2020/notes.txt", b"not a copy of anything"),
+    ]);
+    let out = run(dir.path(), &["--prefer-dir", "keep", "--delete", "--yes",
+        "--prune-empty-dirs", "keep", "old"]);
+    assert!(out.contains("removing empty directory: old/2019\n"), "{out}");
+    assert!(out.contains("removing empty directory: old/2019/deeper\n"), "{out}");
+    assert!(!out.contains("old/2020\n"), "{out}");
+    assert!(!dir.path().join("old/2019").exists());
+    assert!(!dir.path().join("old/2020/photo.jpg").exists());
+    // The sibling with something left in it stays, and the roots are never
+    // touched.
+    assert!(dir.path().join("old/2020/notes.txt").exists());
+    assert!(dir.path().join("old").is_dir() && dir.path().join("keep").is_dir());
+}
+
+#[test]
+fn stats_json_has_every_field_in_bytes() {
+    let dir = tree(&[
+        ("a", b"ten bytes!"), ("b", b"ten bytes!"), ("c", b"ten bytes!"),
+        ("d", b"twenty bytes, twice!"), ("e", b"twenty bytes, twice!"),
+        // The same size as `a`, so it gets looked at, but not the same.
+        ("f", b"ten bytes?"),
+        ("g", b"a size all of its own"),
+    ]);
+    let out = run(dir.path(), &["--stats-json", "."]);
+    assert_eq!(out.lines().count(), 1, "{out}");
+    let stats: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(stats["dupe_count"], 3);
+    assert_eq!(stats["set_count"], 2);
+    assert_eq!(stats["reclaimable_bytes"], 40);
+    assert_eq!(stats["total_files"], 6);
+    assert_eq!(stats["size_classes"], 2);
+    assert_eq!(stats["prehash_groups"], 3);
+    assert_eq!(stats["unreadable"], 0);
+    assert!(stats["elapsed_ms"].is_u64(), "{out}");
+    assert_eq!(stats.as_object().unwrap().len(), 8, "{out}");
+}
+
+#[test]
+fn timing_goes_in_the_summary() {
+    let dir = tree(&[("a", b"some contents"), ("b", b"some contents"), ("c", b"other bytes!!")]);
+    let out = common::drupes(dir.path(), &["--timing", "."]).output().unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.starts_with("finished in "), "{stderr}");
+    assert!(stderr.contains(", after reading 39 bytes ("), "{stderr}");
+
+    // With `--summarize`, it's part of the summary, and isn't repeated.
+    let out = common::drupes(dir.path(), &["--timing", "--summarize", "."]).output().unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.lines().last().unwrap().starts_with("finished in "), "{stdout}");
+    assert!(stdout.contains(", after reading 39 bytes ("), "{stdout}");
+    assert!(out.stderr.is_empty(), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = run(dir.path(), &["--summarize", "."]);
+    assert!(!out.contains("finished in"), "{out}");
+
+    let out = run(dir.path(), &["--timing", "--stats-json", "."]);
+    let stats: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(stats["bytes_read"], 39);
+    assert!(stats["elapsed_ms"].is_u64(), "{out}");
+    let out = run(dir.path(), &["--stats-json", "."]);
+    let stats: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert!(stats.get("bytes_read").is_none(), "{out}");
+}
+
+#[test]
+fn deleting_empty_files_needs_delete_empties() {
+    let dir = tree(&[("a", b""), ("sub/b", b""), ("sub/deeper/c", b""), ("d", b"real"), ("e", b"real")]);
+    let out = common::drupes(dir.path(), &["--empty", "--delete", "--yes", "."]).output().unwrap();
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("leaving 3 empty files alone; use --delete-empties"), "{stderr}");
+    for name in ["a", "sub/b", "sub/deeper/c", "d"] {
+        assert!(dir.path().join(name).exists(), "{name} is gone");
+    }
+    assert!(!dir.path().join("e").exists());
+
+    let out = run(dir.path(), &["--empty", "--delete-empties", "--delete", "--yes", "."]);
+    let left: Vec<_> = ["a", "sub/b", "sub/deeper/c"].into_iter()
+        .filter(|name| dir.path().join(name).exists())
+        .collect();
+    assert_eq!(left.len(), 1, "{out}");
+}
+
+#[test]
+fn hidden_files_are_only_searched_with_hidden() {
+    let dir = tree(&[
+        ("notes.txt", b"a note"), (".notes.txt", b"a note"),
+        ("photo.jpg", b"pixels"), (".cache/thumbs/photo.jpg", b"pixels"),
+        ("config", b"settings"), (".config/app/config", b"settings"), ("sub/config", b"settings"),
+    ]);
+    let out = run(dir.path(), &["."]);
+    assert_eq!(common::groups(&out), [vec!["./config", "./sub/config"]]);
+    let out = run(dir.path(), &["--hidden", "."]);
+    assert_eq!(common::groups(&out), [
+        vec!["./.cache/thumbs/photo.jpg", "./photo.jpg"],
+        vec!["./.config/app/config", "./config", "./sub/config"],
+        vec!["./.notes.txt", "./notes.txt"],
+    ]);
+    // `--exclude-hidden` is the default, and undoes an earlier `--hidden`.
+    let out = run(dir.path(), &["--hidden", "--exclude-hidden", "."]);
+    assert_eq!(common::groups(&out), [vec!["./config", "./sub/config"]]);
+    assert_eq!(run(dir.path(), &["--exclude-hidden", "."]), run(dir.path(), &["."]));
+}
+
+#[test]
+fn git_directories_are_left_alone_by_default() {
+    // A repository's objects can have a copy of a file in them, but that's
+    // not one to keep over the real one, or to delete.
+    let dir = tree(&[("a/x", b"tracked file"), ("a/.git/obj", b"tracked file"), ("b/x", b"tracked file")]);
+    assert_eq!(run(dir.path(), &["a", "b"]), "a/x
+b/x
+
+");
+    run(dir.path(), &["--delete", "--yes", "a", "b"]);
+    assert!(dir.path().join("a/.git/obj").exists() && dir.path().join("a/x").exists());
+    assert!(!dir.path().join("b/x").exists());
+}
+
+#[test]
+fn max_depth_leaves_out_deeper_files() {
+    let dir = tree(&[
+        ("top/a", b"copied at every depth"), ("top/one/a", b"copied at every depth"),
+        ("top/one/two/a", b"copied at every depth"), ("top/one/two/three/a", b"copied at every depth"),
+        ("top/b", b"only copied deep down"), ("top/one/two/b", b"only copied deep down"),
+    ]);
+    let out = run(dir.path(), &["--max-depth", "1", "top"]);
+    assert_eq!(out, "");
+    let out = run(dir.path(), &["--max-depth", "2", "top"]);
+    assert_eq!(common::groups(&out), [vec!["top/a", "top/one/a"]]);
+    let out = run(dir.path(), &["--max-depth", "3", "top"]);
+    assert_eq!(common::groups(&out), [
+        vec!["top/a", "top/one/a", "top/one/two/a"],
+        vec!["top/b", "top/one/two/b"],
+    ]);
+    assert_eq!(common::groups(&run(dir.path(), &["top"]))[0].len(), 4);
+}
+
+#[test]
+fn min_group_size_hides_smaller_sets() {
+    let mut files: Vec<(String, &[u8])> = vec![];
+    for (copies, contents) in [(2, &b"two copies"[..]), (3, b"three copies"), (6, b"six copies")] {
+        for i in 0..copies {
+            files.push((format!("{copies}/{i}"), contents));
+        }
+    }
+    let files: Vec<(&str, &[u8])> = files.iter().map(|(name, contents)| (name.as_str(), *contents)).collect();
+    let dir = tree(&files);
+
+    let sizes = |out: &str| common::groups(out).iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(sizes(&run(dir.path(), &["."])), [2, 3, 6]);
+    assert_eq!(sizes(&run(dir.path(), &["--min-group-size", "3", "."])), [3, 6]);
+    assert_eq!(sizes(&run(dir.path(), &["--min-group-size", "6", "."])), [6]);
+
+    let out = run(dir.path(), &["--min-group-size", "3", "--summarize", "."]);
+    assert!(out.starts_with("7 duplicate files (in 2 sets)"), "{out}");
+    assert!(out.contains("(1 smaller sets not counted, due to --min-group-size)"), "{out}");
+
+    // Only the sets that are shown get acted on.
+    run(dir.path(), &["--min-group-size", "3", "--delete", "--yes", "."]);
+    let left = |copies: usize| (0..copies).filter(|i| dir.path().join(format!("{copies}/{i}")).exists()).count();
+    assert_eq!((left(2), left(3), left(6)), (2, 1, 1));
+}
+
+#[test]
+fn case_insensitive_names_match_names_that_differ_in_case() {
+    let dir = tree(&[
+        ("a/IMG.JPG", b"the same photo"), ("b/img.jpg", b"the same photo"),
+        ("a/Ärger.txt", b"not just ascii"), ("b/ärger.TXT", b"not just ascii"),
+        ("a/one", b"really different names"), ("b/two", b"really different names"),
+    ]);
+    let out = run(dir.path(), &["--same-name", "a", "b"]);
+    assert_eq!(out, "");
+    let out = run(dir.path(), &["--same-name", "--case-insensitive-names", "a", "b"]);
+    assert_eq!(common::groups(&out), [vec!["a/IMG.JPG", "b/img.jpg"], vec!["a/Ärger.txt", "b/ärger.TXT"]]);
+    // It only means anything with `--same-name`.
+    assert!(!common::drupes(dir.path(), &["--case-insensitive-names", "a", "b"])
+        .output().unwrap().status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn script_deletes_awkward_names_when_run() {
+    let dir = tree(&[("a/keep", b"same"), ("b/Bob's copy", b"same"), ("b/it's $HOME", b"same"),
+        ("b/don't touch", b"different")]);
+    run(dir.path(), &["--script", "rm.sh", "a", "b"]);
+    let script = std::fs::read_to_string(dir.path().join("rm.sh")).unwrap();
+    assert!(script.starts_with("#!/bin/sh\n"), "{script}");
+    assert!(script.contains("\n# set 1 of 1: keeping \"a/keep\"\n"), "{script}");
+    assert!(script.contains("\nrm -f -- 'b/Bob'\\''s copy'\n"), "{script}");
+    assert!(script.contains("\nrm -f -- 'b/it'\\''s $HOME'\n"), "{script}");
+    // Nothing's been deleted yet.
+    assert!(dir.path().join("b/Bob's copy").exists());
+
+    // Run it from somewhere else, to check that it finds its way back.
+    let status = std::process::Command::new("sh").arg(dir.path().join("rm.sh"))
+        .current_dir("/").status().unwrap();
+    assert!(status.success());
+    assert!(dir.path().join("a/keep").exists() && dir.path().join("b/don't touch").exists());
+    assert!(!dir.path().join("b/Bob's copy").exists());
+    assert!(!dir.path().join("b/it's $HOME").exists());
+}
+
+#[test]
+fn keep_pattern_keeps_the_original() {
+    let dir = tree(&[
+        ("a/copy.jpg", b"first photo"), ("photos/originals/img.jpg", b"first photo"),
+        ("z/img.jpg", b"first photo"),
+        ("b/x.jpg", b"no original anywhere"), ("c/x.jpg", b"no original anywhere"),
+        ("originals/one", b"both originals"), ("photos/originals/two", b"both originals"),
+    ]);
+    let out = common::drupes(dir.path(), &["--keep-pattern", "**/originals/**", "--delete",
+        "--dry-run", "."]).output().unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let mut deleted: Vec<&str> = stdout.lines()
+        .filter_map(|line| line.strip_prefix("would delete: "))
+        .collect();
+    deleted.sort();
+    // Where nothing matches, the first is kept as usual, and where everything
+    // does, the first that matches.
+    assert_eq!(deleted, ["./a/copy.jpg", "./c/x.jpg", "./photos/originals/two", "./z/img.jpg"]);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("warning: 1 sets of duplicates have no file matching --keep-pattern"),
+        "{stderr}");
+
+    // Really deleting leaves the original behind.
+    run(dir.path(), &["--keep-pattern", "**/originals/**", "--delete", "--yes", "."]);
+    assert!(dir.path().join("photos/originals/img.jpg").exists());
+    assert!(!dir.path().join("a/copy.jpg").exists() && !dir.path().join("z/img.jpg").exists());
+}
+
+#[test]
+fn delete_needs_yes_without_a_terminal() {
+    let dir = tree(&[("a", b"same"), ("b", b"same")]);
+    let out = common::drupes(dir.path(), &["--delete", "."])
+        .stdin(std::process::Stdio::null())
+        .output().unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("it needs --yes"));
+    assert!(dir.path().join("a").exists() && dir.path().join("b").exists());
+
+    let out = run(dir.path(), &["--delete", "--yes", "."]);
+    assert!(!out.contains("[y/N]"), "{out}");
+    assert!(dir.path().join("a").exists() && !dir.path().join("b").exists());
+}
+
+#[test]
+fn breakdown_lists_the_largest_sets_first() {
+    let dir = tree(&[
+        ("a1", &[b'a'; 100]), ("a2", &[b'a'; 100]),
+        ("b1", &[b'b'; 30]), ("b2", &[b'b'; 30]), ("b3", &[b'b'; 30]), ("b4", &[b'b'; 30]),
+        ("b5", &[b'b'; 30]),
+        ("c1", &[b'c'; 500]), ("c2", &[b'c'; 500]),
+        ("d1", &[b'd'; 10]), ("d2", &[b'd'; 10]),
+    ]);
+    let out = run(dir.path(), &["--summarize", "--breakdown", "3", "."]);
+    let (_, table) = out.split_once("largest sets, by space that could be reclaimed:\n").unwrap();
+    let mut rows = table.lines();
+    assert!(rows.next().unwrap().ends_with("reclaimable  keeper"), "{out}");
+    let rows: Vec<(String, &str)> = rows
+        .map(|row| {
+            let cells: Vec<&str> = row.split("  ").filter(|cell| !cell.is_empty()).collect();
+            (cells[3].trim().to_string(), *cells.last().unwrap())
+        })
+        .collect();
+    assert_eq!(rows, [
+        ("500 bytes".to_string(), "./c1"),
+        ("120 bytes".to_string(), "./b1"),
+        ("100 bytes".to_string(), "./a1"),
+    ]);
+}
+
+#[test]
+fn exclude_size_drops_that_size_class() {
+    let dir = tree(&[
+        ("stub1", &[b's'; 1024]), ("stub2", &[b's'; 1024]), ("stub3", &[b's'; 1024]),
+        ("other1", &[b'o'; 1024]), ("other2", &[b'o'; 1024]),
+        ("small1", &[b'x'; 10]), ("small2", &[b'x'; 10]),
+        ("big1", &[b'y'; 2048]), ("big2", &[b'y'; 2048]),
+    ]);
+    let sizes = |out: &str| -> Vec<u64> {
+        let groups: serde_json::Value = serde_json::from_str(out).unwrap();
+        groups.as_array().unwrap().iter().map(|group| group["size"].as_u64().unwrap()).collect()
+    };
+    let mut all = sizes(&run(dir.path(), &["--json", "."]));
+    all.sort();
+    assert_eq!(all, [10, 1024, 1024, 2048]);
+    let mut left = sizes(&run(dir.path(), &["--json", "--exclude-size", "1024", "."]));
+    left.sort();
+    assert_eq!(left, [10, 2048]);
+    let left = sizes(&run(dir.path(), &["--json", "--exclude-size", "1024", "--exclude-size", "10", "."]));
+    assert_eq!(left, [2048]);
+
+    // Those files aren't even looked at.
+    let out = run(dir.path(), &["--summarize", "--exclude-size", "1024", "."]);
+    assert!(out.contains("checked 4 files in 2 size classes"), "{out}");
+}
+
+#[test]
+fn manifest_finds_files_that_are_already_archived() {
+    let archive = tree(&[("photo.jpg", b"already archived"), ("notes.txt", b"archived too")]);
+    let import = tree(&[("new/photo copy.jpg", b"already archived"), ("new/fresh.jpg", b"never seen")]);
+    let checksums = run(archive.path(), &["--checksum-output", "."]);
+    let lists = tempfile::tempdir().unwrap();
+    let manifest = lists.path().join("manifest");
+    std::fs::write(&manifest, &checksums).unwrap();
+
+    let out = common::drupes(import.path(), &["--manifest", manifest.to_str().unwrap(), "."])
+        .output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "./new/photo copy.jpg\n");
+    assert!(String::from_utf8(out.stderr).unwrap()
+        .contains("1 of 2 files are already archived"));
+
+    // A plain list of hashes does as well.
+    let hashes: String = checksums.lines()
+        .map(|line| format!("{}\n", line.split_whitespace().next().unwrap()))
+        .collect();
+    std::fs::write(&manifest, hashes).unwrap();
+    let out = run(import.path(), &["--manifest", manifest.to_str().unwrap(), "."]);
+    assert_eq!(out, "./new/photo copy.jpg\n");
+}
+
+#[test]
+fn written_manifest_reads_back_with_manifest() {
+    let dir = tree(&[("a", b"linked"), ("sub/c", b"on its own"), ("sub/d", b"a size of its own")]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let lists = tempfile::tempdir().unwrap();
+    let manifest = lists.path().join("manifest");
+    let manifest = manifest.to_str().unwrap();
+
+    let out = common::drupes(dir.path(), &["--write-manifest", manifest, "--manifest-paths", "."])
+        .output().unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stderr).unwrap().contains("hashing all 4 files in full"));
+    let written = std::fs::read_to_string(manifest).unwrap();
+    let mut lines = written.lines();
+    assert_eq!(lines.next(), Some("# drupes manifest: blake3"));
+    // Every file is in it, including both names of the hardlinked one.
+    let mut entries: Vec<(&str, &str)> = lines
+        .map(|line| {
+            let fields: Vec<&str> = line.splitn(3, "  ").collect();
+            (fields[2], fields[1])
+        })
+        .collect();
+    entries.sort();
+    assert_eq!(entries, [("./a", "6"), ("./b", "6"), ("./sub/c", "10"), ("./sub/d", "17")]);
+
+    // It won't be overwritten by accident.
+    assert!(!common::drupes(dir.path(), &["--write-manifest", manifest, "."])
+        .output().unwrap().status.success());
+    run(dir.path(), &["--write-manifest", manifest, "--force", "."]);
+    assert!(!std::fs::read_to_string(manifest).unwrap().contains("./a"));
+
+    // Everything in the tree is in the manifest made from it.
+    let out = run(dir.path(), &["--manifest", manifest, "."]);
+    assert_eq!(out, "./a\n./b\n./sub/c\n./sub/d\n");
+    common::write(dir.path(), "sub/e", b"new since");
+    let out = run(dir.path(), &["--manifest", manifest, "sub"]);
+    assert_eq!(out, "sub/c\nsub/d\n");
+}
+
+#[test]
+fn footer_counts_on_stderr() {
+    let dir = tree(&[("a", b"four"), ("b", b"four"), ("c", b"four"), ("d", b"sixsix"),
+        ("e", b"sixsix"), ("f", b"a size of its own"), ("g", b"")]);
+    let plain = run(dir.path(), &["."]);
+    let out = common::drupes(dir.path(), &["--footer", "."]).output().unwrap();
+    assert!(out.status.success());
+    // The listing itself is just the same.
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), plain);
+    // The empty file isn't looked at, without `--empty`.
+    assert_eq!(String::from_utf8(out.stderr).unwrap(),
+        "scanned 6 files, and found 3 duplicates (in 2 sets), occupying 14 bytes\n");
+}
+
+#[test]
+fn by_type_adds_up_each_extension() {
+    let dir = tree(&[
+        ("a.jpg", &[b'j'; 100]), ("b.jpg", &[b'j'; 100]), ("c.jpg", &[b'j'; 100]),
+        ("d.jpg", &[b'k'; 50]), ("e.JPG", &[b'k'; 50]),
+        ("f.mp4", &[b'm'; 1000]), ("g.mp4", &[b'm'; 1000]),
+        ("README", &[b'r'; 7]), ("README.bak", &[b'r'; 7]),
+    ]);
+    let out = run(dir.path(), &["--summarize", "--by-type", "."]);
+    let (_, types) = out.split_once("space that could be reclaimed, by type:\n").unwrap();
+    // Each duplicate counts under its own extension; the one kept counts for
+    // nothing.
+    assert_eq!(types, "\
+        mp4: 1000 bytes (1 files)\n\
+        jpg: 250 bytes (3 files)\n\
+        bak: 7 bytes (1 files)\n");
+}
+
+#[test]
+fn no_prehash_finds_the_same_groups() {
+    // Big files that start the same and only differ past the prehash, as well
+    // as small ones.
+    let mut late = vec![b'L'; 100_000];
+    let same = late.clone();
+    *late.last_mut().unwrap() = b'!';
+    let dir = tree(&[
+        ("big/a", &same), ("big/b", &same), ("big/c", &late), ("big/d", &late), ("big/e", &same[1..]),
+        ("small/a", b"tiny"), ("small/b", b"tiny"), ("small/c", b"tin!"), ("small/d", b""),
+        ("small/e", b""), ("small/f", b"unique size"),
+    ]);
+    for extra in [&[][..], &["--empty"], &["--paranoid"]] {
+        let default = run(dir.path(), &[extra, &["."][..]].concat());
+        let no_prehash = run(dir.path(), &[extra, &["--no-prehash", "."][..]].concat());
+        assert_eq!(no_prehash, default, "{extra:?}");
+    }
+    assert_eq!(common::groups(&run(dir.path(), &["--no-prehash", "."])), [
+        vec!["./big/a", "./big/b"], vec!["./big/c", "./big/d"], vec!["./small/a", "./small/b"],
+    ]);
+}
+
+#[test]
+fn profile_times_every_phase() {
+    let dir = tree(&[("a", &[b'p'; 10_000]), ("b", &[b'p'; 10_000]), ("c", b"small"), ("d", b"small")]);
+    // The profile is the last line on stderr, with the phases in the order
+    // they happen.
+    let phases = |args: &[&str]| {
+        let out = common::drupes(dir.path(), args).output().unwrap();
+        assert!(out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let last = stderr.lines().last().unwrap().to_owned();
+        last.split(", ")
+            .map(|phase| {
+                let (name, time) = phase.split_once(": ").unwrap();
+                (name.to_owned(), time != "0.00ns")
+            })
+            .collect::<Vec<_>>()
+    };
+    let names = |phases: &[(String, bool)]| phases.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+
+    let plain = phases(&["--profile", "."]);
+    assert_eq!(names(&plain), ["walk", "prehash", "fullhash", "verify"]);
+    // Nothing is compared byte-for-byte unless asked for.
+    assert_eq!(plain.iter().map(|(_, took)| *took).collect::<Vec<_>>(), [true, true, true, false]);
+
+    let paranoid = phases(&["--profile", "--paranoid", "."]);
+    assert_eq!(paranoid.iter().map(|(_, took)| *took).collect::<Vec<_>>(), [true, true, true, true]);
+
+    // Streaming JSON lines checks each group as soon as it's hashed, so the
+    // checking is counted as hashing.
+    let streamed = phases(&["--profile", "--paranoid", "--json-lines", "."]);
+    assert_eq!(names(&streamed), ["walk", "prehash", "fullhash", "verify"]);
+    assert_eq!(streamed.iter().map(|(_, took)| *took).collect::<Vec<_>>(), [true, true, true, false]);
+}
+
+#[test]
+fn text_normalize_groups_crlf_with_lf() {
+    let dir = tree(&[
+        ("unix.txt", b"first line\nsecond line\n"), ("dos.txt", b"first line\r\nsecond line\r\n"),
+        ("trailing.txt", b"first line  \nsecond line\n\n\n"), ("other.txt", b"first line\nsecond lime\n"),
+        ("binary", b"first line\0\nsecond line\n"), ("binary.dos", b"first line\0\r\nsecond line\r\n"),
+    ]);
+    // Without the flag, none of them are the same size and contents.
+    assert_eq!(run(dir.path(), &["."]), "");
+    let out = run(dir.path(), &["--text-normalize", "."]);
+    assert_eq!(common::groups(&out), [["./dos.txt", "./trailing.txt", "./unix.txt"]]);
+    // Anything bigger than the size given is left as it is.
+    assert_eq!(run(dir.path(), &["--text-normalize=24", "."]), "");
+    let out = run(dir.path(), &["--text-normalize=30", "."]);
+    assert_eq!(common::groups(&out), [["./dos.txt", "./trailing.txt", "./unix.txt"]]);
+}
+
+#[test]
+fn exit_code_tells_whether_duplicates_were_found() {
+    let dir = tree(&[("dupes/a", b"same"), ("dupes/b", b"same"), ("alone/c", b"one"), ("alone/d", b"two")]);
+    let status = |args: &[&str]| common::drupes(dir.path(), args).output().unwrap().status.code();
+    assert_eq!(status(&["--exit-code", "alone"]), Some(0));
+    assert_eq!(status(&["--exit-code", "dupes"]), Some(1));
+    assert_eq!(status(&["--exit-code", "alone", "dupes"]), Some(1));
+    // Duplicates that aren't shown were still found.
+    assert_eq!(status(&["--exit-code", "--limit", "0", "dupes"]), Some(1));
+    assert_eq!(status(&["--exit-code", "--min-group-size", "3", "dupes"]), Some(1));
+    assert_eq!(status(&["--exit-code", "--min-group-size", "3", "--json", "dupes"]), Some(1));
+    // A root that isn't there is only an error with `--strict`.
+    assert_eq!(status(&["--exit-code", "--strict", "not-there"]), Some(2));
+    // Without it, finding duplicates is as much a success as not.
+    assert_eq!(status(&["dupes"]), Some(0));
+    assert_eq!(status(&["--strict", "not-there"]), Some(1));
+}
+
+#[test]
+fn root_priority_keeps_what_is_under_the_first_root() {
+    let dir = tree(&[("new/photo.jpg", b"pixels"), ("old/photo.jpg", b"pixels"),
+        ("old/deeper/photo.jpg", b"pixels"), ("new/notes.txt", b"words"), ("old/notes.txt", b"words")]);
+    let newest_first = [
+        vec!["new/notes.txt", "old/notes.txt"],
+        vec!["new/photo.jpg", "old/deeper/photo.jpg", "old/photo.jpg"],
+    ];
+    // Without it, the order the roots are given in makes no difference.
+    assert_eq!(common::groups(&run(dir.path(), &["old", "new"])), newest_first);
+    let out = run(dir.path(), &["--root-priority", "old", "new"]);
+    assert_eq!(common::groups(&out), [
+        vec!["old/deeper/photo.jpg", "old/photo.jpg", "new/photo.jpg"],
+        vec!["old/notes.txt", "new/notes.txt"],
+    ]);
+    assert_eq!(common::groups(&run(dir.path(), &["--root-priority", "new", "old"])), newest_first);
+    // `--prefer-dir` comes first, and `--keep` settles it between files under
+    // the same root.
+    let out = run(dir.path(), &["--root-priority", "--prefer-dir", "new", "old", "new"]);
+    assert_eq!(common::groups(&out), newest_first);
+    let out = run(dir.path(), &["--root-priority", "--keep", "shortest-path", "old", "new"]);
+    assert_eq!(common::groups(&out), [
+        vec!["old/notes.txt", "new/notes.txt"],
+        vec!["old/photo.jpg", "old/deeper/photo.jpg", "new/photo.jpg"],
+    ]);
+
+    run(dir.path(), &["--root-priority", "--delete", "--yes", "old", "new"]);
+    assert!(!dir.path().join("new/photo.jpg").exists() && !dir.path().join("new/notes.txt").exists());
+    assert!(dir.path().join("old/notes.txt").exists());
+}
+
+#[test]
+fn count_only_prints_just_the_number() {
+    let dir = tree(&[("a", b"three of"), ("b", b"three of"), ("c", b"three of"),
+        ("d", b"a pair"), ("e", b"a pair"), ("f", b"no copies")]);
+    let out = common::drupes(dir.path(), &["--count-only", "."]).output().unwrap();
+    assert!(out.status.success());
+    // Two of the first set and one of the second give way to the one kept.
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "3\n");
+    assert_eq!(String::from_utf8(out.stderr).unwrap(), "");
+    assert_eq!(run(dir.path(), &["--count-only", "f"]), "0\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn annotate_hardlinks_marks_links_to_the_one_kept() {
+    let dir = tree(&[("a", b"linked and copied"), ("c", b"linked and copied")]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let out = run(dir.path(), &["--keep-hardlinks", "--annotate-hardlinks", "."]);
+    // The real copy isn't a link, so it's not marked.
+    assert_eq!(out, "./a\n./b (hardlink)\n./c\n\n");
+    assert_eq!(run(dir.path(), &["--keep-hardlinks", "."]), "./a\n./b\n./c\n\n");
+    // Without `--keep-hardlinks`, the pair is one file anyway.
+    assert_eq!(run(dir.path(), &["."]), "./a\n./c\n\n");
+}
+
+#[test]
+fn sort_paths_orders_each_set() {
+    let dir = tree(&[("a/b/deep", b"same"), ("m", b"same"), ("z/y", b"same")]);
+    common::set_mtime(&dir.path().join("m"), 3_000_000);
+    common::set_mtime(&dir.path().join("a/b/deep"), 2_000_000);
+    common::set_mtime(&dir.path().join("z/y"), 1_000_000);
+    let order = |mode| run(dir.path(), &["--sort-paths", mode, "."]);
+    assert_eq!(order("alpha"), "./a/b/deep\n./m\n./z/y\n\n");
+    assert_eq!(run(dir.path(), &["."]), order("alpha"));
+    assert_eq!(order("mtime"), "./z/y\n./a/b/deep\n./m\n\n");
+    // Files at the same depth stay in alphabetical order.
+    assert_eq!(order("depth"), "./m\n./z/y\n./a/b/deep\n\n");
+}
+
+#[test]
+fn no_recursion_looks_only_at_the_top_level() {
+    let dir = tree(&[("top/a", b"copied"), ("top/b", b"copied"), ("top/sub/c", b"copied"),
+        ("top/d", b"only copied below"), ("top/sub/e", b"only copied below"), ("other/f", b"copied")]);
+    let out = run(dir.path(), &["--no-recursion", "top"]);
+    assert_eq!(common::groups(&out), [["top/a", "top/b"]]);
+    assert_eq!(run(dir.path(), &["--no-recursion", "."]), "");
+    // Each root gets its own top level.
+    let out = run(dir.path(), &["--no-recursion", "top", "other"]);
+    assert_eq!(common::groups(&out), [["other/f", "top/a", "top/b"]]);
+    assert_eq!(common::groups(&run(dir.path(), &["top"])).len(), 2);
+    // It's short for `--max-depth 1`, and can't be given along with it.
+    assert_eq!(out, run(dir.path(), &["--max-depth", "1", "top", "other"]));
+    assert!(!common::drupes(dir.path(), &["--no-recursion", "--max-depth", "2", "top"])
+        .output().unwrap().status.success());
+}
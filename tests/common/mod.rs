@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by the integration tests. Not every test file uses all of
+//! them.
+#![allow(dead_code)]
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use tempfile::TempDir;
+
+/// Makes a temporary directory with the given files in it, each given as a
+/// path relative to the directory and its contents. Any directories they're
+/// in are created too.
+pub fn tree(files: &[(&str, &[u8])]) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, contents) in files {
+        write(dir.path(), name, contents);
+    }
+    dir
+}
+
+/// Writes `contents` to `name` under `dir`, creating any directories it's in.
+pub fn write(dir: &Path, name: &str, contents: &[u8]) {
+    let path = dir.join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// The paths in the result of a walk, relative to `dir` and sorted, with the
+/// size each was filed under.
+pub fn walked(dir: &Path, paths: &BTreeMap<u64, Vec<PathBuf>>) -> Vec<(u64, String)> {
+    let mut walked: Vec<(u64, String)> = paths.iter()
+        .flat_map(|(size, files)| files.iter().map(move |f| (*size, relative(dir, f))))
+        .collect();
+    walked.sort();
+    walked
+}
+
+/// `path`, relative to `dir`, with `/` between its parts on every platform.
+pub fn relative(dir: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(dir).unwrap_or(path);
+    rel.components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A command that runs the `drupes` binary, from `dir`, with `args`.
+pub fn drupes(dir: &Path, args: &[&str]) -> std::process::Command {
+    let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_drupes"));
+    cmd.current_dir(dir).args(args);
+    cmd
+}
+
+/// Runs `drupes` from `dir` with `args`, and returns what it printed on
+/// standard output, checking that it succeeded.
+pub fn run(dir: &Path, args: &[&str]) -> String {
+    let out = drupes(dir, args).output().unwrap();
+    assert!(out.status.success(), "drupes {args:?} failed: {}",
+        String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// Sets the modification time of `path` to `secs` seconds after the Unix
+/// epoch.
+pub fn set_mtime(path: &Path, secs: u64) {
+    std::fs::File::options().write(true).open(path).unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+        .unwrap();
+}
+
+/// Just the paths in the result of a walk, relative to `dir` and sorted.
+pub fn names(dir: &Path, paths: &BTreeMap<u64, Vec<PathBuf>>) -> Vec<String> {
+    let mut names: Vec<String> = paths.values()
+        .flatten()
+        .map(|f| relative(dir, f))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Splits the plain list of duplicates that `drupes` prints into its groups.
+pub fn groups(out: &str) -> Vec<Vec<&str>> {
+    out.split("\n\n")
+        .filter(|group| !group.is_empty())
+        .map(|group| group.lines().collect())
+        .collect()
+}
+
+/// Makes `path` unreadable, and returns whether that actually keeps us out,
+/// which it doesn't when running as root.
+#[cfg(unix)]
+pub fn lock(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o000)).unwrap();
+    if path.is_dir() {
+        std::fs::read_dir(path).is_err()
+    } else {
+        std::fs::File::open(path).is_err()
+    }
+}
+
+/// Undoes [`lock`], setting the permissions to `mode`, so that the
+/// temporary directory can be cleaned up.
+#[cfg(unix)]
+pub fn unlock(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
@@ -0,0 +1,80 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests of comparing two trees with `diff_trees`.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use drupes::{diff_trees, ScanOptions, TreeDiff};
+
+use common::{relative, tree};
+
+/// Files in one tree, relative to the directory both trees are in.
+fn rel(dir: &Path, files: &[PathBuf]) -> Vec<String> {
+    files.iter().map(|f| relative(dir, f)).collect()
+}
+
+/// Compares `base` and `other` under `dir`, with the default options.
+fn diff(dir: &Path) -> TreeDiff {
+    diff_trees(&dir.join("base"), &dir.join("other"), &ScanOptions::new()).unwrap()
+}
+
+#[test]
+fn identical_trees_share_everything() {
+    let dir = tree(&[("base/a", b"first"), ("base/sub/b", b"second file"),
+        ("other/a", b"first"), ("other/sub/b", b"second file")]);
+    let diff = diff(dir.path());
+    let shared: Vec<_> = diff.in_both.iter()
+        .map(|s| (rel(dir.path(), &s.base), rel(dir.path(), &s.other), s.size))
+        .collect();
+    assert_eq!(shared, [
+        (vec!["base/a".to_string()], vec!["other/a".to_string()], 5),
+        (vec!["base/sub/b".to_string()], vec!["other/sub/b".to_string()], 11),
+    ]);
+    assert_ne!(diff.in_both[0].hash, diff.in_both[1].hash);
+    assert!(diff.only_in_base.is_empty());
+    assert!(diff.only_in_other.is_empty());
+}
+
+#[test]
+fn disjoint_trees_share_nothing() {
+    // Some of these are the same size, or start the same, so that they're
+    // only told apart by the later passes.
+    let dir = tree(&[("base/a", b"aaaa"), ("base/b", b"same start, one"), ("base/c", b"lonely"),
+        ("other/a", b"bbbb"), ("other/b", b"same start, two"), ("other/d", b"by itself!")]);
+    let diff = diff(dir.path());
+    assert!(diff.in_both.is_empty());
+    assert_eq!(rel(dir.path(), &diff.only_in_base), ["base/a", "base/b", "base/c"]);
+    assert_eq!(rel(dir.path(), &diff.only_in_other), ["other/a", "other/b", "other/d"]);
+}
+
+#[test]
+fn partly_overlapping_trees_are_sorted_out() {
+    let dir = tree(&[
+        ("base/photo", b"a photo"), ("base/photo copy", b"a photo"),
+        ("base/notes", b"only in base"),
+        ("base/old", b"changed later"),
+        ("other/renamed", b"a photo"),
+        ("other/old", b"changed since"),
+        ("other/new", b"only in other"),
+        // Copies within one tree don't make their contents shared.
+        ("other/twice1", b"only in other, twice"), ("other/twice2", b"only in other, twice"),
+    ]);
+    let diff = diff(dir.path());
+    assert_eq!(diff.in_both.len(), 1);
+    assert_eq!(rel(dir.path(), &diff.in_both[0].base), ["base/photo", "base/photo copy"]);
+    assert_eq!(rel(dir.path(), &diff.in_both[0].other), ["other/renamed"]);
+    assert_eq!(rel(dir.path(), &diff.only_in_base), ["base/notes", "base/old"]);
+    assert_eq!(rel(dir.path(), &diff.only_in_other),
+        ["other/new", "other/old", "other/twice1", "other/twice2"]);
+}
+
+#[test]
+fn nested_trees_are_refused() {
+    let dir = tree(&[("base/a", b"x"), ("base/other/a", b"x")]);
+    assert!(diff_trees(&dir.path().join("base"), &dir.path().join("base/other"),
+        &ScanOptions::new()).is_err());
+}
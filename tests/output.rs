@@ -0,0 +1,170 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests of the statistics and reports written about what was found.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use drupes::{
+    summarize, write_csv, write_groups, write_json, write_json_lines, write_table, Digest,
+    DuplicateGroup, HashAlgorithm, ListOptions, ScanStats,
+};
+
+/// A group of duplicates, of files `size` bytes long, at `paths`.
+fn group(size: u64, paths: &[&str]) -> DuplicateGroup {
+    DuplicateGroup {
+        hash: Digest::from_hex(&"ab".repeat(32)).unwrap(),
+        size,
+        paths: paths.iter().map(PathBuf::from).collect(),
+    }
+}
+
+/// What `write_groups` writes for `groups` with `opts`.
+fn listed(groups: &[DuplicateGroup], opts: &ListOptions) -> String {
+    let mut out = vec![];
+    write_groups(&mut out, groups, opts).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn summarize_counts_known_groups() {
+    let sized: BTreeMap<u64, Vec<PathBuf>> = [
+        (10, vec!["a".into(), "b".into(), "c".into()]),
+        (100, vec!["d".into(), "e".into()]),
+        (7, vec!["f".into(), "g".into(), "h".into(), "i".into()]),
+    ].into_iter().collect();
+    // A group of three 10-byte files, one of two 100-byte files, and a lone
+    // file left over from a split group, which doesn't count.
+    let stats = summarize(&sized, 5, [(10, 3), (100, 2), (7, 1)]);
+    assert_eq!(stats, ScanStats {
+        dupe_count: 3,
+        set_count: 2,
+        reclaimable_bytes: 2 * 10 + 100,
+        total_files: 9,
+        size_classes: 3,
+        prehash_groups: 5,
+        unreadable: 0,
+        timing: None,
+    });
+    assert_eq!(stats.to_string(), "3 duplicate files (in 2 sets), occupying 120 bytes\n\
+        checked 9 files in 3 size classes\n\
+        prehashing identified 5 groups");
+
+    let stats = ScanStats { unreadable: 2, ..stats };
+    assert!(stats.to_string().ends_with("\n2 files could not be read and were excluded"));
+    let stats = ScanStats { timing: Some((std::time::Duration::from_secs(2), 2048)), ..stats };
+    assert!(stats.to_string().ends_with("excluded\n\
+        finished in 2.00s, after reading 2.00 KiB (1.00 KiB/s)"), "{stats}");
+    assert_eq!(summarize(&BTreeMap::new(), 0, []), ScanStats::default());
+}
+
+#[test]
+fn headers_describe_each_group() {
+    let groups = [group(2048, &["a", "b", "c"]), group(10, &["d", "e"])];
+    let out = listed(&groups, &ListOptions { headers: true, ..ListOptions::default() });
+    assert_eq!(out, "\
+        # group 1: 3 files (1 kept, 2 duplicates), 2.00 KiB each, 4.00 KiB reclaimable\n\
+        a\nb\nc\n\n\
+        # group 2: 2 files (1 kept, 1 duplicate), 10 bytes each, 10 bytes reclaimable\n\
+        d\ne\n\n");
+}
+
+#[test]
+fn mixed_extensions_are_noted() {
+    let groups = [group(10, &["photo.jpg", "copy/photo.jpeg"]), group(20, &["a.png", "b.png"]),
+        group(30, &["c.JPG", "d.jpg"]), group(40, &["notes", "notes.bak"])];
+    let opts = ListOptions { note_mixed_extensions: true, ..ListOptions::default() };
+    // Extensions that only differ in case are the same extension.
+    assert_eq!(listed(&groups, &opts), "\
+        # mixed extensions: jpeg, jpg\nphoto.jpg\ncopy/photo.jpeg\n\n\
+        a.png\nb.png\n\n\
+        c.JPG\nd.jpg\n\n\
+        # mixed extensions: (none), bak\nnotes\nnotes.bak\n\n");
+    // And without the option, there's nothing extra.
+    assert!(!listed(&groups, &ListOptions::default()).contains('#'));
+}
+
+#[test]
+fn empty_files_are_always_labelled() {
+    let groups = [group(10, &["a", "b"]), group(0, &["e1", "e2", "e3"])];
+    let label = "empty files, which only match because they're empty";
+    assert_eq!(listed(&groups, &ListOptions::default()), format!("a\nb\n\n# 3 {label}\ne1\ne2\ne3\n\n"));
+    let out = listed(&groups, &ListOptions { headers: true, ..ListOptions::default() });
+    assert!(out.ends_with(&format!("\n# group 2: 3 {label}\ne1\ne2\ne3\n\n")), "{out}");
+    // Output that's only paths is left that way.
+    assert_eq!(listed(&groups, &ListOptions { quiet: true, ..ListOptions::default() }),
+        "a\nb\ne1\ne2\ne3\n");
+    assert_eq!(listed(&groups, &ListOptions { omit_first: true, ..ListOptions::default() }),
+        "b\ne2\ne3\n");
+}
+
+#[test]
+fn groups_render_exactly_into_a_buffer() {
+    let groups = [group(5, &["a/one", "b/one", "c/one"]), group(3, &["x", "y"])];
+    assert_eq!(listed(&groups, &ListOptions::default()), "a/one\nb/one\nc/one\n\nx\ny\n\n");
+    assert_eq!(listed(&groups, &ListOptions { omit_first: true, ..ListOptions::default() }),
+        "b/one\nc/one\ny\n");
+    assert_eq!(listed(&groups, &ListOptions { quiet: true, ..ListOptions::default() }),
+        "a/one\nb/one\nc/one\nx\ny\n");
+
+    let mut out = vec![];
+    write_groups(&mut out, &groups, &ListOptions { null: true, ..ListOptions::default() }).unwrap();
+    assert_eq!(out, b"a/one\0b/one\0c/one\0\0x\0y\0\0");
+    assert_eq!(listed(&[], &ListOptions::default()), "");
+}
+
+#[test]
+fn machine_readable_formats_render_exactly_into_a_buffer() {
+    let groups = [group(5, &["b/one", "a/one"]), group(3, &["x", "y"])];
+    let hash = "ab".repeat(32);
+
+    let mut out = vec![];
+    write_json_lines(&mut out, &groups, HashAlgorithm::Blake3).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), format!("\
+        {{\"algorithm\":\"blake3\",\"hash\":\"{hash}\",\"size\":5,\"paths\":[\"b/one\",\"a/one\"]}}\n\
+        {{\"algorithm\":\"blake3\",\"hash\":\"{hash}\",\"size\":3,\"paths\":[\"x\",\"y\"]}}\n"));
+
+    let mut out = vec![];
+    write_json(&mut out, &groups[1..], HashAlgorithm::Sha256).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), format!("\
+        [\n  {{\n    \"algorithm\": \"sha256\",\n    \"hash\": \"{hash}\",\n    \
+        \"size\": 3,\n    \"paths\": [\n      \"x\",\n      \"y\"\n    ]\n  }}\n]\n"));
+
+    // Rows are sorted by path, but the keeper is still the first file given.
+    let mut out = vec![];
+    write_csv(&mut out, &groups[..1]).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), format!("\
+        hash,size,path,is_keeper\n\
+        {hash},5,a/one,false\n\
+        {hash},5,b/one,true\n"));
+}
+
+#[test]
+fn table_columns_line_up_at_a_fixed_width() {
+    let groups = [
+        group(2048, &["photos/2019/summer/beach/IMG_0001.jpg", "backup/IMG_0001.jpg"]),
+        group(10, &["a", "b", "c"]),
+    ];
+    let table = |expand| {
+        let mut out = vec![];
+        write_table(&mut out, &groups, 50, expand).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    // The path that doesn't fit in what's left of the 50 columns loses its
+    // start.
+    assert_eq!(table(false), "\
+        #  copies      size  reclaimable  keeper\n\
+        1       2  2.00 KiB     2.00 KiB  …ch/IMG_0001.jpg\n\
+        2       3  10 bytes     20 bytes  a\n");
+    assert_eq!(table(true), "\
+        #  copies      size  reclaimable  keeper\n\
+        1       2  2.00 KiB     2.00 KiB  …ch/IMG_0001.jpg\n\
+        \x20                                 …up/IMG_0001.jpg\n\
+        2       3  10 bytes     20 bytes  a\n\
+        \x20                                 b\n\
+        \x20                                 c\n");
+    for line in table(true).lines() {
+        assert!(line.chars().count() <= 50, "{line}");
+    }
+}
@@ -0,0 +1,558 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests of finding duplicates through the library, from the walk to the
+//! finished groups.
+
+mod common;
+
+use std::{path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Instant};
+
+use drupes::{discard_unique_sizes, find_duplicates, pass_three, pass_two, walk, DuplicateGroup, HashAlgorithm, HashCache, ScanOptions};
+
+use common::{names, relative, tree};
+
+/// The paths in each group, relative to `dir`, in the order they're given.
+fn group_names(dir: &Path, groups: &[DuplicateGroup]) -> Vec<Vec<String>> {
+    groups.iter()
+        .map(|group| group.paths.iter().map(|f| relative(dir, f)).collect())
+        .collect()
+}
+
+#[test]
+fn hardlinks_are_walked_and_collapsed_in_groups() {
+    let dir = tree(&[("a", b"linked contents"), ("copy", b"linked contents")]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let root = vec![dir.path().to_owned()];
+
+    // The walk sees every name, whatever the options.
+    let paths = walk(&root, &ScanOptions::new(), Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a", "b", "copy"]);
+
+    let groups = find_duplicates(root.clone(), &ScanOptions::new()).unwrap();
+    if cfg!(unix) {
+        assert_eq!(group_names(dir.path(), &groups), [["a", "copy"]]);
+    }
+
+    let opts = ScanOptions::new().keep_hardlinks(true);
+    let groups = find_duplicates(root, &opts).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b", "copy"]]);
+}
+
+#[test]
+fn hardlinks_alone_are_not_duplicates() {
+    let dir = tree(&[("a", b"only one file")]);
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    let groups = find_duplicates(vec![dir.path().to_owned()], &ScanOptions::new()).unwrap();
+    if cfg!(unix) {
+        assert!(groups.is_empty());
+    }
+}
+
+#[test]
+fn scan_options_change_what_is_found() {
+    let dir = tree(&[
+        ("empty1", b""),
+        ("empty2", b""),
+        ("a.txt", b"text"),
+        ("b.txt", b"text"),
+        ("a.log", b"logged"),
+        ("b.log", b"logged"),
+        ("deep/er/a.dat", b"buried!"),
+        ("b.dat", b"buried!"),
+        (".hidden", b"secret"),
+        ("shown", b"secret"),
+    ]);
+    let found = |opts: ScanOptions| {
+        let groups = find_duplicates(vec![dir.path().to_owned()], &opts).unwrap();
+        group_names(dir.path(), &groups)
+    };
+
+    let default = found(ScanOptions::new());
+    assert_eq!(default, [
+        vec!["a.log", "b.log"],
+        vec!["a.txt", "b.txt"],
+        vec!["b.dat", "deep/er/a.dat"],
+    ]);
+    assert!(found(ScanOptions::new().include_empty(true)).contains(&vec![
+        "empty1".to_string(), "empty2".to_string(),
+    ]));
+    assert_eq!(found(ScanOptions::new().include_ext("txt")), [["a.txt", "b.txt"]]);
+    assert_eq!(found(ScanOptions::new().exclude_ext("log").exclude_ext("dat")),
+        [["a.txt", "b.txt"]]);
+    assert_eq!(found(ScanOptions::new().exclude_size(4).exclude_size(6)),
+        [["b.dat", "deep/er/a.dat"]]);
+    assert_eq!(found(ScanOptions::new().max_depth(Some(2))).len(), 2);
+    assert!(found(ScanOptions::new().include_hidden(true)).contains(&vec![
+        ".hidden".to_string(), "shown".to_string(),
+    ]));
+    // These change how files are read, not which ones match.
+    assert_eq!(found(ScanOptions::new().prehash_size(0)), default);
+    assert_eq!(found(ScanOptions::new().prehash_size(1)), default);
+    assert_eq!(found(ScanOptions::new().mmap(false)), default);
+}
+
+#[test]
+fn no_prehash_gives_plain_hashes() {
+    let big = vec![b'b'; 100_000];
+    let dir = tree(&[("a", b"small"), ("b", b"small"), ("c", &big), ("d", &big), ("e", b""), ("f", b"")]);
+    let root = vec![dir.path().to_owned()];
+    let opts = ScanOptions::new().prehash_size(0).include_empty(true);
+    let groups = find_duplicates(root.clone(), &opts).unwrap();
+    let hashes: Vec<_> = groups.iter().map(|g| g.hash.to_hex()).collect();
+    // The same as `b3sum` would say, with nothing to key them with.
+    assert_eq!(hashes, [blake3::hash(b"small").to_hex().to_string(),
+        blake3::hash(&big).to_hex().to_string(), blake3::hash(b"").to_hex().to_string()]);
+    for group in &groups {
+        assert_eq!(group.hash, drupes::hash_file(&group.paths[0], HashAlgorithm::Blake3).unwrap());
+    }
+    // With a prehash, they're chained off of that instead.
+    let chained = find_duplicates(root, &ScanOptions::new().include_empty(true)).unwrap();
+    assert_eq!(group_names(dir.path(), &chained), group_names(dir.path(), &groups));
+    assert_ne!(chained[0].hash, groups[0].hash);
+}
+
+#[test]
+fn large_prehash_tells_apart_files_with_a_long_common_start() {
+    let mut same = vec![b'x'; 20_000];
+    let mut different = same.clone();
+    different[15_000] = b'y';
+    same[19_999] = b'z';
+    different[19_999] = b'z';
+    let dir = tree(&[("a", &same), ("b", &same), ("c", &different)]);
+    let root = vec![dir.path().to_owned()];
+
+    let prehash_groups = |opts: &ScanOptions| {
+        let paths = drupes::pass_one(&root, opts, Instant::now(), |_| ()).unwrap();
+        let mut sizes: Vec<usize> = drupes::pass_two(&paths, opts, Instant::now(), |_| ())
+            .values()
+            .map(Vec::len)
+            .collect();
+        sizes.sort();
+        sizes
+    };
+    // The default prehash only sees the part they have in common...
+    assert_eq!(prehash_groups(&ScanOptions::new()), [3]);
+    // ...but one that reaches past where they differ splits them up already.
+    let opts = ScanOptions::new().prehash_size(16 * 1024);
+    assert_eq!(prehash_groups(&opts), [1, 2]);
+
+    // Either way, the duplicates found are the same.
+    for opts in [ScanOptions::new(), opts, ScanOptions::new().prehash_size(1 << 20)] {
+        let groups = find_duplicates(root.clone(), &opts).unwrap();
+        assert_eq!(group_names(dir.path(), &groups), [["a", "b"]]);
+    }
+}
+
+#[test]
+fn sizes_are_carried_through_rather_than_looked_up_again() {
+    let dir = tree(&[("a", &[1; 1000]), ("b", &[1; 1000]), ("c", &[1; 1000])]);
+    let root = vec![dir.path().to_owned()];
+    let opts = ScanOptions::new();
+    let start = Instant::now();
+    let paths = drupes::pass_one(&root, &opts, start, |_| ()).unwrap();
+    let prehashed = drupes::pass_two(&paths, &opts, start, |_| ());
+    let prehash_groups = prehashed.len();
+    let hashed = drupes::pass_three(prehashed, &opts, start, |_| ());
+
+    // Shrink one of them after it's been hashed, but before the summary.
+    std::fs::File::options().write(true).open(dir.path().join("b")).unwrap()
+        .set_len(10).unwrap();
+    let stats = drupes::summarize(&paths, prehash_groups,
+        hashed.iter().map(|((size, _), files)| (*size, files.len())));
+    assert_eq!((stats.set_count, stats.dupe_count, stats.reclaimable_bytes), (1, 2, 2000));
+}
+
+#[test]
+fn every_hash_algorithm_finds_the_same_duplicates() {
+    let big = vec![3; 100_000];
+    let mut big_other = big.clone();
+    big_other[99_999] = 4;
+    let dir = tree(&[("a", b"small"), ("b", b"small"), ("c", b"smalL"),
+        ("d", &big), ("e", &big), ("f", &big_other)]);
+    for name in drupes::HashAlgorithm::NAMES {
+        let algorithm: drupes::HashAlgorithm = name.parse().unwrap();
+        let opts = ScanOptions::new().hash_algorithm(algorithm);
+        let groups = find_duplicates(vec![dir.path().to_owned()], &opts).unwrap();
+        assert_eq!(group_names(dir.path(), &groups), [["a", "b"], ["d", "e"]], "{name}");
+    }
+}
+
+#[test]
+fn mmap_and_read_give_the_same_hashes() {
+    let len = drupes::MMAP_THRESHOLD as usize * 3 + 12_345;
+    let contents: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+    let dir = tree(&[("a", &contents), ("b", &contents), ("small1", b"tiny"),
+        ("small2", b"tiny")]);
+    let root = vec![dir.path().to_owned()];
+    for algorithm in drupes::HashAlgorithm::NAMES {
+        let opts = ScanOptions::new().hash_algorithm(algorithm.parse().unwrap());
+        let mapped = find_duplicates(root.clone(), &opts.clone().mmap(true)).unwrap();
+        let read = find_duplicates(root.clone(), &opts.mmap(false)).unwrap();
+        assert_eq!(group_names(dir.path(), &mapped), [["a", "b"], ["small1", "small2"]]);
+        let hashes = |groups: &[DuplicateGroup]| {
+            groups.iter().map(|group| group.hash).collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(&mapped), hashes(&read), "{algorithm}");
+    }
+}
+
+#[test]
+fn one_thread_finds_the_same_groups() {
+    let big = vec![9; 200_000];
+    let dir = tree(&[("a", b"dupe"), ("b", b"dupe"), ("c", &big), ("d/e", &big),
+        ("f", b"unique")]);
+    let root = vec![dir.path().to_owned()];
+    let scanner = drupes::Scanner::new(ScanOptions::new()).threads(1).unwrap();
+    let groups = scanner.scan(&root).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b"], ["c", "d/e"]]);
+    let everywhere = find_duplicates(root, &ScanOptions::new()).unwrap();
+    assert_eq!(groups.iter().map(|g| g.hash).collect::<Vec<_>>(),
+        everywhere.iter().map(|g| g.hash).collect::<Vec<_>>());
+}
+
+#[test]
+fn one_scanner_scans_again_and_again() {
+    let first = tree(&[("a", b"one pair"), ("b", b"one pair"), ("c", b"on its own")]);
+    let second = tree(&[("x", b"another pair"), ("sub/y", b"another pair"),
+        ("p", b"and a third"), ("q", b"and a third"), ("r", b"and a third")]);
+    let scanner = drupes::Scanner::new(ScanOptions::new().min_size(2)).threads(2).unwrap();
+
+    let groups = scanner.scan(&[first.path().to_owned()]).unwrap();
+    assert_eq!(group_names(first.path(), &groups), [["a", "b"]]);
+    // Nothing from the first scan is carried over into the second.
+    let groups = scanner.scan(&[second.path().to_owned()]).unwrap();
+    assert_eq!(group_names(second.path(), &groups), [vec!["p", "q", "r"], vec!["sub/y", "x"]]);
+
+    // And a scan of the first again sees what's changed since.
+    std::fs::write(first.path().join("c"), "one pair").unwrap();
+    let groups = scanner.scan(&[first.path().to_owned()]).unwrap();
+    assert_eq!(group_names(first.path(), &groups), [["a", "b", "c"]]);
+}
+
+#[test]
+fn scan_iter_finds_what_scan_does() {
+    let big = vec![4; 100_000];
+    let mut late = big.clone();
+    *late.last_mut().unwrap() = 5;
+    let dir = tree(&[("a", b"dupe"), ("b", b"dupe"), ("sub/c", b"dupe"), ("d", &big),
+        ("e", &big), ("f", &late), ("g", &late), ("h", b"duPe"), ("i", b"unique")]);
+    let root = vec![dir.path().to_owned()];
+    // Groups come out of the iterator in any order, so they're sorted the way
+    // `scan` sorts them before comparing.
+    let key = |group: &DuplicateGroup| (group.paths.clone(), group.size, group.hash);
+    for scanner in [drupes::Scanner::new(ScanOptions::new()),
+        drupes::Scanner::new(ScanOptions::new()).threads(1).unwrap()]
+    {
+        let scanned: Vec<_> = scanner.scan(&root).unwrap().iter().map(key).collect();
+        let mut streamed: Vec<_> = scanner.scan_iter(&root).unwrap().map(|g| key(&g)).collect();
+        streamed.sort();
+        assert_eq!(streamed, scanned);
+        assert_eq!(scanned.len(), 3);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn sparse_files_hash_like_the_zeros_they_read_as() {
+    let len = 8 * 1024 * 1024;
+    let dir = tree(&[]);
+    let sparse = |name: &str| {
+        let f = std::fs::File::create(dir.path().join(name)).unwrap();
+        f.set_len(len).unwrap();
+        f
+    };
+    sparse("a");
+    sparse("b");
+    // The same length, but with something at the very end, past the
+    // prehash.
+    let c = sparse("c");
+    std::os::unix::fs::FileExt::write_at(&c, b"!", len - 1).unwrap();
+    // And a file that's really full of zeros.
+    common::write(dir.path(), "dense", &vec![0; len as usize]);
+
+    let meta = std::fs::metadata(dir.path().join("a")).unwrap();
+    if drupes::is_sparse(&meta) {
+        assert!(!drupes::is_sparse(&std::fs::metadata(dir.path().join("dense")).unwrap()));
+    } else {
+        eprintln!("note: this filesystem doesn't make sparse files");
+    }
+
+    let zeros = blake3::hash(&vec![0; len as usize]);
+    let hash = drupes::hash_file(&dir.path().join("a"), HashAlgorithm::Blake3).unwrap();
+    assert_eq!(hash.to_hex(), zeros.to_hex().as_str());
+
+    let groups = find_duplicates(vec![dir.path().to_owned()], &ScanOptions::new()).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b", "dense"]]);
+}
+
+#[cfg(windows)]
+#[test]
+fn paths_longer_than_max_path_are_scanned() {
+    let dir = tree(&[("short", b"deep down")]);
+    let deep: PathBuf = std::iter::repeat("a directory with a long name").take(12).collect();
+    let deep = dir.path().join(deep);
+    assert!(deep.as_os_str().len() > 260);
+    common::write(&deep, "copy", b"deep down");
+    common::write(&deep, "other", b"deep down");
+
+    let groups = find_duplicates(vec![dir.path().to_owned()], &ScanOptions::new()).unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].paths.len(), 3);
+    assert!(drupes::verify_group(&groups[0].paths.iter().map(PathBuf::as_path).collect::<Vec<_>>())
+        .unwrap());
+}
+
+#[test]
+fn files_that_vanish_before_hashing_are_skipped_quietly() {
+    // What's printed can only be seen from outside, so this runs itself again
+    // with its standard error captured.
+    const CHILD: &str = "DRUPES_TEST_VANISHING_CHILD";
+    if std::env::var_os(CHILD).is_none() {
+        let out = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "files_that_vanish_before_hashing_are_skipped_quietly", "--nocapture"])
+            .env(CHILD, "1")
+            .output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+        assert_eq!(String::from_utf8(out.stderr).unwrap(), "");
+        return;
+    }
+
+    let big = vec![b'b'; 10_000];
+    let dir = tree(&[("a", b"same"), ("b", b"same"), ("c", b"same"), ("d", &big), ("e", &big),
+        ("f", &big)]);
+    let opts = ScanOptions::new();
+    // Each phase counts its own errors, going up as it goes.
+    let errors = [AtomicU64::new(0), AtomicU64::new(0)];
+    let mut paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |_| ()).unwrap();
+    discard_unique_sizes(&mut paths, &opts);
+
+    // One goes before the prehash, and another before the full hash.
+    std::fs::remove_file(dir.path().join("b")).unwrap();
+    let prehashed = pass_two(&paths, &opts, Instant::now(), |p| {
+        errors[0].fetch_max(p.errors, Ordering::Relaxed);
+    });
+    std::fs::remove_file(dir.path().join("f")).unwrap();
+    let hashed = pass_three(prehashed, &opts, Instant::now(), |p| {
+        errors[1].fetch_max(p.errors, Ordering::Relaxed);
+    });
+    assert_eq!(errors.map(AtomicU64::into_inner), [0, 0]);
+
+    let mut groups: Vec<Vec<String>> = hashed.into_values()
+        .filter(|files| files.len() > 1)
+        .map(|files| {
+            let mut names: Vec<String> = files.iter().map(|f| relative(dir.path(), f)).collect();
+            names.sort();
+            names
+        })
+        .collect();
+    groups.sort();
+    assert_eq!(groups, [["a", "c"], ["d", "e"]]);
+}
+
+#[test]
+fn cache_saves_reading_unchanged_files() {
+    let same = [b'x'; 100];
+    let mut similar = [b'x'; 100];
+    similar[99] = b'y';
+    // `c` starts the same as `a` and `b`, so it's only told apart by reading
+    // the whole thing, but `d` is told apart by just the start.
+    let dir = tree(&[("a", &same), ("b", &same), ("c", &similar), ("d", &[b'z'; 100]),
+        ("e", b"unlike the rest by size")]);
+    for name in ["a", "b", "c", "d", "e"] {
+        common::set_mtime(&dir.path().join(name), 1_000_000_000);
+    }
+    let root = vec![dir.path().to_owned()];
+
+    // Everything read, in either pass, relative to `dir` and without repeats.
+    let reads = Arc::new(Mutex::new(Vec::<PathBuf>::new()));
+    let read = || {
+        let mut names: Vec<String> = reads.lock().unwrap().drain(..)
+            .map(|f| relative(dir.path(), &f))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    };
+    let cache = Arc::new(HashCache::new(HashAlgorithm::Blake3, 4));
+    let opts = ScanOptions::new()
+        .prehash_size(4)
+        .cache(Some(Arc::clone(&cache)))
+        .on_read({
+            let reads = Arc::clone(&reads);
+            move |f| reads.lock().unwrap().push(f.to_owned())
+        });
+
+    let groups = find_duplicates(root.clone(), &opts).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b"]]);
+    // The file with a size all its own never needed reading.
+    assert_eq!(read(), ["a", "b", "c", "d"]);
+
+    let groups = find_duplicates(root.clone(), &opts).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b"]]);
+    assert_eq!(read(), Vec::<String>::new());
+
+    // A file that's changed is read again, and only that file.
+    common::write(dir.path(), "c", &same);
+    common::set_mtime(&dir.path().join("c"), 1_000_000_001);
+    let groups = find_duplicates(root.clone(), &opts).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b", "c"]]);
+    assert_eq!(read(), ["c"]);
+
+    // The same goes for a cache that's been saved and loaded again.
+    let saved = tempfile::tempdir().unwrap();
+    let saved = saved.path().join("cache.json");
+    cache.save(&saved).unwrap();
+    let loaded = HashCache::load(&saved, HashAlgorithm::Blake3, 4).unwrap();
+    let opts = opts.cache(Some(Arc::new(loaded)));
+    let groups = find_duplicates(root.clone(), &opts).unwrap();
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b", "c"]]);
+    assert_eq!(read(), Vec::<String>::new());
+
+    // A cache saved with different settings doesn't count.
+    let stale = HashCache::load(&saved, HashAlgorithm::Blake3, 8).unwrap();
+    let opts = opts.prehash_size(8).cache(Some(Arc::new(stale)));
+    find_duplicates(root, &opts).unwrap();
+    assert_eq!(read(), ["a", "b", "c", "d"]);
+}
+
+#[test]
+fn pass_three_each_finds_what_pass_three_does() {
+    let mut similar = vec![b'x'; 10_000];
+    let dir = tree(&[("a", &similar), ("b", &similar), ("c", b"short"),
+        ("d", b"short"), ("e", b"other"), ("lonely", b"no match at all")]);
+    similar[9_999] = b'y';
+    common::write(dir.path(), "f", &similar);
+    let opts = ScanOptions::new();
+    let start = Instant::now();
+    let paths = walk(&[dir.path().to_owned()], &opts, start, |_| ()).unwrap();
+
+    let all = drupes::pass_three(drupes::pass_two(&paths, &opts, start, |_| ()), &opts, start,
+        |_| ());
+    let mut expected: Vec<_> = all.into_iter()
+        .map(|(key, mut files)| {
+            files.sort();
+            (key, files)
+        })
+        .collect();
+    expected.sort();
+
+    let found = Mutex::new(vec![]);
+    drupes::pass_three_each(drupes::pass_two(&paths, &opts, start, |_| ()), &opts, start,
+        |_| (), |key, mut files| {
+            files.sort();
+            found.lock().unwrap().push((key, files));
+            Ok::<_, ()>(())
+        }).unwrap();
+    let mut found = found.into_inner().unwrap();
+    found.sort();
+    assert_eq!(found, expected);
+    // `f` starts the same as `a` and `b`, so it gets this far, but `e` and
+    // `lonely` don't.
+    let mut names: Vec<Vec<String>> = found.iter()
+        .map(|(_, files)| files.iter().map(|f| relative(dir.path(), f)).collect())
+        .collect();
+    names.sort();
+    assert_eq!(names, [vec!["a", "b"], vec!["c", "d"], vec!["f"]]);
+
+    // An error stops it, and is handed back.
+    let result = drupes::pass_three_each(drupes::pass_two(&paths, &opts, start, |_| ()), &opts,
+        start, |_| (), |_, _| Err("stop"));
+    assert_eq!(result, Err("stop"));
+}
+
+#[test]
+fn owned_passes_match_borrowed_ones() {
+    let mut long = vec![b'l'; 9_000];
+    let dir = tree(&[("a", &long), ("sub/a", &long), ("b", b"short"), ("c", b"short"),
+        ("d", b"shorT"), ("e", b"something unique"), ("empty", b"")]);
+    long[8_999] = b'!';
+    common::write(dir.path(), "f", &long);
+    // Small prehashes, for some variety in which pass tells files apart.
+    for opts in [ScanOptions::new(), ScanOptions::new().prehash_size(2),
+        ScanOptions::new().include_empty(true).hash_algorithm(HashAlgorithm::Sha256)]
+    {
+        let start = Instant::now();
+        let paths = walk(&[dir.path().to_owned()], &opts, start, |_| ()).unwrap();
+
+        let sorted = |map: std::collections::HashMap<_, Vec<PathBuf>>| {
+            let mut groups: Vec<_> = map.into_iter()
+                .map(|(key, mut files)| {
+                    files.sort();
+                    (key, files)
+                })
+                .collect();
+            groups.sort();
+            groups
+        };
+        let prehashed = drupes::pass_two(&paths, &opts, start, |_| ());
+        let owned_prehashed = drupes::pass_two_owned(paths.clone(), &opts, start, |_| ());
+        let borrowed_prehashed = prehashed.iter()
+            .map(|(key, files)| (*key, files.iter().map(|f| f.to_path_buf()).collect()))
+            .collect();
+        assert_eq!(sorted(owned_prehashed.clone()), sorted(borrowed_prehashed), "{opts:?}");
+
+        let borrowed = drupes::pass_three(prehashed, &opts, start, |_| ()).into_iter()
+            .map(|(key, files)| (key, files.into_iter().map(Path::to_path_buf).collect()))
+            .collect();
+        let owned = sorted(drupes::pass_three(owned_prehashed, &opts, start, |_| ()));
+        assert!(owned.iter().any(|(_, files)| files.len() > 1), "{opts:?}");
+        assert_eq!(owned, sorted(borrowed), "{opts:?}");
+    }
+}
+
+#[test]
+fn updating_an_index_changes_only_the_affected_groups() {
+    let dir = tree(&[("a", b"pair one"), ("b", b"pair one"), ("c", b"pair two"), ("d", b"pair two"),
+        ("sub/e", b"a third pair"), ("sub/f", b"a third pair")]);
+    let root = vec![dir.path().to_owned()];
+    let scanner = drupes::Scanner::new(ScanOptions::new());
+    let mut index = scanner.index(&root).unwrap();
+    let groups = scanner.groups(&index);
+    assert_eq!(group_names(dir.path(), &groups), [["a", "b"], ["c", "d"], ["sub/e", "sub/f"]]);
+    let third = &groups[2];
+
+    // `c` becomes a copy of `a` instead, which breaks up the pair it was in.
+    let c = dir.path().join("c");
+    std::fs::write(&c, b"pair one").unwrap();
+    common::set_mtime(&c, 1_000_000);
+    scanner.update(&mut index, &[c]).unwrap();
+    let updated = scanner.groups(&index);
+    assert_eq!(group_names(dir.path(), &updated), [vec!["a", "b", "c"], vec!["sub/e", "sub/f"]]);
+    assert_eq!(updated[0].hash, groups[0].hash);
+    // The group that had nothing to do with it is just as it was.
+    assert_eq!((updated[1].size, updated[1].hash, &updated[1].paths), (third.size, third.hash, &third.paths));
+    // Which is what a scan from scratch would find.
+    let scanned = scanner.scan(&root).unwrap();
+    assert_eq!(group_names(dir.path(), &scanned), group_names(dir.path(), &updated));
+
+    // Removing a file takes it out of its group, and a new directory's files
+    // are added.
+    std::fs::remove_file(dir.path().join("b")).unwrap();
+    common::write(dir.path(), "new/g", b"pair two");
+    scanner.update(&mut index, &[dir.path().join("b"), dir.path().join("new")]).unwrap();
+    assert_eq!(group_names(dir.path(), &scanner.groups(&index)),
+        [vec!["a", "c"], vec!["d", "new/g"], vec!["sub/e", "sub/f"]]);
+}
+
+#[test]
+fn updating_an_index_anchors_excludes_at_the_root() {
+    let dir = tree(&[("keep", b"a copy"), ("sub/skip/b", b"a copy"), ("skip/c", b"a copy")]);
+    let patterns = tree(&[("excludes", b"/skip\n")]);
+    let root = vec![dir.path().to_owned()];
+    let scanner = drupes::Scanner::new(ScanOptions::new()
+        .exclude_from(patterns.path().join("excludes")));
+    let mut index = scanner.index(&root).unwrap();
+    // Only the `skip` at the top of the root is left out.
+    let expected = [["keep", "sub/skip/b"]];
+    assert_eq!(group_names(dir.path(), &scanner.groups(&index)), expected);
+
+    // The pattern doesn't start matching from wherever the change was, so
+    // `sub/skip` is still searched, and `skip` still isn't.
+    scanner.update(&mut index, &[dir.path().join("sub")]).unwrap();
+    assert_eq!(group_names(dir.path(), &scanner.groups(&index)), expected);
+    scanner.update(&mut index, &[dir.path().join("skip/c"), dir.path().join("skip")]).unwrap();
+    assert_eq!(group_names(dir.path(), &scanner.groups(&index)), expected);
+    assert_eq!(group_names(dir.path(), &scanner.scan(&root).unwrap()), expected);
+}
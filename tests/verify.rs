@@ -0,0 +1,150 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests of comparing files byte-for-byte.
+
+mod common;
+
+use std::{cell::RefCell, path::PathBuf};
+
+use drupes::{first_mismatch, first_mismatch_reporting, first_mismatch_sampled, first_mismatch_with_progress, verify_group, Phase, Progress};
+
+use common::tree;
+
+#[test]
+fn verifying_reports_each_file() {
+    let dir = tree(&[("a", b"same bytes"), ("b", b"same bytes"), ("c", b"same bytes"),
+        ("d", b"same bytes")]);
+    let paths: Vec<PathBuf> = ["a", "b", "c", "d"].iter().map(|f| dir.path().join(f)).collect();
+    let files: Vec<_> = paths.iter().map(PathBuf::as_path).collect();
+
+    let reports: RefCell<Vec<Progress>> = RefCell::new(vec![]);
+    let mismatch = first_mismatch_reporting(&files, None, |p| reports.borrow_mut().push(p))
+        .unwrap();
+    assert_eq!(mismatch, None);
+    let reports = reports.into_inner();
+    assert_eq!(reports.len(), 4);
+    assert!(reports.iter().all(|p| p.phase == Phase::Verifying && p.errors == 0));
+    assert_eq!(reports.iter().map(|p| p.files).collect::<Vec<_>>(), [1, 2, 3, 4]);
+    assert_eq!(reports[3].bytes_hashed, 4 * 10);
+}
+
+#[test]
+fn verifying_reports_a_mismatch() {
+    let dir = tree(&[("a", b"same bytes"), ("b", b"diff bytes"), ("c", b"same bytes")]);
+    let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(|f| dir.path().join(f)).collect();
+    let files: Vec<_> = paths.iter().map(PathBuf::as_path).collect();
+
+    let reports: RefCell<Vec<Progress>> = RefCell::new(vec![]);
+    let mismatch = first_mismatch_reporting(&files, None, |p| reports.borrow_mut().push(p))
+        .unwrap();
+    assert_eq!(mismatch, Some(files[1]));
+    let reports = reports.into_inner();
+    assert_eq!(reports.len(), 1);
+    assert_eq!((reports[0].phase, reports[0].files), (Phase::Verifying, 0));
+    assert!(reports[0].bytes_hashed > 0);
+}
+
+#[test]
+fn matching_files_verify() {
+    // Bigger than one chunk, so that the comparison goes round more than once.
+    let contents = vec![0x5a; 200 * 1024];
+    let dir = tree(&[("a", &contents), ("b", &contents), ("c", &contents)]);
+    let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(|f| dir.path().join(f)).collect();
+    let files: Vec<_> = paths.iter().map(PathBuf::as_path).collect();
+    assert!(verify_group(&files).unwrap());
+    assert_eq!(first_mismatch(&files).unwrap(), None);
+}
+
+#[test]
+fn mismatched_files_are_caught() {
+    let contents = vec![0x5a; 200 * 1024];
+    let mut late = contents.clone();
+    *late.last_mut().unwrap() = 0;
+    let dir = tree(&[("a", &contents), ("b", &contents), ("late", &late),
+        ("short", &contents[1..])]);
+    let path = |f: &str| dir.path().join(f);
+    let (a, b, late, short) = (path("a"), path("b"), path("late"), path("short"));
+
+    assert_eq!(first_mismatch(&[&a, &b, &late]).unwrap(), Some(late.as_path()));
+    assert!(!verify_group(&[&a, &late]).unwrap());
+    // A different length is a mismatch, without needing to read anything.
+    assert_eq!(first_mismatch(&[&a, &short, &b]).unwrap(), Some(short.as_path()));
+    // A file that can't be opened is an error, not a mismatch.
+    assert!(first_mismatch(&[&a, &path("missing")]).is_err());
+}
+
+#[test]
+fn only_the_last_byte_differs() {
+    // Around the edges of the 64 KiB chunks that files are compared in.
+    for len in [1, 64 * 1024 - 1, 64 * 1024, 64 * 1024 + 1, 3 * 64 * 1024] {
+        let contents = vec![b'q'; len];
+        let mut last = contents.clone();
+        last[len - 1] = b'r';
+        let dir = tree(&[("a", &contents), ("b", &contents), ("c", &last)]);
+        let (a, b, c) = (dir.path().join("a"), dir.path().join("b"), dir.path().join("c"));
+        assert!(verify_group(&[&a, &b]).unwrap(), "{len} bytes");
+        assert_eq!(first_mismatch(&[&a, &b, &c]).unwrap(), Some(c.as_path()), "{len} bytes");
+        assert_eq!(first_mismatch(&[&c, &a]).unwrap(), Some(a.as_path()), "{len} bytes");
+    }
+}
+
+#[test]
+fn third_of_four_differs_mid_stream() {
+    let len = 300 * 1024;
+    let contents = vec![b'm'; len];
+    let mut middle = contents.clone();
+    middle[len / 2] = b'n';
+    let dir = tree(&[("1", &contents), ("2", &contents), ("3", &middle), ("4", &contents)]);
+    let paths: Vec<PathBuf> = ["1", "2", "3", "4"].iter().map(|f| dir.path().join(f)).collect();
+    let files: Vec<_> = paths.iter().map(PathBuf::as_path).collect();
+
+    let read = std::cell::Cell::new(0);
+    let mismatch = first_mismatch_with_progress(&files, |n| read.set(read.get() + n)).unwrap();
+    assert_eq!(mismatch, Some(files[2]));
+    // All four are read in lockstep, and that stops where the third differs,
+    // rather than going on to the end of any of them.
+    assert!(read.get() > 0 && read.get() < 4 * len as u64, "read {}", read.get());
+    assert!(read.get() <= 4 * (len as u64 / 2 + 64 * 1024), "read {}", read.get());
+}
+
+#[test]
+fn sampling_only_catches_differences_where_it_looks() {
+    // Ten 64 KiB chunks. 20% of that is the first and last chunks, and 30%
+    // adds one in the middle, the fifth.
+    let chunk = 64 * 1024;
+    let contents = vec![b's'; 10 * chunk];
+    let differ_at = |offset: usize| {
+        let mut changed = contents.clone();
+        changed[offset] = b't';
+        changed
+    };
+    let dir = tree(&[("a", &contents), ("b", &contents),
+        ("start", &differ_at(10)), ("end", &differ_at(10 * chunk - 1)),
+        ("middle", &differ_at(4 * chunk + 10)), ("third", &differ_at(2 * chunk)),
+        ("short", &contents[1..])]);
+    let path = |f: &str| dir.path().join(f);
+    let a = path("a");
+    let caught = |other: &str, percent: f64| {
+        let other = path(other);
+        first_mismatch_sampled(&[&a, &other], percent, |_| ()).unwrap().is_some()
+    };
+
+    assert!(!caught("b", 20.0));
+    assert!(caught("start", 20.0));
+    assert!(caught("end", 20.0));
+    assert!(caught("short", 20.0));
+    // This is documented: a difference outside the chunks compared is missed.
+    assert!(!caught("middle", 20.0));
+    assert!(caught("middle", 30.0));
+    assert!(!caught("third", 30.0));
+    // Comparing everything catches all of them.
+    assert!(caught("third", 100.0));
+    assert_eq!(first_mismatch(&[&a, &path("third")]).unwrap(), Some(path("third").as_path()));
+
+    // Only the sampled chunks are read, from both files.
+    let read = std::cell::Cell::new(0);
+    first_mismatch_sampled(&[&a, &path("b")], 20.0, |n| read.set(read.get() + n)).unwrap();
+    assert_eq!(read.get(), 2 * 2 * chunk as u64);
+}
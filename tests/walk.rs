@@ -0,0 +1,309 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tests of which files the walk turns up, given various options.
+
+mod common;
+
+use std::time::Instant;
+
+use drupes::{walk, ScanOptions};
+
+use common::{names, tree, walked};
+
+#[test]
+fn min_size_leaves_out_smaller_files() {
+    let dir = tree(&[
+        ("tiny", b"a"),
+        ("small", b"abcd"),
+        ("exact", b"abcdefgh"),
+        ("big", b"abcdefghijklmnop"),
+        ("sub/big", b"ponmlkjihgfedcba"),
+    ]);
+    let opts = ScanOptions::new().min_size(8);
+    let paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |_| ()).unwrap();
+    // The threshold itself is included.
+    assert_eq!(walked(dir.path(), &paths), [
+        (8, "exact".to_string()),
+        (16, "big".to_string()),
+        (16, "sub/big".to_string()),
+    ]);
+    assert!(!paths.contains_key(&1) && !paths.contains_key(&4));
+}
+
+#[test]
+fn max_size_and_min_size_make_a_window() {
+    let dir = tree(&[
+        ("one", b"a"),
+        ("four", b"abcd"),
+        ("six", b"abcdef"),
+        ("eight", b"abcdefgh"),
+        ("nine", b"abcdefghi"),
+    ]);
+    let root = [dir.path().to_owned()];
+
+    let opts = ScanOptions::new().max_size(Some(6));
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(walked(dir.path(), &paths), [
+        (1, "one".to_string()),
+        (4, "four".to_string()),
+        (6, "six".to_string()),
+    ]);
+
+    // Both ends are included.
+    let opts = ScanOptions::new().min_size(4).max_size(Some(8));
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(walked(dir.path(), &paths), [
+        (4, "four".to_string()),
+        (6, "six".to_string()),
+        (8, "eight".to_string()),
+    ]);
+}
+
+#[test]
+fn exclude_patterns_match_names_and_paths() {
+    let dir = tree(&[
+        ("keep.txt", b"kept"),
+        ("scratch.tmp", b"left out by name"),
+        ("sub/deeper/more.tmp", b"left out by name"),
+        ("sub/kept.txt", b"kept too"),
+        ("node_modules/pkg/index.js", b"left out by path"),
+        ("app/node_modules/pkg/index.js", b"left out by path"),
+        ("app/main.js", b"kept as well"),
+    ]);
+    let opts = ScanOptions::new()
+        .exclude("*.tmp")
+        .exclude("**/node_modules/**");
+    let paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["app/main.js", "keep.txt", "sub/kept.txt"]);
+}
+
+#[test]
+fn excluded_directory_leaves_nothing_behind() {
+    let dir = tree(&[("keep/a", b"same"), ("keep/b", b"same")]);
+    for i in 0..200 {
+        common::write(dir.path(), &format!("big/{}/file{i}", i % 10), b"same");
+    }
+    let opts = ScanOptions::new().exclude("big");
+    let seen = std::cell::Cell::new(0);
+    let paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |p| {
+        seen.set(p.files);
+    }).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["keep/a", "keep/b"]);
+    // Nothing under it was even looked at.
+    assert_eq!(seen.get(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn following_a_link_to_an_ancestor_terminates() {
+    let dir = tree(&[("a/f", b"same"), ("a/b/g", b"same")]);
+    std::os::unix::fs::symlink("..", dir.path().join("a/b/up")).unwrap();
+    std::os::unix::fs::symlink(dir.path(), dir.path().join("a/top")).unwrap();
+    let opts = ScanOptions::new().follow_symlinks(true);
+    let paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a/b/g", "a/f"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn fifos_are_left_alone() {
+    let dir = tree(&[("a", b"same"), ("sub/b", b"same"), ("e1", b""), ("e2", b"")]);
+    let made = std::process::Command::new("mkfifo").arg(dir.path().join("sub/pipe")).status();
+    if !made.is_ok_and(|status| status.success()) {
+        eprintln!("skipping: couldn't make a FIFO");
+        return;
+    }
+    let errors = std::cell::Cell::new(0);
+    // A FIFO has no size, so it'd be filed with the empty files if anywhere.
+    let opts = ScanOptions::new().include_empty(true);
+    let paths = walk(&[dir.path().to_owned()], &opts, Instant::now(), |p| errors.set(p.errors)).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a", "e1", "e2", "sub/b"]);
+    assert_eq!(errors.get(), 0);
+    // Nothing ever opens it, which would wait forever for a writer.
+    let out = common::run(dir.path(), &["--empty", "."]);
+    assert_eq!(common::groups(&out), [
+        vec!["./a", "./sub/b"],
+        vec!["# 2 empty files, which only match because they're empty", "./e1", "./e2"],
+    ]);
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_directory_is_skipped_unless_strict() {
+    let dir = tree(&[("ok/a", b"fine"), ("locked/b", b"hidden away")]);
+    let locked = dir.path().join("locked");
+    if !common::lock(&locked) {
+        common::unlock(&locked, 0o755);
+        eprintln!("skipping: permissions don't keep us out (running as root?)");
+        return;
+    }
+    let root = [dir.path().to_owned()];
+    let errors = std::cell::Cell::new(0);
+    let paths = walk(&root, &ScanOptions::new(), Instant::now(), |p| errors.set(p.errors));
+    let strict = walk(&root, &ScanOptions::new().strict(true), Instant::now(), |_| ());
+    common::unlock(&locked, 0o755);
+
+    assert_eq!(names(dir.path(), &paths.unwrap()), ["ok/a"]);
+    assert_eq!(errors.get(), 1);
+    assert!(strict.is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn device_id_tells_filesystems_apart() {
+    let dir = tree(&[("a", b"one"), ("sub/b", b"two")]);
+    let dev = |path: &std::path::Path| {
+        drupes::device_id(&std::fs::metadata(path).unwrap()).unwrap()
+    };
+    assert_eq!(dev(&dir.path().join("a")), dev(&dir.path().join("sub/b")));
+    assert_eq!(dev(&dir.path().join("a")), dev(dir.path()));
+
+    // For a second filesystem, /dev/shm is usually a tmpfs we can write to.
+    let Ok(other) = tempfile::tempdir_in("/dev/shm") else {
+        eprintln!("skipping the rest: no /dev/shm to write to");
+        return;
+    };
+    if dev(other.path()) == dev(dir.path()) {
+        eprintln!("skipping the rest: /dev/shm is on the same filesystem");
+        return;
+    }
+    common::write(other.path(), "far", b"one");
+    std::os::unix::fs::symlink(other.path(), dir.path().join("elsewhere")).unwrap();
+    let root = [dir.path().to_owned()];
+
+    let opts = ScanOptions::new().follow_symlinks(true);
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a", "elsewhere/far", "sub/b"]);
+
+    let opts = opts.same_filesystem(true);
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a", "sub/b"]);
+}
+
+#[test]
+fn extension_filters_ignore_case() {
+    let dir = tree(&[("a.jpg", b"x"), ("b.JPG", b"x"), ("c.Jpg", b"x"), ("d.png", b"x"),
+        ("e.log", b"x"), ("README", b"x"), ("sub/f.jpeg", b"x")]);
+    let root = [dir.path().to_owned()];
+
+    let opts = ScanOptions::new().include_ext("jpg");
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a.jpg", "b.JPG", "c.Jpg"]);
+
+    let opts = ScanOptions::new().include_ext(".JPG").include_ext("png");
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a.jpg", "b.JPG", "c.Jpg", "d.png"]);
+
+    let opts = ScanOptions::new().exclude_ext("JPG").exclude_ext("log");
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["README", "d.png", "sub/f.jpeg"]);
+
+    // Excluding wins over including.
+    let opts = ScanOptions::new().include_ext("jpg").include_ext("png").exclude_ext("jpg");
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["d.png"]);
+}
+
+#[test]
+fn extension_filters_can_pick_out_files_without_one() {
+    let dir = tree(&[("README", b"x"), ("Makefile", b"x"), ("a.txt", b"x"), ("sub/LICENSE", b"x")]);
+    let root = [dir.path().to_owned()];
+
+    for none in ["", "."] {
+        let opts = ScanOptions::new().include_ext(none);
+        let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+        assert_eq!(names(dir.path(), &paths), ["Makefile", "README", "sub/LICENSE"]);
+
+        let opts = ScanOptions::new().exclude_ext(none);
+        let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+        assert_eq!(names(dir.path(), &paths), ["a.txt"]);
+    }
+}
+
+#[test]
+fn exclude_from_reads_gitignore_syntax() {
+    let dir = tree(&[
+        ("keep.txt", b"x"), ("debug.log", b"x"), ("sub/trace.log", b"x"),
+        ("important.log", b"x"), ("build/out.o", b"x"), ("src/build", b"x"),
+        ("top.txt", b"x"), ("sub/top.txt", b"x"), ("#notes", b"x"),
+    ]);
+    let lists = tempfile::tempdir().unwrap();
+    let list = lists.path().join("excludes");
+    std::fs::write(&list, "\
+        # Logs are noise, except for this one.\n\
+        *.log\n\
+        !important.log\n\
+        \n\
+        # Only the directory, not a file with the same name.\n\
+        build/\n\
+        # Only at the top.\n\
+        /top.txt\n\
+        \\#notes\n").unwrap();
+    let root = [dir.path().to_owned()];
+    let opts = ScanOptions::new().exclude_from(&list);
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths),
+        ["important.log", "keep.txt", "src/build", "sub/top.txt"]);
+
+    // A later file takes precedence, and `exclude` patterns apply as well.
+    let more = lists.path().join("more");
+    std::fs::write(&more, "!debug.log\n").unwrap();
+    let opts = opts.exclude_from(&more).exclude("keep.txt");
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths),
+        ["debug.log", "important.log", "src/build", "sub/top.txt"]);
+
+    let opts = ScanOptions::new().exclude_from(lists.path().join("missing"));
+    assert!(walk(&root, &opts, Instant::now(), |_| ()).is_err());
+}
+
+#[test]
+fn respect_gitignore_skips_what_a_repository_ignores() {
+    let dir = tree(&[
+        (".gitignore", b"*.log\n!keep.log\nbuild/\n/top.txt\n"),
+        (".git/info/exclude", b"private\n"),
+        (".git/HEAD", b"ref: refs/heads/main\n"),
+        ("a.txt", b"x"), ("debug.log", b"x"), ("keep.log", b"x"), ("top.txt", b"x"),
+        ("private", b"x"), ("build/out.o", b"x"),
+        ("sub/top.txt", b"x"), ("sub/trace.log", b"x"), ("sub/build", b"x"),
+        ("sub/.gitignore", b"*.tmp\n!debug.log\n"), ("sub/debug.log", b"x"), ("sub/scratch.tmp", b"x"),
+        ("sub/private", b"x"),
+    ]);
+    let root = [dir.path().to_owned()];
+    let opts = ScanOptions::new().include_hidden(true).exclude("**/.git/**");
+
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths).len(), 14);
+
+    let opts = opts.respect_gitignore(true);
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(dir.path(), &paths), [
+        ".gitignore", "a.txt", "keep.log", "sub/.gitignore", "sub/build",
+        "sub/debug.log", "sub/top.txt",
+    ]);
+
+    // Walking part of the repository still uses the ignore files above it,
+    // `info/exclude` included.
+    let root = [dir.path().join("sub")];
+    let paths = walk(&root, &opts, Instant::now(), |_| ()).unwrap();
+    assert_eq!(names(&dir.path().join("sub"), &paths),
+        [".gitignore", "build", "debug.log", "top.txt"]);
+}
+
+#[test]
+fn nested_roots_find_each_file_once() {
+    let dir = tree(&[("a", b"one"), ("sub/b", b"two"), ("sub/deeper/c", b"three")]);
+    let roots = [
+        dir.path().join("sub"),
+        dir.path().to_owned(),
+        dir.path().join("sub/deeper"),
+        // The same as the second, spelled differently.
+        dir.path().join("sub/.."),
+    ];
+    let seen = std::cell::Cell::new(0);
+    let paths = walk(&roots, &ScanOptions::new(), Instant::now(), |p| seen.set(p.files)).unwrap();
+    assert_eq!(names(dir.path(), &paths), ["a", "sub/b", "sub/deeper/c"]);
+    assert_eq!(seen.get(), 3);
+}